@@ -0,0 +1,576 @@
+// ACME client, RFC 8555: gets certificates for the servers' hostnames
+// with HTTP-01 challenges, answered from memory, and renews them before
+// they expire.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::Duration;
+use async_io::Timer;
+use async_lock::Mutex;
+use async_native_tls::{Certificate, TlsConnector};
+use async_net::TcpStream;
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+use memchr::memmem;
+use once_cell::sync::Lazy;
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509, X509NameBuilder, X509ReqBuilder};
+use serde_json::{json, Value};
+use crate::app_resp::RespHead;
+use crate::conf::{AcmeConf, Conf, CONF};
+use crate::http;
+use crate::jws::{self, Signer};
+use crate::timeout::timeout;
+use crate::tls;
+
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_RESP_SIZE: u64 = 1024 * 1024;
+/// Polls of a pending authorization or order before giving up.
+const MAX_POLLS: u32 = 60;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Failed renewals are retried after this, or check_interval_hours if
+/// that's shorter.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Lifetime of the self-signed certificate served until the first one is
+/// issued, short so that it's always due.
+const PLACEHOLDER_DAYS: u32 = 1;
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+const MAX_NONCE_RETRIES: u32 = 3;
+
+/// Key authorizations of pending HTTP-01 challenges, by token.
+static CHALLENGES: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+
+#[derive(Debug)]
+pub enum AcmeError {
+    Io(io::Error),
+    Tls(String),
+    Crypto(ErrorStack),
+    Timeout(String),
+    BadResponse(String),
+    /// Problem document of the server, RFC 8555 section 6.7.
+    Problem { status: u16, kind: String, detail: String },
+    /// An authorization or the order turned invalid.
+    Failed(String),
+}
+
+impl fmt::Display for AcmeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcmeError::Io(e) => write!(f, "ACME io err: {e}"),
+            AcmeError::Tls(e) => write!(f, "ACME TLS err: {e}"),
+            AcmeError::Crypto(e) => write!(f, "ACME crypto err: {e}"),
+            AcmeError::Timeout(v) => write!(f, "ACME timeout: {v}"),
+            AcmeError::BadResponse(e) => write!(f, "Bad ACME response: {e}"),
+            AcmeError::Problem { status, kind, detail } => {
+                write!(f, "ACME server err {status} {kind}: {detail}")
+            },
+            AcmeError::Failed(e) => write!(f, "ACME validation failed: {e}"),
+        }
+    }
+}
+
+impl From<io::Error> for AcmeError {
+    fn from(e: io::Error) -> Self {
+        AcmeError::Io(e)
+    }
+}
+
+impl From<ErrorStack> for AcmeError {
+    fn from(e: ErrorStack) -> Self {
+        AcmeError::Crypto(e)
+    }
+}
+
+
+/// Certificate of the conf which the client gets and renews.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Managed {
+    pub cert_path: String,
+    pub key_path: String,
+    pub hostnames: Vec<String>,
+}
+
+/// Certificates with `acme = true` and the hostnames using them: a
+/// server's own, and the top-level one for servers without their own.
+pub fn managed(conf: &Conf) -> Vec<Managed> {
+    let mut out = vec![];
+    if conf.tls.acme {
+        out.push(Managed {
+            cert_path: conf.tls.cert_path.to_string(),
+            key_path: conf.tls.key_path.to_string(),
+            hostnames: conf.servers.iter()
+                .filter(|srv| srv.tls.is_none())
+                .flat_map(|srv| srv.hostnames.iter().cloned())
+                .collect(),
+        });
+    }
+    for srv in &conf.servers {
+        match &srv.tls {
+            Some(tls) if tls.acme => out.push(Managed {
+                cert_path: tls.cert_path.to_string(),
+                key_path: tls.key_path.to_string(),
+                hostnames: srv.hostnames.clone(),
+            }),
+            _ => {},
+        }
+    }
+    out.retain(|m| !m.hostnames.is_empty());
+    out
+}
+
+/// Whether the certificate is missing, expires within `days` or doesn't
+/// cover all the hostnames.
+pub fn needs_renewal(m: &Managed, days: u32) -> bool {
+    let cert = match fs::read(&m.cert_path).map(|v| X509::from_pem(&v)) {
+        Ok(Ok(v)) => v,
+        _ => return true,
+    };
+    match Asn1Time::days_from_now(days) {
+        Ok(t) if cert.not_after() > t => {},
+        _ => return true,
+    }
+    let names: Vec<String> = cert.subject_alt_names().iter()
+        .flat_map(|names| names.iter().filter_map(|v| v.dnsname().map(str::to_string)).collect::<Vec<_>>())
+        .collect();
+    !m.hostnames.iter().all(|h| names.iter().any(|v| v.eq_ignore_ascii_case(h)))
+}
+
+/// Writes a short-lived self-signed certificate where none exists yet, so
+/// that the listener starts and the client replaces it.
+pub fn ensure_placeholders(conf: &Conf) -> Result<(), AcmeError> {
+    for m in managed(conf) {
+        if Path::new(&m.cert_path).exists() {
+            continue;
+        }
+        println!("ACME: self-signed certificate for {} until one is issued", m.hostnames.join(", "));
+        let key = jws::generate_key()?;
+        let cert = self_signed(&key, &m.hostnames)?;
+        write_files(&m, &cert.to_pem()?, &key)?;
+    }
+    Ok(())
+}
+
+fn self_signed(key: &PKey<Private>, hostnames: &[String]) -> Result<X509, ErrorStack> {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", "miarh placeholder")?;
+    let name = name.build();
+    let mut cert = X509::builder()?;
+    cert.set_version(2)?;
+    let serial = BigNum::from_u32(1)?.to_asn1_integer()?;
+    cert.set_serial_number(&serial)?;
+    cert.set_subject_name(&name)?;
+    cert.set_issuer_name(&name)?;
+    cert.set_pubkey(key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(PLACEHOLDER_DAYS)?;
+    cert.set_not_before(&not_before)?;
+    cert.set_not_after(&not_after)?;
+    let mut san = SubjectAlternativeName::new();
+    for hostname in hostnames {
+        san.dns(hostname);
+    }
+    let san = san.build(&cert.x509v3_context(None, None))?;
+    cert.append_extension(san)?;
+    cert.sign(key, MessageDigest::sha256())?;
+    Ok(cert.build())
+}
+
+/// DER certificate signing request of `key` for `hostnames`.
+pub fn csr(key: &PKey<Private>, hostnames: &[String]) -> Result<Vec<u8>, ErrorStack> {
+    let mut req = X509ReqBuilder::new()?;
+    let mut name = X509NameBuilder::new()?;
+    // The names are in the SAN, CNs are limited to 64 chars.
+    if hostnames[0].len() <= 64 {
+        name.append_entry_by_text("CN", &hostnames[0])?;
+    }
+    req.set_subject_name(&name.build())?;
+    req.set_pubkey(key)?;
+    let mut san = SubjectAlternativeName::new();
+    for hostname in hostnames {
+        san.dns(hostname);
+    }
+    let mut extensions = Stack::new()?;
+    extensions.push(san.build(&req.x509v3_context(None))?)?;
+    req.add_extensions(&extensions)?;
+    req.sign(key, MessageDigest::sha256())?;
+    req.build().to_der()
+}
+
+/// Writes the key and the chain next to their paths and renames them into
+/// place, so that no reader sees half a file.
+pub fn write_files(m: &Managed, chain: &[u8], key: &PKey<Private>) -> Result<(), AcmeError> {
+    write_atomic(&m.key_path, &key.private_key_to_pem_pkcs8()?, 0o600)?;
+    write_atomic(&m.cert_path, chain, 0o644)?;
+    Ok(())
+}
+
+fn write_atomic(path: &str, data: &[u8], mode: u32) -> io::Result<()> {
+    let tmp = format!("{path}.tmp");
+    let mut file = fs::OpenOptions::new()
+        .write(true).create(true).truncate(true).mode(mode).open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// The account key, created on first use.
+fn account_key(path: &str) -> Result<PKey<Private>, AcmeError> {
+    match fs::read(path) {
+        Ok(pem) => Ok(PKey::private_key_from_pem(&pem)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            println!("ACME: new account key {path}");
+            let key = jws::generate_key()?;
+            write_atomic(path, &key.private_key_to_pem_pkcs8()?, 0o600)?;
+            Ok(key)
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+
+/// Key authorization of a pending HTTP-01 challenge.
+pub async fn key_authorization(token: &str) -> Option<String> {
+    CHALLENGES.lock().await.get(token).cloned()
+}
+
+/// Answer to a challenge request on the HTTP port, None if `path` isn't
+/// one of a pending challenge.
+pub async fn challenge_response(path: &str) -> Option<String> {
+    let prefix = CONF.read().await.acme_challenge_url.to_string();
+    key_authorization(path.strip_prefix(&prefix)?).await
+}
+
+
+/// Checks the managed certificates each check_interval_hours and renews
+/// the ones which are due.
+pub async fn run() {
+    loop {
+        let (conf, managed) = {
+            let conf = CONF.read().await;
+            (conf.acme.clone(), managed(&conf))
+        };
+        let check_interval = Duration::from_secs(conf.check_interval_hours * 60 * 60);
+        let (renewed, errors) = renew_due(&conf, &managed).await;
+        if renewed > 0 {
+            let _ = tls::reload().await;
+        }
+        for e in &errors {
+            println!("{e}");
+        }
+        let wait = if errors.is_empty() { check_interval } else { check_interval.min(RETRY_INTERVAL) };
+        Timer::after(wait).await;
+    }
+}
+
+/// Gets new certificates for the due ones and writes them. Returns how
+/// many were renewed, and the errors of the rest.
+pub async fn renew_due(conf: &AcmeConf, managed: &[Managed]) -> (usize, Vec<AcmeError>) {
+    let due: Vec<&Managed> = managed.iter()
+        .filter(|m| needs_renewal(m, conf.renew_before_days))
+        .collect();
+    if due.is_empty() {
+        return (0, vec![]);
+    }
+    let mut client = match AcmeClient::new(conf).await {
+        Ok(v) => v,
+        Err(e) => return (0, vec![e]),
+    };
+    let mut renewed = 0;
+    let mut errors = vec![];
+    for m in due {
+        println!("ACME: ordering a certificate for {}", m.hostnames.join(", "));
+        let r = match client.obtain(&m.hostnames).await {
+            Ok((chain, key)) => write_files(m, &chain, &key),
+            Err(e) => Err(e),
+        };
+        match r {
+            Ok(()) => renewed += 1,
+            Err(e) => errors.push(e),
+        }
+    }
+    (renewed, errors)
+}
+
+
+pub struct AcmeClient {
+    connector: TlsConnector,
+    key: PKey<Private>,
+    thumbprint: String,
+    directory: Value,
+    /// Account URL.
+    kid: String,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    /// Fetches the directory and registers the account, or finds it if
+    /// the key is registered already.
+    pub async fn new(conf: &AcmeConf) -> Result<Self, AcmeError> {
+        let mut connector = TlsConnector::new();
+        if !conf.ca_cert_path.is_empty() {
+            let pem = fs::read(&conf.ca_cert_path)?;
+            let cert = Certificate::from_pem(&pem).map_err(|e| AcmeError::Tls(e.to_string()))?;
+            connector = connector.add_root_certificate(cert);
+        }
+        let key = account_key(&conf.account_key_path)?;
+        let thumbprint = jws::thumbprint(&jws::jwk(&key)?)?;
+        let directory = request(&connector, "GET", &conf.directory_url, None).await?.json()?;
+        let mut client = Self { connector, key, thumbprint, directory, kid: String::new(), nonce: None };
+        let mut account = json!({ "termsOfServiceAgreed": true });
+        if !conf.contact_email.is_empty() {
+            account["contact"] = json!([format!("mailto:{}", conf.contact_email)]);
+        }
+        let url = client.url("newAccount")?;
+        let resp = client.post(&url, Some(&account)).await?;
+        client.kid = resp.location()?;
+        Ok(client)
+    }
+
+    /// Orders a certificate for `hostnames`. Returns the PEM chain and the
+    /// new key it's issued for.
+    pub async fn obtain(&mut self, hostnames: &[String]) -> Result<(Vec<u8>, PKey<Private>), AcmeError> {
+        let identifiers: Vec<Value> = hostnames.iter()
+            .map(|h| json!({ "type": "dns", "value": h }))
+            .collect();
+        let url = self.url("newOrder")?;
+        let resp = self.post(&url, Some(&json!({ "identifiers": identifiers }))).await?;
+        let order_url = resp.location()?;
+        let order = resp.json()?;
+        let authorizations = match order["authorizations"].as_array() {
+            Some(v) => v.iter().filter_map(|v| v.as_str().map(str::to_string)).collect(),
+            None => vec![],
+        };
+        for url in authorizations {
+            self.authorize(&url).await?;
+        }
+        let key = jws::generate_key()?;
+        let csr = csr(&key, hostnames)?;
+        let finalize = str_field(&order, "finalize")?;
+        self.post(&finalize, Some(&json!({ "csr": jws::base64url(&csr) }))).await?;
+        let order = self.poll(&order_url).await?;
+        let cert_url = str_field(&order, "certificate")?;
+        let chain = self.post(&cert_url, None).await?.body;
+        Ok((chain, key))
+    }
+
+    async fn authorize(&mut self, url: &str) -> Result<(), AcmeError> {
+        let authz = self.post(url, None).await?.json()?;
+        if authz["status"] == "valid" {
+            return Ok(());
+        }
+        let challenge = authz["challenges"].as_array()
+            .and_then(|v| v.iter().find(|c| c["type"] == "http-01"));
+        let challenge = match challenge {
+            Some(v) => v,
+            None => return Err(AcmeError::Failed(format!("no http-01 challenge in {url}"))),
+        };
+        let token = str_field(challenge, "token")?;
+        let challenge_url = str_field(challenge, "url")?;
+        let key_authorization = format!("{token}.{}", self.thumbprint);
+        CHALLENGES.lock().await.insert(token.to_string(), key_authorization);
+        let mut r = self.post(&challenge_url, Some(&json!({}))).await.map(|_| ());
+        if r.is_ok() {
+            r = self.poll(url).await.map(|_| ());
+        }
+        CHALLENGES.lock().await.remove(&token);
+        r
+    }
+
+    /// Polls an authorization or order until it's valid.
+    async fn poll(&mut self, url: &str) -> Result<Value, AcmeError> {
+        for _ in 0..MAX_POLLS {
+            let v = self.post(url, None).await?.json()?;
+            match v["status"].as_str() {
+                Some("valid") => return Ok(v),
+                Some("pending" | "processing" | "ready") => Timer::after(POLL_INTERVAL).await,
+                status => {
+                    let status = status.unwrap_or("unknown");
+                    return Err(AcmeError::Failed(format!("{url} is {status}: {}", failure(&v))));
+                },
+            };
+        }
+        Err(AcmeError::Timeout(format!("{url} is still pending")))
+    }
+
+    fn url(&self, name: &str) -> Result<String, AcmeError> {
+        str_field(&self.directory, name)
+    }
+
+    async fn nonce(&mut self) -> Result<String, AcmeError> {
+        if let Some(v) = self.nonce.take() {
+            return Ok(v);
+        }
+        let url = self.url("newNonce")?;
+        let resp = request(&self.connector, "HEAD", &url, None).await?;
+        match resp.head.get("replay-nonce") {
+            Some(v) => Ok(v.to_string()),
+            None => Err(AcmeError::BadResponse("no Replay-Nonce".to_string())),
+        }
+    }
+
+    /// Signed POST of `payload`, POST-as-GET if it's None. Rejected nonces
+    /// are retried with the fresh one from the error.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Resp, AcmeError> {
+        let mut retries = 0;
+        loop {
+            let nonce = self.nonce().await?;
+            let signer = if self.kid.is_empty() { Signer::Jwk } else { Signer::Kid(&self.kid) };
+            let body = jws::sign(&self.key, signer, &nonce, url, payload)?;
+            let resp = request(&self.connector, "POST", url, Some(&body)).await?;
+            self.nonce = resp.head.get("replay-nonce").map(str::to_string);
+            match resp.problem() {
+                Some(AcmeError::Problem { kind, .. }) if kind == BAD_NONCE && retries < MAX_NONCE_RETRIES => {
+                    retries += 1;
+                },
+                Some(e) => return Err(e),
+                None => return Ok(resp),
+            }
+        }
+    }
+}
+
+fn str_field(v: &Value, name: &str) -> Result<String, AcmeError> {
+    match v[name].as_str() {
+        Some(v) => Ok(v.to_string()),
+        None => Err(AcmeError::BadResponse(format!("no {name} in {v}"))),
+    }
+}
+
+/// Error detail of an invalid order or authorization.
+fn failure(v: &Value) -> String {
+    let challenge_errors = v["challenges"].as_array().into_iter().flatten().map(|c| &c["error"]);
+    std::iter::once(&v["error"]).chain(challenge_errors)
+        .find_map(|e| e["detail"].as_str())
+        .unwrap_or("no details")
+        .to_string()
+}
+
+
+pub struct Resp {
+    pub head: RespHead,
+    pub body: Vec<u8>,
+}
+
+impl Resp {
+    pub fn json(&self) -> Result<Value, AcmeError> {
+        serde_json::from_slice(&self.body).map_err(|e| AcmeError::BadResponse(e.to_string()))
+    }
+    pub fn location(&self) -> Result<String, AcmeError> {
+        match self.head.get("location") {
+            Some(v) => Ok(v.to_string()),
+            None => Err(AcmeError::BadResponse("no Location".to_string())),
+        }
+    }
+    /// The problem document of an error response.
+    pub fn problem(&self) -> Option<AcmeError> {
+        if self.head.code < 400 {
+            return None;
+        }
+        let v = self.json().unwrap_or(Value::Null);
+        Some(AcmeError::Problem {
+            status: self.head.code,
+            kind: v["type"].as_str().unwrap_or("").to_string(),
+            detail: v["detail"].as_str().unwrap_or("").to_string(),
+        })
+    }
+}
+
+/// HTTP/1.1 response read until the server closed the connection.
+pub fn parse_resp(data: &[u8]) -> Result<Resp, AcmeError> {
+    let end = match memmem::find(data, b"\r\n\r\n") {
+        Some(v) => v,
+        None => return Err(AcmeError::BadResponse("no response head".to_string())),
+    };
+    let head = RespHead::parse(&data[..end]).map_err(AcmeError::BadResponse)?;
+    let rest = &data[end + 4..];
+    let is_chunked = head.get("transfer-encoding")
+        .map(|v| v.eq_ignore_ascii_case("chunked")).unwrap_or(false);
+    let len = head.get("content-length").and_then(|v| v.parse::<usize>().ok());
+    let body = match (is_chunked, len) {
+        (true, _) => dechunk(rest)?,
+        // HEAD responses have the length but no body.
+        (false, Some(len)) => rest[..len.min(rest.len())].to_vec(),
+        (false, None) => rest.to_vec(),
+    };
+    Ok(Resp { head, body })
+}
+
+fn dechunk(mut data: &[u8]) -> Result<Vec<u8>, AcmeError> {
+    let bad = || AcmeError::BadResponse("bad chunked body".to_string());
+    let mut body = vec![];
+    loop {
+        let line_end = memmem::find(data, b"\r\n").ok_or_else(bad)?;
+        let line = std::str::from_utf8(&data[..line_end]).map_err(|_| bad())?;
+        // Chunk extensions after ';' are ignored.
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| bad())?;
+        if size == 0 {
+            return Ok(body);
+        }
+        let start = line_end + 2;
+        if data.len() < start + size + 2 {
+            return Err(bad());
+        }
+        body.extend_from_slice(&data[start..start + size]);
+        data = &data[start + size + 2..];
+    }
+}
+
+/// "https://host[:port]/path" as host, port and path.
+fn split_url(url: &str) -> Result<(String, u16, String), AcmeError> {
+    let bad = || AcmeError::BadResponse(format!("not an https URL: {url}"));
+    let rest = url.strip_prefix("https://").ok_or_else(bad)?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().map_err(|_| bad())?),
+        None => (authority, 443),
+    };
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+/// Request over a new connection, closed by the server after the response.
+async fn request(connector: &TlsConnector, method: &str, url: &str,
+                 body: Option<&Value>) -> Result<Resp, AcmeError> {
+    let (host, port, path) = split_url(url)?;
+    let exchange = async {
+        let stream = TcpStream::connect((host.as_str(), port)).await?;
+        let mut stream = connector.connect(host.as_str(), stream).await
+            .map_err(|e| AcmeError::Tls(e.to_string()))?;
+        let mut req = format!("{method} {path} HTTP/1.1\r\n\
+            Host: {}\r\n\
+            User-Agent: miarh\r\n\
+            Connection: close\r\n", http::authority("https", &host, Some(port)));
+        let body = body.map(|v| v.to_string()).unwrap_or_default();
+        if method == "POST" {
+            req.push_str(&format!("Content-Type: application/jose+json\r\n\
+                Content-Length: {}\r\n", body.len()));
+        }
+        req.push_str("\r\n");
+        req.push_str(&body);
+        stream.write_all(req.as_bytes()).await?;
+        stream.flush().await?;
+        let mut data = vec![];
+        (&mut stream).take(MAX_RESP_SIZE).read_to_end(&mut data).await?;
+        parse_resp(&data)
+    };
+    match timeout(REQUEST_TIMEOUT, exchange).await {
+        Some(r) => r,
+        None => Err(AcmeError::Timeout(format!("{method} {url}"))),
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use async_lock::{Mutex, Semaphore, SemaphoreGuardArc};
+use futures_lite::{future, AsyncReadExt};
+use once_cell::sync::Lazy;
+use crate::app_proto::{self, ProtoError};
+use crate::conf::ServerConf;
+use crate::timeout::Phase;
+use crate::upstream::{AppStream, UpstreamAddr};
+
+
+/// Server name and address, each server has its own pool settings.
+type PoolKey = (String, String);
+
+static POOLS: Lazy<Mutex<HashMap<PoolKey, Arc<AppPool>>>> = Lazy::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+
+#[derive(Debug)]
+pub enum AppError {
+    NoServer,
+    Saturated,
+    /// Circuits of all upstreams are open, with seconds until a retry.
+    Unavailable(Option<u64>),
+    /// The server has its maximum of open WebSocket tunnels.
+    TooManyTunnels,
+    Connect(io::Error),
+    Io(io::Error),
+    Proto(ProtoError),
+    Timeout(Phase),
+    Client(io::Error),
+    BadRequest(String),
+    LengthRequired,
+    TooLarge,
+    InvalidResponse(String),
+    /// Failed after a part of the response was sent to the client.
+    Streaming(Box<AppError>),
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppError::NoServer => write!(f, "No app server for this host."),
+            AppError::Saturated => write!(f, "App connection pool is saturated."),
+            AppError::Unavailable(_) => write!(f, "No available app servers."),
+            AppError::TooManyTunnels => write!(f, "Too many open WebSocket connections."),
+            AppError::Connect(e) => write!(f, "Can't connect to app server: {e}"),
+            AppError::Io(e) => write!(f, "App server io err: {e}"),
+            AppError::Proto(e) => write!(f, "{e}"),
+            AppError::Timeout(phase) => write!(f, "App server timed out at {phase} phase."),
+            AppError::Client(e) => write!(f, "Client io err: {e}"),
+            AppError::BadRequest(e) => write!(f, "Bad request: {e}"),
+            AppError::LengthRequired => write!(f, "Chunked request bodies are not supported."),
+            AppError::TooLarge => write!(f, "Request entity too large."),
+            AppError::InvalidResponse(e) => write!(f, "Invalid app response: {e}"),
+            AppError::Streaming(e) => write!(f, "Response streaming err: {e}"),
+        }
+    }
+}
+
+impl From<ProtoError> for AppError {
+    fn from(e: ProtoError) -> Self {
+        AppError::Proto(e)
+    }
+}
+
+impl AppError {
+    pub fn http_code(&self) -> u16 {
+        match self {
+            AppError::NoServer => 404,
+            AppError::Saturated | AppError::Unavailable(_) | AppError::TooManyTunnels => 503,
+            AppError::Connect(_) | AppError::Io(_) | AppError::Proto(_)
+                | AppError::InvalidResponse(_) => 502,
+            AppError::Timeout(_) => 504,
+            AppError::Client(_) | AppError::BadRequest(_) => 400,
+            AppError::LengthRequired => 411,
+            AppError::TooLarge => 413,
+            AppError::Streaming(e) => e.http_code(),
+        }
+    }
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            AppError::Unavailable(v) => *v,
+            _ => None,
+        }
+    }
+    /// Whether the error counts against the health of an upstream.
+    pub fn is_upstream_failure(&self) -> bool {
+        match self {
+            AppError::Connect(_) | AppError::Io(_) | AppError::Proto(_) | AppError::Timeout(_)
+                | AppError::InvalidResponse(_) => true,
+            AppError::Streaming(e) => e.is_upstream_failure(),
+            _ => false,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct PoolConf {
+    pub addr: UpstreamAddr,
+    pub size: usize,
+    pub max_idle: Duration,
+    pub queue_limit: usize,
+}
+
+impl PoolConf {
+    pub fn new(addr: UpstreamAddr, srv: &ServerConf) -> Self {
+        Self {
+            addr,
+            size: srv.pool_size.max(1),
+            max_idle: Duration::from_secs(srv.pool_max_idle_secs),
+            queue_limit: srv.pool_queue_limit,
+        }
+    }
+}
+
+/// Persistent connections to one app server. At most `size` connections
+/// are in use at a time, up to `queue_limit` requests may wait for one.
+pub struct AppPool {
+    pub conf: PoolConf,
+    idle: Mutex<Vec<(AppStream, Instant)>>,
+    permits: Arc<Semaphore>,
+    waiting: AtomicUsize,
+}
+
+pub struct PooledConn {
+    pub stream: AppStream,
+    _permit: SemaphoreGuardArc,
+}
+
+impl PooledConn {
+    /// Takes the connection out of the pool, e.g. for a WebSocket tunnel.
+    /// It no longer counts against the pool size.
+    pub fn into_stream(self) -> AppStream {
+        self.stream
+    }
+}
+
+pub async fn get_pool(server: &str, conf: PoolConf) -> Arc<AppPool> {
+    let mut pools = POOLS.lock().await;
+    let pool = pools.entry((server.to_string(), conf.addr.to_string()))
+        .or_insert_with(|| Arc::new(AppPool::new(conf)));
+    Arc::clone(pool)
+}
+
+impl AppPool {
+    pub fn new(conf: PoolConf) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(conf.size)),
+            conf,
+            idle: Mutex::new(vec![]),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    pub async fn get(&self) -> Result<PooledConn, AppError> {
+        let permit = match self.permits.try_acquire_arc() {
+            Some(v) => v,
+            None => {
+                // Released on drop too, when a timeout cancels the wait.
+                let waiting = Waiting::new(&self.waiting);
+                if waiting.count >= self.conf.queue_limit {
+                    return Err(AppError::Saturated);
+                }
+                self.permits.acquire_arc().await
+            },
+        };
+        while let Some(mut stream) = self.pop_idle().await {
+            if is_alive(&mut stream).await {
+                return Ok(PooledConn { stream, _permit: permit });
+            }
+        }
+        let mut stream = match self.conf.addr.connect().await {
+            Ok(v) => v,
+            Err(e) => return Err(AppError::Connect(e)),
+        };
+        app_proto::handshake(&mut stream).await?;
+        Ok(PooledConn { stream, _permit: permit })
+    }
+
+    /// Returns a connection after a complete response was read from it.
+    /// Connections which failed mid-request must be dropped instead.
+    pub async fn release(&self, conn: PooledConn) {
+        let mut idle = self.idle.lock().await;
+        if idle.len() < self.conf.size {
+            idle.push((conn.stream, Instant::now()));
+        }
+    }
+
+    async fn pop_idle(&self) -> Option<AppStream> {
+        let mut idle = self.idle.lock().await;
+        while let Some((stream, since)) = idle.pop() {
+            if since.elapsed() < self.conf.max_idle {
+                return Some(stream);
+            }
+        }
+        None
+    }
+}
+
+/// A request waiting for a connection.
+struct Waiting<'a> {
+    waiting: &'a AtomicUsize,
+    /// Requests waiting before this one.
+    count: usize,
+}
+
+impl<'a> Waiting<'a> {
+    fn new(waiting: &'a AtomicUsize) -> Self {
+        Self { count: waiting.fetch_add(1, Ordering::SeqCst), waiting }
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// An idle connection must have nothing to read. EOF or unexpected data
+// means the app has closed it or the protocol is out of sync.
+async fn is_alive(stream: &mut AppStream) -> bool {
+    let mut buf = [0; 1];
+    future::poll_once(stream.read(&mut buf)).await.is_none()
+}
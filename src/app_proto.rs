@@ -0,0 +1,246 @@
+// App socket protocol.
+//
+// Every message is a sequence of frames:
+//
+//     [type: u8][payload length: u32 BE][payload]
+//
+// After connecting, miarh sends HELLO with its protocol version (u16 BE)
+// and the app answers with HELLO carrying the same version, or with ERROR
+// if it does not support it.
+//
+// Request:  META (bincode miarh_saras_http::Request), BODY*, END
+// Response: BODY* (raw HTTP response bytes), END
+//
+// The Request has no headers, a verified client certificate comes in its
+// route map as tls_client_subject, tls_client_san and
+// tls_client_fingerprint.
+//
+// Either side may send ERROR (utf-8 text) instead of the rest of a message.
+// After END the connection can be reused for the next request.
+//
+// WebSocket handshakes are sent as UPGRADE (raw HTTP request head), END and
+// answered like requests. After a "101 Switching Protocols" response the
+// connection leaves the protocol: it carries the raw bytes of the upgraded
+// connection both ways until either side closes it.
+//
+// WebSockets terminated by miarh are bridged over a connection of their
+// own, which carries WS_OPEN, WS_MESSAGE and WS_CLOSE events after HELLO,
+// see ws_bridge.rs.
+
+use std::fmt;
+use std::io;
+use futures_lite::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use miarh_saras_http::Request;
+
+
+pub const PROTOCOL_VERSION: u16 = 1;
+pub const FRAME_HEADER_LEN: usize = 5;
+pub const MAX_FRAME_LEN: usize = 1024 * 1024 * 16;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameType {
+    Hello = 1,
+    Meta = 2,
+    Body = 3,
+    End = 4,
+    Error = 5,
+    Upgrade = 6,
+    WsOpen = 7,
+    WsMessage = 8,
+    WsClose = 9,
+}
+
+impl FrameType {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(FrameType::Hello),
+            2 => Some(FrameType::Meta),
+            3 => Some(FrameType::Body),
+            4 => Some(FrameType::End),
+            5 => Some(FrameType::Error),
+            6 => Some(FrameType::Upgrade),
+            7 => Some(FrameType::WsOpen),
+            8 => Some(FrameType::WsMessage),
+            9 => Some(FrameType::WsClose),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    pub kind: FrameType,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum ProtoError {
+    Io(io::Error),
+    Version(u16),
+    UnknownFrame(u8),
+    Unexpected(FrameType),
+    FrameTooLarge(usize),
+    Encoding(String),
+    App(String),
+}
+
+impl fmt::Display for ProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtoError::Io(e) => write!(f, "App socket io err: {e}"),
+            ProtoError::Version(v) => write!(f, "Unsupported app protocol version: {v}"),
+            ProtoError::UnknownFrame(v) => write!(f, "Unknown app frame type: {v}"),
+            ProtoError::Unexpected(v) => write!(f, "Unexpected app frame: {v:?}"),
+            ProtoError::FrameTooLarge(v) => write!(f, "App frame is too large: {v}"),
+            ProtoError::Encoding(e) => write!(f, "App message encoding err: {e}"),
+            ProtoError::App(e) => write!(f, "App err: {e}"),
+        }
+    }
+}
+
+impl From<io::Error> for ProtoError {
+    fn from(e: io::Error) -> Self {
+        ProtoError::Io(e)
+    }
+}
+
+
+pub fn encode_frame(kind: FrameType, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.push(kind as u8);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(w: &mut W, kind: FrameType, payload: &[u8]
+                                                ) -> Result<(), ProtoError> {
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(ProtoError::FrameTooLarge(payload.len()));
+    }
+    w.write_all(&encode_frame(kind, payload)).await?;
+    Ok(())
+}
+
+pub async fn read_frame<R: AsyncRead + Unpin>(r: &mut R) -> Result<Frame, ProtoError> {
+    let mut header = [0; FRAME_HEADER_LEN];
+    r.read_exact(&mut header).await?;
+    let kind = match FrameType::from_u8(header[0]) {
+        Some(v) => v,
+        None => return Err(ProtoError::UnknownFrame(header[0])),
+    };
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(ProtoError::FrameTooLarge(len));
+    }
+    let mut payload = vec![0; len];
+    r.read_exact(&mut payload).await?;
+    if kind == FrameType::Error {
+        return Err(ProtoError::App(String::from_utf8_lossy(&payload).to_string()));
+    }
+    Ok(Frame { kind, payload })
+}
+
+/// Client side of the handshake, done once per connection.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(s: &mut S) -> Result<(), ProtoError> {
+    write_frame(s, FrameType::Hello, &PROTOCOL_VERSION.to_be_bytes()).await?;
+    s.flush().await?;
+    let frame = read_frame(s).await?;
+    if frame.kind != FrameType::Hello {
+        return Err(ProtoError::Unexpected(frame.kind));
+    }
+    let version = parse_version(&frame.payload)?;
+    if version != PROTOCOL_VERSION {
+        return Err(ProtoError::Version(version));
+    }
+    Ok(())
+}
+
+/// App side of the handshake.
+pub async fn accept_handshake<S: AsyncRead + AsyncWrite + Unpin>(s: &mut S
+                                                                 ) -> Result<u16, ProtoError> {
+    let frame = read_frame(s).await?;
+    if frame.kind != FrameType::Hello {
+        return Err(ProtoError::Unexpected(frame.kind));
+    }
+    let version = parse_version(&frame.payload)?;
+    if version != PROTOCOL_VERSION {
+        let msg = format!("unsupported protocol version {version}");
+        write_frame(s, FrameType::Error, msg.as_bytes()).await?;
+        s.flush().await?;
+        return Err(ProtoError::Version(version));
+    }
+    write_frame(s, FrameType::Hello, &PROTOCOL_VERSION.to_be_bytes()).await?;
+    s.flush().await?;
+    Ok(version)
+}
+
+fn parse_version(payload: &[u8]) -> Result<u16, ProtoError> {
+    match payload {
+        [hi, lo, ..] => Ok(u16::from_be_bytes([*hi, *lo])),
+        _ => Err(ProtoError::Encoding("bad hello frame".to_string())),
+    }
+}
+
+pub async fn send_request<W: AsyncWrite + Unpin>(w: &mut W, req: &Request, body: &[u8]
+                                                 ) -> Result<(), ProtoError> {
+    let meta = bincode::serialize(req).map_err(|e| ProtoError::Encoding(e.to_string()))?;
+    write_frame(w, FrameType::Meta, &meta).await?;
+    for chunk in body.chunks(MAX_FRAME_LEN) {
+        write_frame(w, FrameType::Body, chunk).await?;
+    }
+    write_frame(w, FrameType::End, &[]).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+pub async fn send_upgrade<W: AsyncWrite + Unpin>(w: &mut W, head: &[u8]
+                                                 ) -> Result<(), ProtoError> {
+    write_frame(w, FrameType::Upgrade, head).await?;
+    write_frame(w, FrameType::End, &[]).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+/// Reads a request on the app side: META, BODY frames and END.
+pub async fn read_request<R: AsyncRead + Unpin>(r: &mut R
+                                                ) -> Result<(Request, Vec<u8>), ProtoError> {
+    let frame = read_frame(r).await?;
+    if frame.kind != FrameType::Meta {
+        return Err(ProtoError::Unexpected(frame.kind));
+    }
+    let req: Request = bincode::deserialize(&frame.payload)
+        .map_err(|e| ProtoError::Encoding(e.to_string()))?;
+    let mut body = vec![];
+    loop {
+        let frame = read_frame(r).await?;
+        match frame.kind {
+            FrameType::Body => body.extend_from_slice(&frame.payload),
+            FrameType::End => return Ok((req, body)),
+            kind => return Err(ProtoError::Unexpected(kind)),
+        }
+    }
+}
+
+pub async fn send_response<W: AsyncWrite + Unpin>(w: &mut W, resp: &[u8]
+                                                  ) -> Result<(), ProtoError> {
+    for chunk in resp.chunks(MAX_FRAME_LEN) {
+        write_frame(w, FrameType::Body, chunk).await?;
+    }
+    write_frame(w, FrameType::End, &[]).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+pub async fn read_response<R: AsyncRead + Unpin>(r: &mut R) -> Result<Vec<u8>, ProtoError> {
+    let mut resp = vec![];
+    loop {
+        let frame = read_frame(r).await?;
+        match frame.kind {
+            FrameType::Body => resp.extend_from_slice(&frame.payload),
+            FrameType::End => return Ok(resp),
+            kind => return Err(ProtoError::Unexpected(kind)),
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use std::io::Write;
+use std::time::SystemTime;
+use memchr::memmem;
+use crate::cgi::CgiResp;
+use crate::compress;
+use crate::http;
+use crate::proxy;
+
+
+pub const MAX_RESP_HEAD_SIZE: usize = 1024 * 64;
+/// Bodies up to this size are buffered to send an exact Content-Length.
+pub const MAX_BUFFERED_BODY: usize = 1024 * 64;
+pub const SERVER_NAME: &str = "miarh";
+
+pub const HOP_BY_HOP: [&str; 8] = [
+    "connection", "keep-alive", "proxy-authenticate", "proxy-authorization",
+    "te", "trailer", "transfer-encoding", "upgrade",
+];
+
+
+#[derive(Debug, Clone)]
+pub struct RespHead {
+    pub code: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RespHead {
+    pub fn parse(head: &[u8]) -> Result<Self, String> {
+        let head = std::str::from_utf8(head).map_err(|_| "head is not utf-8".to_string())?;
+        let mut lines = head.split("\r\n");
+        let status_line = lines.next().unwrap_or("");
+        let mut parts = status_line.splitn(3, ' ');
+        let version = parts.next().unwrap_or("");
+        if !version.starts_with("HTTP/1.") {
+            return Err(format!("bad status line: {status_line}"));
+        }
+        let code = match parts.next().map(|v| v.parse::<u16>()) {
+            Some(Ok(v)) if (100..600).contains(&v) => v,
+            _ => return Err(format!("bad status line: {status_line}")),
+        };
+        let reason = parts.next().unwrap_or("").to_string();
+        let mut headers = vec![];
+        for line in lines {
+            if line.starts_with(' ') || line.starts_with('\t') {
+                return Err("folded headers are not supported".to_string());
+            }
+            match line.split_once(':') {
+                Some((k, v)) if !k.is_empty() && !k.contains(' ') => {
+                    headers.push((k.to_string(), v.trim().to_string()));
+                },
+                _ => return Err(format!("bad header line: {line}")),
+            }
+        }
+        Ok(Self { code, reason, headers })
+    }
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+    pub fn remove(&mut self, name: &str) {
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+    pub fn set_default(&mut self, name: &str, value: &str) {
+        if self.get(name).is_none() {
+            self.headers.push((name.to_string(), value.to_string()));
+        }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.code, self.reason);
+        for (k, v) in &self.headers {
+            head.push_str(&format!("{k}: {v}\r\n"));
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+}
+
+
+pub struct RespOptions {
+    pub is_accept_brotli: bool,
+    /// Per-server headers, replacing ones set by the app.
+    pub headers: Vec<(String, String)>,
+    /// Location prefix => replacement, for responses of proxied backends.
+    pub location_rewrites: Vec<(String, String)>,
+    /// The response starts with a CGI head ("Status: 200 OK") instead of
+    /// a status line.
+    pub is_cgi: bool,
+    /// The response to a HEAD request, whose head is passed without a body.
+    pub is_head: bool,
+}
+
+enum Mode {
+    /// Waiting for the rest of the body to send an exact Content-Length.
+    Buffering,
+    Passthrough,
+    Chunked,
+    Brotli(Box<compress::BrotliWriter>),
+    NoBody,
+}
+
+/// Rewrites a raw HTTP response coming from the app: validates its head,
+/// removes hop-by-hop headers, adds Date, Server and per-server headers,
+/// fixes body framing and compresses compressible bodies.
+pub struct RespProcessor {
+    opts: RespOptions,
+    cgi: Option<CgiResp>,
+    head_buf: Vec<u8>,
+    head: Option<RespHead>,
+    mode: Mode,
+    body: Vec<u8>,
+    body_len: usize,
+}
+
+impl RespProcessor {
+    pub fn new(opts: RespOptions) -> Self {
+        Self {
+            cgi: if opts.is_cgi { Some(CgiResp::new()) } else { None },
+            opts,
+            head_buf: vec![],
+            head: None,
+            mode: Mode::Buffering,
+            body: vec![],
+            body_len: 0,
+        }
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        self.head.as_ref().map(|h| h.code)
+    }
+
+    /// Takes bytes from the app, returns bytes ready to be sent to the client.
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        if self.head.is_some() {
+            return self.feed_body(data);
+        }
+        match &mut self.cgi {
+            Some(cgi) => self.head_buf.extend(cgi.feed(data)?),
+            None => self.head_buf.extend_from_slice(data),
+        }
+        let end = match memmem::find(&self.head_buf, b"\r\n\r\n") {
+            Some(v) => v,
+            None if self.head_buf.len() > MAX_RESP_HEAD_SIZE => {
+                return Err("response head is too large".to_string());
+            },
+            None => return Ok(vec![]),
+        };
+        let head = RespHead::parse(&self.head_buf[..end])?;
+        let rest = self.head_buf.split_off(end + 4);
+        self.head_buf.clear();
+        let mut out = self.start(head);
+        out.extend(self.feed_body(&rest)?);
+        Ok(out)
+    }
+
+    pub fn finish(&mut self) -> Result<Vec<u8>, String> {
+        if let Some(cgi) = &self.cgi {
+            cgi.finish()?;
+        }
+        let head = match self.head.as_mut() {
+            Some(v) => v,
+            None => return Err("incomplete response head".to_string()),
+        };
+        let mut out = vec![];
+        match std::mem::replace(&mut self.mode, Mode::NoBody) {
+            Mode::Buffering => {
+                head.set("Content-Length", &self.body.len().to_string());
+                out.extend(head.to_bytes());
+                out.append(&mut self.body);
+            },
+            Mode::Chunked => out.extend(b"0\r\n\r\n"),
+            Mode::Brotli(writer) => {
+                out.extend(chunk(&writer.into_inner()));
+                out.extend(b"0\r\n\r\n");
+            },
+            Mode::Passthrough => {
+                if let Some(len) = head.get("content-length") {
+                    if len != self.body_len.to_string() {
+                        println!("App response length mismatch: {len} != {}", self.body_len);
+                    }
+                }
+            },
+            Mode::NoBody => {},
+        }
+        Ok(out)
+    }
+
+    fn start(&mut self, mut head: RespHead) -> Vec<u8> {
+        let is_app_chunked = head.get("transfer-encoding")
+            .map(|v| v.to_lowercase().contains("chunked")).unwrap_or(false);
+        if let Some(v) = head.get("connection").map(|v| v.to_string()) {
+            for name in v.split(',') {
+                head.remove(name.trim());
+            }
+        }
+        for name in HOP_BY_HOP {
+            head.remove(name);
+        }
+        if let Some(v) = head.get("location")
+                .and_then(|v| proxy::rewrite_location(v, &self.opts.location_rewrites)) {
+            head.set("Location", &v);
+        }
+        head.set_default("Date", &http::http_date(SystemTime::now()));
+        head.set_default("Server", SERVER_NAME);
+        for (k, v) in &self.opts.headers {
+            head.set(k, v);
+        }
+        // Only one request is served per connection.
+        head.set("Connection", "close");
+
+        let ctype = head.get("content-type").unwrap_or("").to_lowercase();
+        let is_stream = ctype.starts_with("text/event-stream");
+        self.mode = if head.code < 200 || head.code == 204 || head.code == 304 {
+            head.remove("content-length");
+            Mode::NoBody
+        } else if self.opts.is_head {
+            // Content-Length of the body which GET would get.
+            Mode::NoBody
+        } else if is_app_chunked {
+            head.remove("content-length");
+            head.set("Transfer-Encoding", "chunked");
+            Mode::Passthrough
+        } else if self.opts.is_accept_brotli && head.code == 200
+                && head.get("content-encoding").is_none()
+                && compress::is_compressable_type(&ctype) {
+            head.remove("content-length");
+            head.set("Content-Encoding", "br");
+            head.set("Vary", "Accept-Encoding");
+            head.set("Transfer-Encoding", "chunked");
+            Mode::Brotli(Box::new(compress::stream_compressor()))
+        } else if is_stream && head.get("content-length").is_none() {
+            head.set("Transfer-Encoding", "chunked");
+            Mode::Chunked
+        } else if is_stream {
+            Mode::Passthrough
+        } else {
+            Mode::Buffering
+        };
+        let out = match self.mode {
+            Mode::Buffering => vec![],
+            _ => head.to_bytes(),
+        };
+        self.head = Some(head);
+        out
+    }
+
+    fn feed_body(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        self.body_len += data.len();
+        let out = match &mut self.mode {
+            Mode::Buffering => {
+                self.body.extend_from_slice(data);
+                if self.body.len() <= MAX_BUFFERED_BODY {
+                    return Ok(vec![]);
+                }
+                // Too large to buffer: trust the app's Content-Length or
+                // fall back to chunked encoding.
+                let head = self.head.as_mut().unwrap();
+                let body = std::mem::take(&mut self.body);
+                let mut out;
+                if head.get("content-length").is_some() {
+                    out = head.to_bytes();
+                    out.extend(body);
+                    self.mode = Mode::Passthrough;
+                } else {
+                    head.set("Transfer-Encoding", "chunked");
+                    out = head.to_bytes();
+                    out.extend(chunk(&body));
+                    self.mode = Mode::Chunked;
+                }
+                out
+            },
+            Mode::Passthrough => data.to_vec(),
+            Mode::Chunked => chunk(data),
+            Mode::Brotli(writer) => {
+                writer.write_all(data).map_err(|e| e.to_string())?;
+                writer.flush().map_err(|e| e.to_string())?;
+                chunk(&std::mem::take(writer.get_mut()))
+            },
+            Mode::NoBody => vec![],
+        };
+        Ok(out)
+    }
+}
+
+fn chunk(data: &[u8]) -> Vec<u8> {
+    if data.is_empty() { return vec![] }
+    let mut out = format!("{:x}\r\n", data.len()).into_bytes();
+    out.extend_from_slice(data);
+    out.extend_from_slice(b"\r\n");
+    out
+}
@@ -0,0 +1,202 @@
+// CGI/1.1 request meta-variables (RFC 3875) and response parsing, shared
+// by the FastCGI, SCGI and uwsgi backends.
+
+use std::fmt;
+use memchr::memmem;
+use serde::Deserialize;
+use crate::app_resp::SERVER_NAME;
+use crate::conf::CgiConf;
+use crate::proxy::{ForwardInfo, RawHead};
+use crate::urlencoded::percent_decode;
+
+
+pub const MAX_CGI_HEAD_SIZE: usize = 1024 * 64;
+
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CgiProtocol {
+    #[default]
+    Fastcgi,
+    Scgi,
+    Uwsgi,
+}
+
+impl fmt::Display for CgiProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CgiProtocol::Fastcgi => write!(f, "FastCGI"),
+            CgiProtocol::Scgi => write!(f, "SCGI"),
+            CgiProtocol::Uwsgi => write!(f, "uwsgi"),
+        }
+    }
+}
+
+/// Splits a decoded request path into SCRIPT_NAME and PATH_INFO:
+/// "/app.php/users" => ("/app.php", "/users"). Paths without a .php script
+/// go to the index script, e.g. a front controller.
+pub fn split_script(path: &str, index: &str) -> (String, String) {
+    if let Some(idx) = path.find(".php") {
+        let end = idx + ".php".len();
+        if path.len() == end || path[end..].starts_with('/') {
+            return (path[..end].to_string(), path[end..].to_string());
+        }
+    }
+    (format!("/{}", index.trim_start_matches('/')), path.to_string())
+}
+
+/// Builds CGI meta-variables of a request.
+pub fn cgi_env(raw: &RawHead, fwd: &ForwardInfo, conf: &CgiConf, server_port: u16
+               ) -> Result<Vec<(String, String)>, String> {
+    let mut parts = raw.request_line.split(' ');
+    let method = parts.next().unwrap_or("").to_uppercase();
+    let target = parts.next().unwrap_or("");
+    let query = target.split_once('?').map(|(_, q)| q).unwrap_or("");
+    let path = String::from_utf8(percent_decode(raw.path()))
+        .map_err(|_| "path is not utf-8".to_string())?;
+    if path.split('/').any(|v| v == "..") || path.contains('\0') {
+        return Err(format!("bad path: {path}"));
+    }
+    // WSGI and similar apps are mounted at the root and route by PATH_INFO.
+    let (script_name, path_info) = match conf.protocol {
+        CgiProtocol::Fastcgi => split_script(&path, &conf.index),
+        _ => (String::new(), path),
+    };
+    let root = conf.root.trim_end_matches('/');
+    let server_name = match fwd.host.rsplit_once(':') {
+        Some((v, port)) if port.chars().all(|c| c.is_ascii_digit()) => v,
+        _ => &fwd.host,
+    };
+
+    let mut env: Vec<(String, String)> = vec![];
+    let mut set = |k: &str, v: &str| env.push((k.to_string(), v.to_string()));
+    set("GATEWAY_INTERFACE", "CGI/1.1");
+    set("SERVER_SOFTWARE", SERVER_NAME);
+    set("SERVER_PROTOCOL", "HTTP/1.1");
+    set("SERVER_NAME", server_name);
+    set("SERVER_PORT", &server_port.to_string());
+    set("REQUEST_SCHEME", &fwd.proto);
+    if fwd.proto == "https" {
+        set("HTTPS", "on");
+    }
+    set("REQUEST_METHOD", &method);
+    set("REQUEST_URI", target);
+    set("QUERY_STRING", query);
+    set("SCRIPT_NAME", &script_name);
+    set("PATH_INFO", &path_info);
+    if !root.is_empty() {
+        set("DOCUMENT_ROOT", root);
+        set("SCRIPT_FILENAME", &format!("{root}{script_name}"));
+        set("PATH_TRANSLATED", &format!("{root}{path_info}"));
+    }
+    if let Some(ip) = fwd.client_ip {
+        set("REMOTE_ADDR", &ip.to_string());
+    }
+    // As mod_ssl names them.
+    if let Some(cert) = &fwd.client_cert {
+        set("SSL_CLIENT_VERIFY", "SUCCESS");
+        set("SSL_CLIENT_S_DN", &cert.subject);
+        set("SSL_CLIENT_SAN", &cert.san.join(", "));
+        set("SSL_CLIENT_FINGERPRINT", &cert.fingerprint);
+    }
+    if let Some(v) = raw.get("content-type") {
+        set("CONTENT_TYPE", v);
+    }
+    set("CONTENT_LENGTH", raw.get("content-length").unwrap_or("0"));
+
+    for (k, v) in &raw.headers {
+        let name = k.to_lowercase();
+        // Proxy: would become HTTP_PROXY and redirect outgoing requests
+        // of the script (httpoxy).
+        if matches!(name.as_str(), "content-type" | "content-length" | "proxy") {
+            continue;
+        }
+        let name = format!("HTTP_{}", name.to_uppercase().replace('-', "_"));
+        match env.iter_mut().find(|(k, _)| *k == name) {
+            Some((_, prev)) => { prev.push_str(", "); prev.push_str(v); },
+            None => env.push((name, v.to_string())),
+        }
+    }
+    for (k, v) in &conf.params {
+        env.retain(|(name, _)| name != k);
+        env.push((k.to_string(), v.to_string()));
+    }
+    Ok(env)
+}
+
+
+/// Turns a CGI response ("Status: 404 Not Found", headers, body) into
+/// an HTTP response.
+#[derive(Default)]
+pub struct CgiResp {
+    head_buf: Vec<u8>,
+    is_head_done: bool,
+}
+
+impl CgiResp {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        if self.is_head_done {
+            return Ok(data.to_vec());
+        }
+        self.head_buf.extend_from_slice(data);
+        let crlf = memmem::find(&self.head_buf, b"\r\n\r\n").map(|v| (v, 4));
+        let lf = memmem::find(&self.head_buf, b"\n\n").map(|v| (v, 2));
+        let (end, sep_len) = match (crlf, lf) {
+            (Some(a), Some(b)) => if a.0 <= b.0 { a } else { b },
+            (Some(v), None) | (None, Some(v)) => v,
+            (None, None) if self.head_buf.len() > MAX_CGI_HEAD_SIZE => {
+                return Err("CGI response head is too large".to_string());
+            },
+            (None, None) => return Ok(vec![]),
+        };
+        let mut out = http_head(&self.head_buf[..end])?;
+        out.extend_from_slice(&self.head_buf[end + sep_len..]);
+        self.head_buf = vec![];
+        self.is_head_done = true;
+        Ok(out)
+    }
+
+    pub fn finish(&self) -> Result<(), String> {
+        match self.is_head_done {
+            true => Ok(()),
+            false => Err("incomplete CGI response head".to_string()),
+        }
+    }
+}
+
+fn http_head(head: &[u8]) -> Result<Vec<u8>, String> {
+    let head = std::str::from_utf8(head).map_err(|_| "CGI head is not utf-8".to_string())?;
+    let mut status = None;
+    let mut has_location = false;
+    let mut headers = String::new();
+    for line in head.split('\n').map(|v| v.trim_end_matches('\r')).filter(|v| !v.is_empty()) {
+        let (k, v) = match line.split_once(':') {
+            Some((k, v)) if !k.is_empty() && !k.contains(' ') => (k, v.trim()),
+            _ => return Err(format!("bad CGI header line: {line}")),
+        };
+        if k.eq_ignore_ascii_case("status") {
+            status = Some(v.to_string());
+            continue;
+        }
+        if k.eq_ignore_ascii_case("location") {
+            has_location = true;
+        }
+        headers.push_str(&format!("{k}: {v}\r\n"));
+    }
+    let status = match status {
+        Some(v) => {
+            let code = v.split(' ').next().unwrap_or("");
+            if code.len() != 3 || code.parse::<u16>().is_err() {
+                return Err(format!("bad CGI status: {v}"));
+            }
+            v
+        },
+        None if has_location => "302 Found".to_string(),
+        None => "200 OK".to_string(),
+    };
+    Ok(format!("HTTP/1.1 {status}\r\n{headers}\r\n").into_bytes())
+}
@@ -19,6 +19,22 @@ pub fn compress(buf: &Vec<u8>) -> Vec<u8> {
 	brotli_buf
 }
 
+pub type BrotliWriter = brotli::CompressorWriter<Vec<u8>>;
+
+/// Brotli writer for bodies compressed on the fly. Quality is lower than
+/// in compress() since it runs on every response.
+pub fn stream_compressor() -> BrotliWriter {
+	brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22)
+}
+
+pub fn is_compressable_type(content_type: &str) -> bool {
+	let ctype = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+	if ctype == "text/event-stream" { return false }
+	ctype.starts_with("text/")
+		|| ["application/json", "application/javascript", "application/xml",
+			"image/svg+xml"].contains(&ctype.as_str())
+}
+
 pub fn is_compressable(hp: RequestParser) -> bool {
 	if hp.is_accept_brotli() == false { return false }
 	let path = hp.get_header("static_path").split("?").next().unwrap().to_string();
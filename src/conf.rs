@@ -1,9 +1,14 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 use once_cell::sync::Lazy;
 use async_lock::RwLock;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+use serde::de::Error;
+use crate::cgi::CgiProtocol;
+use crate::tls::{ClientAuthMode, TlsBackend, TlsVersion};
+use crate::upstream::Balance;
 
 
 pub static CONF: Lazy<RwLock<Conf>> = Lazy::new(|| {
@@ -15,10 +20,177 @@ pub static CONF: Lazy<RwLock<Conf>> = Lazy::new(|| {
 pub struct ServerConf {
     pub name: String,
     pub hostnames: Vec<String>,
+    /// Single app socket, used when `upstreams` is empty.
+    #[serde(default)]
     pub socket_path: String,
+    /// App servers: unix socket paths ("unix:/path" or "/path")
+    /// and TCP addresses ("tcp:host:port" or "host:port").
+    #[serde(default)]
+    pub upstreams: Vec<String>,
+    #[serde(default)]
+    pub balance: Balance,
+    /// Consecutive failures after which an upstream is ejected
+    /// for `fail_timeout_secs`.
+    #[serde(default = "default_max_fails")]
+    pub max_fails: u32,
+    #[serde(default = "default_fail_timeout_secs")]
+    pub fail_timeout_secs: u64,
+    /// 0 disables active health checks.
+    #[serde(default = "default_health_check_interval_secs")]
+    pub health_check_interval_secs: u64,
+    #[serde(default = "default_health_check_timeout_ms")]
+    pub health_check_timeout_ms: u64,
+    /// Requested by health checks if set, must answer 2xx.
+    #[serde(default)]
+    pub health_check_path: String,
     pub static_dir: String,
     pub dev_static_dir: String,
     pub index_path: String,
+    #[serde(default)]
+    pub upload_allowed_extensions: Vec<String>,
+    #[serde(default)]
+    pub upload_allowed_mime_types: Vec<String>,
+    #[serde(default = "default_pool_size")]
+    pub pool_size: usize,
+    #[serde(default = "default_pool_max_idle_secs")]
+    pub pool_max_idle_secs: u64,
+    #[serde(default = "default_pool_queue_limit")]
+    pub pool_queue_limit: usize,
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    #[serde(default = "default_write_timeout_ms")]
+    pub write_timeout_ms: u64,
+    #[serde(default = "default_first_byte_timeout_ms")]
+    pub first_byte_timeout_ms: u64,
+    #[serde(default = "default_read_timeout_ms")]
+    pub read_timeout_ms: u64,
+    #[serde(default = "default_total_timeout_ms")]
+    pub total_timeout_ms: u64,
+    /// Added to app responses, replacing headers set by the app.
+    #[serde(default)]
+    pub response_headers: HashMap<String, String>,
+    /// Status code => path of an html page sent instead of the plain text.
+    #[serde(default)]
+    pub error_pages: HashMap<String, String>,
+    /// Plain HTTP/1.1 backend for the whole server: "http://host:port",
+    /// "host:port" or "unix:/path".
+    #[serde(default)]
+    pub proxy_pass: String,
+    #[serde(default)]
+    pub proxy_routes: Vec<ProxyRoute>,
+    /// CGI-style backend: php-fpm over FastCGI, Python apps over SCGI
+    /// or uwsgi.
+    #[serde(default)]
+    pub cgi: Option<CgiConf>,
+    /// Open WebSocket tunnels of the server, further upgrades get 503.
+    #[serde(default = "default_websocket_max_connections")]
+    pub websocket_max_connections: usize,
+    /// Tunnels without traffic in either direction are closed after this.
+    #[serde(default = "default_websocket_idle_timeout_secs")]
+    pub websocket_idle_timeout_secs: u64,
+    /// Paths where miarh terminates WebSockets itself and bridges their
+    /// messages to the app.
+    #[serde(default)]
+    pub websocket_paths: Vec<String>,
+    #[serde(default = "default_websocket_max_message_kb")]
+    pub websocket_max_message_kb: usize,
+    /// Certificate of the server's hostnames, picked by SNI. Servers
+    /// without one use the top-level certificate.
+    #[serde(default)]
+    pub tls: Option<TlsConf>,
+    /// Client certificates asked for on handshakes for the server's
+    /// hostnames.
+    #[serde(default)]
+    pub client_auth: Option<ClientAuthConf>,
+    /// Status of redirects to HTTPS and to canonical_host: 301, or 308
+    /// which keeps the method and body.
+    #[serde(default = "default_redirect_code", deserialize_with = "redirect_code")]
+    pub redirect_code: u16,
+    /// Serve requests on http_port too, instead of redirecting them to
+    /// HTTPS.
+    #[serde(default)]
+    pub serve_http: bool,
+    /// Requests for the other hostnames are redirected to this one, e.g.
+    /// "example.com" for "www.example.com". Empty serves them all.
+    #[serde(default)]
+    pub canonical_host: String,
+    /// Strict-Transport-Security of HTTPS responses.
+    #[serde(default)]
+    pub hsts: Option<HstsConf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CgiConf {
+    #[serde(default)]
+    pub protocol: CgiProtocol,
+    /// "unix:/run/php-fpm.sock" or "127.0.0.1:9000".
+    pub pass: String,
+    /// Document root on the backend side, SCRIPT_FILENAME is root + script.
+    #[serde(default)]
+    pub root: String,
+    /// Script of requests which don't name one, e.g. a front controller.
+    #[serde(default = "default_cgi_index")]
+    pub index: String,
+    /// Extra params, replacing the generated ones.
+    #[serde(default)]
+    pub params: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HstsConf {
+    pub max_age_secs: u64,
+    #[serde(default)]
+    pub include_subdomains: bool,
+    /// Asks for inclusion in the browsers' preload lists, which also need
+    /// a max-age of a year and includeSubDomains.
+    #[serde(default)]
+    pub preload: bool,
+}
+
+impl HstsConf {
+    pub fn header_value(&self) -> String {
+        let mut v = format!("max-age={}", self.max_age_secs);
+        if self.include_subdomains {
+            v.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            v.push_str("; preload");
+        }
+        v
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProxyRoute {
+    /// Path prefix, e.g. "/api/".
+    pub prefix: String,
+    pub proxy_pass: String,
+}
+
+fn default_max_fails() -> u32 { 3 }
+fn default_cgi_index() -> String { "index.php".to_string() }
+fn default_fail_timeout_secs() -> u64 { 10 }
+fn default_health_check_interval_secs() -> u64 { 10 }
+fn default_health_check_timeout_ms() -> u64 { 2_000 }
+fn default_pool_size() -> usize { 16 }
+fn default_pool_max_idle_secs() -> u64 { 60 }
+fn default_pool_queue_limit() -> usize { 64 }
+fn default_connect_timeout_ms() -> u64 { 5_000 }
+fn default_write_timeout_ms() -> u64 { 30_000 }
+fn default_first_byte_timeout_ms() -> u64 { 60_000 }
+fn default_read_timeout_ms() -> u64 { 60_000 }
+fn default_total_timeout_ms() -> u64 { 120_000 }
+fn default_websocket_max_connections() -> usize { 1024 }
+fn default_websocket_idle_timeout_secs() -> u64 { 300 }
+fn default_websocket_max_message_kb() -> usize { 1024 }
+fn default_redirect_code() -> u16 { 301 }
+
+/// Only the permanent redirects, the hostnames of a server don't change.
+fn redirect_code<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    match u16::deserialize(deserializer)? {
+        code @ (301 | 308) => Ok(code),
+        code => Err(D::Error::custom(format!("redirect_code {code}, expected 301 or 308"))),
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -26,12 +198,136 @@ pub struct Conf {
     pub ip: String,
     pub https_port: u16,
     pub http_port: u16,
+    /// HTTPS port the clients connect to, when it's forwarded to
+    /// https_port, e.g. 443. 0 if it's https_port.
+    #[serde(default)]
+    pub public_https_port: u16,
+    /// Serve every server on http_port, with no HTTPS listener and no
+    /// certificates, e.g. behind a load balancer which terminates TLS.
+    #[serde(default)]
+    pub plain_http: bool,
     pub acme_challenge_dir: String,
     pub acme_challenge_url: String,
     pub index_url: String,
     pub tmp_dir: String,
     pub servers: Vec<ServerConf>,
 	pub max_request_size_mb: usize,
+    #[serde(default = "default_max_upload_size_mb")]
+    pub max_upload_size_mb: usize,
+    #[serde(default = "default_max_upload_size_mb")]
+    pub max_upload_file_size_mb: usize,
+    #[serde(default)]
+    pub tls: TlsConf,
+    #[serde(default)]
+    pub acme: AcmeConf,
+}
+
+fn default_max_upload_size_mb() -> usize { 100 }
+
+/// Certificate of the HTTPS listener: a PEM chain and key if cert_path is
+/// set, otherwise a PKCS#12 bundle. The top-level one is the default for
+/// hostnames without their own.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TlsConf {
+    pub pkcs12_path: String,
+    pub pkcs12_password: String,
+    /// Leaf certificate first, then the intermediates.
+    pub cert_path: String,
+    pub key_path: String,
+    /// Refuse handshakes for names not in any server's hostnames, instead
+    /// of answering with the default certificate. Top-level only.
+    pub reject_unknown_hostnames: bool,
+    /// Cert files are checked for changes this often, 0 disables it.
+    /// Top-level only, like reload_url.
+    pub watch_interval_secs: u64,
+    /// Admin endpoint which reloads the certificates on POST from
+    /// localhost, disabled if empty.
+    pub reload_url: String,
+    /// Get and renew the certificate with the ACME client, for the
+    /// hostnames using it. cert_path and key_path are written then.
+    pub acme: bool,
+    /// Handshake settings below are top-level only, they apply to all
+    /// certificates.
+    pub backend: TlsBackend,
+    pub min_version: TlsVersion,
+    /// rustls suite names, e.g. "TLS13_AES_128_GCM_SHA256". Empty keeps
+    /// the defaults.
+    pub cipher_suites: Vec<String>,
+    /// ALPN protocols in order of preference, rustls only. Only "http/1.1"
+    /// is accepted until there is h2.
+    pub alpn: Vec<String>,
+    /// Stateless resumption tickets besides the session cache, rustls only.
+    pub session_tickets: bool,
+    /// Appends session secrets to the file named by SSLKEYLOGFILE, for
+    /// debugging with Wireshark. rustls only.
+    pub key_log: bool,
+}
+
+impl Default for TlsConf {
+    fn default() -> Self {
+        Self {
+            pkcs12_path: "identity.pfx".to_string(),
+            pkcs12_password: String::new(),
+            cert_path: String::new(),
+            key_path: String::new(),
+            reject_unknown_hostnames: false,
+            watch_interval_secs: 60,
+            reload_url: String::new(),
+            acme: false,
+            backend: TlsBackend::default(),
+            min_version: TlsVersion::default(),
+            cipher_suites: vec![],
+            alpn: vec!["http/1.1".to_string()],
+            session_tickets: true,
+            key_log: false,
+        }
+    }
+}
+
+/// Client certificates of a server. They are checked on the handshake,
+/// then again for each request by its Host, as a connection made for one
+/// hostname may send requests for another.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientAuthConf {
+    /// PEM bundle of the CAs which issue the client certificates.
+    pub ca_path: String,
+    #[serde(default)]
+    pub mode: ClientAuthMode,
+    /// Patterns of the subject, e.g. "CN=*,OU=ops,O=Example", with `*`
+    /// for any text within a value. Empty allows every certificate of the
+    /// CAs.
+    #[serde(default)]
+    pub allowed_subjects: Vec<String>,
+}
+
+/// ACME client of the certificates with `acme = true`, HTTP-01 challenges
+/// are answered on http_port at acme_challenge_url.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AcmeConf {
+    pub directory_url: String,
+    pub contact_email: String,
+    /// Created if it doesn't exist.
+    pub account_key_path: String,
+    /// PEM certificates trusted for the ACME server besides the system
+    /// ones, e.g. the CA of a test server.
+    pub ca_cert_path: String,
+    pub renew_before_days: u32,
+    pub check_interval_hours: u64,
+}
+
+impl Default for AcmeConf {
+    fn default() -> Self {
+        Self {
+            directory_url: "https://acme-v02.api.letsencrypt.org/directory".to_string(),
+            contact_email: String::new(),
+            account_key_path: "acme_account.key".to_string(),
+            ca_cert_path: String::new(),
+            renew_before_days: 30,
+            check_interval_hours: 12,
+        }
+    }
 }
 
 impl Conf {
@@ -43,4 +339,43 @@ impl Conf {
         let conf: Conf = toml::from_str(&contents).unwrap();
         return conf;
     }
+    pub fn server(&self, host: &str) -> Option<&ServerConf> {
+        self.servers.iter().find(|srv| srv.hostnames.iter().any(|h| h == host))
+    }
+    /// Port of the HTTPS URLs of redirects.
+    pub fn redirect_https_port(&self) -> u16 {
+        match self.public_https_port {
+            0 => self.https_port,
+            v => v,
+        }
+    }
+}
+
+impl ServerConf {
+    pub fn upstreams(&self) -> Vec<String> {
+        if self.upstreams.is_empty() && !self.socket_path.is_empty() {
+            return vec![self.socket_path.to_string()];
+        }
+        self.upstreams.clone()
+    }
+    /// Hostname a request for `hostname` is redirected to, if it's not the
+    /// canonical one.
+    pub fn canonical_redirect(&self, hostname: &str) -> Option<&str> {
+        if self.canonical_host.is_empty() || self.canonical_host.eq_ignore_ascii_case(hostname) {
+            return None;
+        }
+        Some(&self.canonical_host)
+    }
+    /// HTTP backend of a request path: the longest matching route prefix,
+    /// or proxy_pass of the server.
+    pub fn proxy_for(&self, path: &str) -> Option<&str> {
+        let route = self.proxy_routes.iter()
+            .filter(|r| path.starts_with(&r.prefix))
+            .max_by_key(|r| r.prefix.len());
+        match route {
+            Some(r) => Some(&r.proxy_pass),
+            None if !self.proxy_pass.is_empty() => Some(&self.proxy_pass),
+            None => None,
+        }
+    }
 }
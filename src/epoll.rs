@@ -11,6 +11,7 @@ pub const EPOLL_MAX_WAIT_TIME: u32 = 5000;
 
 pub const EPOLL_HTTPS_LISTENER_ID: u64 = 0;
 pub const EPOLL_HTTP_LISTENER_ID: u64 = 1;
+pub const EPOLL_SIGNAL_ID: u64 = 2;
 pub const EPOLL_HTTPS_TCP_STREAM_START_ID: u64 = 18_000_000_000_000_000_000;
 pub const EPOLL_TLS_STREAM_START_ID: u64 = 10_000_000_000_000_000_000;
 
@@ -40,14 +41,17 @@ impl Epoll {
             tls_stream_id: EPOLL_TLS_STREAM_START_ID,
         })
     }
-    pub fn reg_listeners(&self, https_listener_fd: i32, http_listener_fd: i32
+    /// No HTTPS listener in plain HTTP mode.
+    pub fn reg_listeners(&self, https_listener_fd: Option<i32>, http_listener_fd: i32
                          ) -> Result<(), Error> {
-        let _ = add_interest(
-            self.epoll_fd, https_listener_fd,
-            libc::epoll_event {
-                events: READ_FLAG as u32, u64: EPOLL_HTTPS_LISTENER_ID
-            }
-        )?;
+        if let Some(https_listener_fd) = https_listener_fd {
+            let _ = add_interest(
+                self.epoll_fd, https_listener_fd,
+                libc::epoll_event {
+                    events: READ_FLAG as u32, u64: EPOLL_HTTPS_LISTENER_ID
+                }
+            )?;
+        }
         let _ = add_interest(
             self.epoll_fd, http_listener_fd,
             libc::epoll_event { 
@@ -56,6 +60,12 @@ impl Epoll {
         )?;
         Ok(())
     }
+    pub fn reg_signals(&self, signal_fd: i32) -> Result<(), Error> {
+        add_interest(
+            self.epoll_fd, signal_fd,
+            libc::epoll_event { events: READ_FLAG as u32, u64: EPOLL_SIGNAL_ID }
+        )
+    }
     pub fn reg_tls_stream(&mut self, stream_fd: i32) -> Result<(), Error> {
         self.tls_stream_id += 1;
         self.reg_stream(stream_fd, self.tls_stream_id)?;
@@ -93,6 +103,29 @@ fn add_interest(epoll_fd: RawFd, fd: RawFd, mut event: libc::epoll_event
     Ok(())
 }
 
+/// Blocks `signals` and returns a signalfd to read them from instead.
+/// Threads started afterwards inherit the mask, so call it before any.
+pub fn signal_fd(signals: &[i32]) -> Result<RawFd, Error> {
+    let mut mask: libc::sigset_t = unsafe { std::mem::zeroed() };
+    syscall!(sigemptyset(&mut mask))?;
+    for signal in signals {
+        syscall!(sigaddset(&mut mask, *signal))?;
+    }
+    let res = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &mask, std::ptr::null_mut()) };
+    if res != 0 {
+        return Err(Error::from_raw_os_error(res));
+    }
+    syscall!(signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC))
+}
+
+/// Number of the next pending signal, WouldBlock if there's none.
+pub fn read_signal(signal_fd: RawFd) -> Result<u32, Error> {
+    let mut info: libc::signalfd_siginfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::signalfd_siginfo>();
+    syscall!(read(signal_fd, &mut info as *mut _ as *mut libc::c_void, size))?;
+    Ok(info.ssi_signo)
+}
+
 pub fn rearm_interest(epoll_fd: RawFd, fd: RawFd, ev_id: u64) -> Result<(), Error> {
     let mut ev = libc::epoll_event { events: READ_ONESHOT_FLAGS as u32, u64: ev_id };
     syscall!(epoll_ctl(epoll_fd, libc::EPOLL_CTL_MOD, fd, &mut ev))?;
@@ -0,0 +1,206 @@
+// FastCGI client, https://fastcgi-archives.github.io/FastCGI_Specification.html
+//
+// One request per connection: BEGIN_REQUEST without FCGI_KEEP_CONN, PARAMS
+// records terminated by an empty one, STDIN records terminated by an empty
+// one. The app answers with STDOUT and STDERR records and END_REQUEST.
+
+use std::fmt;
+use std::io;
+use futures_lite::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::app_pool::AppError;
+
+
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 8;
+pub const MAX_CONTENT_LEN: usize = 65535;
+pub const REQUEST_ID: u16 = 1;
+pub const ROLE_RESPONDER: u16 = 1;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordType {
+    BeginRequest = 1,
+    AbortRequest = 2,
+    EndRequest = 3,
+    Params = 4,
+    Stdin = 5,
+    Stdout = 6,
+    Stderr = 7,
+    Data = 8,
+    GetValues = 9,
+    GetValuesResult = 10,
+    UnknownType = 11,
+}
+
+impl RecordType {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(RecordType::BeginRequest),
+            2 => Some(RecordType::AbortRequest),
+            3 => Some(RecordType::EndRequest),
+            4 => Some(RecordType::Params),
+            5 => Some(RecordType::Stdin),
+            6 => Some(RecordType::Stdout),
+            7 => Some(RecordType::Stderr),
+            8 => Some(RecordType::Data),
+            9 => Some(RecordType::GetValues),
+            10 => Some(RecordType::GetValuesResult),
+            11 => Some(RecordType::UnknownType),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Record {
+    pub kind: RecordType,
+    pub request_id: u16,
+    pub content: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum FcgiError {
+    Io(io::Error),
+    UnknownRecord(u8),
+    Unexpected(RecordType),
+    /// END_REQUEST with a protocol status other than REQUEST_COMPLETE.
+    Rejected(u8),
+}
+
+impl fmt::Display for FcgiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FcgiError::Io(e) => write!(f, "FastCGI io err: {e}"),
+            FcgiError::UnknownRecord(v) => write!(f, "Unknown FastCGI record type: {v}"),
+            FcgiError::Unexpected(v) => write!(f, "Unexpected FastCGI record: {v:?}"),
+            FcgiError::Rejected(1) => write!(f, "FastCGI server can't multiplex connections"),
+            FcgiError::Rejected(2) => write!(f, "FastCGI server is overloaded"),
+            FcgiError::Rejected(3) => write!(f, "FastCGI server doesn't support the role"),
+            FcgiError::Rejected(v) => write!(f, "FastCGI request rejected: {v}"),
+        }
+    }
+}
+
+impl From<io::Error> for FcgiError {
+    fn from(e: io::Error) -> Self {
+        FcgiError::Io(e)
+    }
+}
+
+impl From<FcgiError> for AppError {
+    fn from(e: FcgiError) -> Self {
+        match e {
+            FcgiError::Io(e) => AppError::Io(e),
+            e => AppError::InvalidResponse(e.to_string()),
+        }
+    }
+}
+
+
+pub fn encode_record(kind: RecordType, content: &[u8]) -> Vec<u8> {
+    let padding = (8 - content.len() % 8) % 8;
+    let mut record = Vec::with_capacity(HEADER_LEN + content.len() + padding);
+    record.push(VERSION);
+    record.push(kind as u8);
+    record.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    record.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    record.push(padding as u8);
+    record.push(0);
+    record.extend_from_slice(content);
+    record.resize(record.len() + padding, 0);
+    record
+}
+
+/// Records of a stream (PARAMS, STDIN), without the terminating empty one.
+pub fn encode_stream(kind: RecordType, data: &[u8]) -> Vec<u8> {
+    data.chunks(MAX_CONTENT_LEN).flat_map(|v| encode_record(kind, v)).collect()
+}
+
+fn encode_len(out: &mut Vec<u8>, len: usize) {
+    if len < 128 {
+        out.push(len as u8);
+    } else {
+        out.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+pub fn encode_params(params: &[(String, String)]) -> Vec<u8> {
+    let mut out = vec![];
+    for (k, v) in params {
+        encode_len(&mut out, k.len());
+        encode_len(&mut out, v.len());
+        out.extend_from_slice(k.as_bytes());
+        out.extend_from_slice(v.as_bytes());
+    }
+    out
+}
+
+/// BEGIN_REQUEST, PARAMS and the start of STDIN. The rest of the body goes
+/// with `encode_stream(Stdin, ..)` and the request ends with `end_stdin()`.
+pub fn begin_request(params: &[(String, String)]) -> Vec<u8> {
+    let mut begin = ROLE_RESPONDER.to_be_bytes().to_vec();
+    // Flags: no FCGI_KEEP_CONN, the app closes the connection.
+    begin.extend_from_slice(&[0; 6]);
+    let mut out = encode_record(RecordType::BeginRequest, &begin);
+    out.extend(encode_stream(RecordType::Params, &encode_params(params)));
+    out.extend(encode_record(RecordType::Params, &[]));
+    out
+}
+
+pub fn end_stdin() -> Vec<u8> {
+    encode_record(RecordType::Stdin, &[])
+}
+
+pub async fn write_record<W: AsyncWrite + Unpin>(w: &mut W, kind: RecordType, content: &[u8]
+                                                 ) -> Result<(), FcgiError> {
+    w.write_all(&encode_record(kind, content)).await?;
+    Ok(())
+}
+
+pub async fn read_record<R: AsyncRead + Unpin>(r: &mut R) -> Result<Record, FcgiError> {
+    let mut header = [0; HEADER_LEN];
+    r.read_exact(&mut header).await?;
+    let kind = match RecordType::from_u8(header[1]) {
+        Some(v) => v,
+        None => return Err(FcgiError::UnknownRecord(header[1])),
+    };
+    let request_id = u16::from_be_bytes([header[2], header[3]]);
+    let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let mut content = vec![0; len + header[6] as usize];
+    r.read_exact(&mut content).await?;
+    content.truncate(len);
+    Ok(Record { kind, request_id, content })
+}
+
+/// Part of a FastCGI response.
+#[derive(Debug, PartialEq)]
+pub enum Output {
+    Stdout(Vec<u8>),
+    /// The response is complete.
+    End,
+}
+
+/// Reads records until there's stdout data or the end of the request.
+/// STDERR is logged.
+pub async fn read_output<R: AsyncRead + Unpin>(r: &mut R) -> Result<Output, FcgiError> {
+    loop {
+        let record = read_record(r).await?;
+        if record.request_id != REQUEST_ID {
+            continue;
+        }
+        match record.kind {
+            RecordType::Stdout if record.content.is_empty() => continue,
+            RecordType::Stdout => return Ok(Output::Stdout(record.content)),
+            RecordType::Stderr => {
+                println!("FastCGI stderr: {}", String::from_utf8_lossy(&record.content).trim_end());
+            },
+            RecordType::EndRequest => {
+                return match record.content.get(4) {
+                    Some(0) | None => Ok(Output::End),
+                    Some(v) => Err(FcgiError::Rejected(*v)),
+                };
+            },
+            kind => return Err(FcgiError::Unexpected(kind)),
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use serde_json::{Map, Value};
+
+
+/// Form fields of a urlencoded or multipart body, serialized to
+/// `body_string`. Text values are always strings, files are objects.
+/// Repeated field names are collected into arrays.
+#[derive(Debug, Default)]
+pub struct FormFields {
+    map: Map<String, Value>,
+}
+
+impl FormFields {
+    pub fn new() -> Self {
+        Self { map: Map::new() }
+    }
+    pub fn insert(&mut self, name: String, value: Value) {
+        match self.map.get_mut(&name) {
+            None => { self.map.insert(name, value); },
+            Some(Value::Array(values)) => values.push(value),
+            Some(prev) => {
+                let first = prev.take();
+                *prev = Value::Array(vec![first, value]);
+            },
+        }
+    }
+    pub fn to_json_string(&self) -> String {
+        Value::Object(self.map.clone()).to_string()
+    }
+}
@@ -4,6 +4,7 @@ use std::path::Path;
 use cookie::Cookie;
 use miarh_saras_http::{ Request, RequestFile };
 use crate::conf::CONF;
+use crate::multipart::TmpFile;
 
 
 pub const MAX_HEADERS_SIZE: usize = 2048;
@@ -16,11 +17,13 @@ pub struct RequestParser {
     pub is_static: bool,
     pub is_static_valid: bool,
     pub is_multipart: bool,
+    pub is_urlencoded: bool,
     pub headers_len: usize,
     pub body: Vec<u8>,
     pub body_string: String,
     pub route: HashMap<String, String>,
     pub files: HashMap<String, RequestFile>,
+    pub tmp_files: Vec<TmpFile>,
 }
 impl RequestParser {
     fn new() -> Self {
@@ -30,11 +33,13 @@ impl RequestParser {
             is_static: false,
             is_static_valid: false,
             is_multipart: false,
+            is_urlencoded: false,
             headers_len: 0,
             body: vec![],
             body_string: String::new(),
             route: HashMap::new(),
             files: HashMap::new(),
+            tmp_files: vec![],
         }
     }
     pub fn get_req(&mut self) -> Request {
@@ -58,15 +63,20 @@ impl RequestParser {
             None => "".to_string(),
         }
     }
-    pub fn is_valid(&self) -> bool {
+    /// Request line and Host were parsed, with a method of any name.
+    pub fn is_complete(&self) -> bool {
         let h = &self.parsed_headers;
-        if h.contains_key("method")
-            && h.contains_key("host")
-            && h.contains_key("path")
-            && (h.get("method").unwrap() == "get"
-                || h.get("method").unwrap() == "post"
-                || h.get("method").unwrap() == "put"
-                || h.get("method").unwrap() == "delete")
+        h.contains_key("method") && h.contains_key("host") && h.contains_key("path")
+    }
+    /// Methods of static files and of the app protocol. Proxied and CGI
+    /// backends take any.
+    pub fn is_app_method(&self) -> bool {
+        matches!(self.parsed_headers.get("method").map(|v| v.as_str()),
+                 Some("get" | "post" | "put" | "delete"))
+    }
+    pub fn is_valid(&self) -> bool {
+        if self.is_complete()
+            && self.is_app_method()
             && { self.is_static_valid == true || self.is_static == false }
         { return true }
         return false
@@ -78,10 +88,25 @@ impl RequestParser {
             return true }
         return false;
     }
+    /// WebSocket opening handshake, RFC 6455 4.1.
+    pub fn is_websocket_upgrade(&self) -> bool {
+        let h = &self.parsed_headers;
+        let has_token = |name: &str, token: &str| match h.get(name) {
+            Some(v) => v.split(",").any(|v| v == token),
+            None => false,
+        };
+        h.get("method").map(|v| v == "get").unwrap_or(false)
+            && has_token("upgrade", "websocket") && has_token("connection", "upgrade")
+    }
     pub async fn check_is_multipart(&mut self) {
         self.is_multipart = self.parsed_headers.contains_key("content-type")
             && self.parsed_headers.get("content-type").unwrap()
-            .contains("multipart/form-data");
+            .to_lowercase().contains("multipart/form-data");
+    }
+    pub async fn check_is_urlencoded(&mut self) {
+        self.is_urlencoded = self.parsed_headers.contains_key("content-type")
+            && self.parsed_headers.get("content-type").unwrap()
+            .to_lowercase().contains("application/x-www-form-urlencoded");
     }
     pub async fn check_is_static(&mut self) {
         if self.parsed_headers.contains_key("host")
@@ -107,6 +132,19 @@ impl RequestParser {
         for srv_conf in &conf.servers {
             for host in &srv_conf.hostnames {
                 if host == headers_host {
+                    // Paths of a static_dir, dev_static_dir or index_path
+                    // which the server doesn't have go to its backend.
+                    let is_unset = match path {
+                        p if p.starts_with("/static/") => srv_conf.static_dir.is_empty(),
+                        p if p.starts_with("/dev_static/") => srv_conf.dev_static_dir.is_empty(),
+                        p if p == &conf.index_url => srv_conf.index_path.is_empty(),
+                        _ => false,
+                    };
+                    if is_unset {
+                        self.remove_trailing_slash();
+                        self.is_static = false;
+                        return;
+                    }
                     if path.starts_with("/static/") {
                         let static_dir = Path::new(&srv_conf.static_dir);
                         let path = path.replace("/static/", "");
@@ -203,6 +241,9 @@ pub fn parse_headers(buffer: &Vec<u8>) -> RequestParser {
     for mut i in 0..max_headers_size {
         if buffer[i] == b'\r' && buffer[i+1] == b'\n' {
             match std::str::from_utf8(&buffer[start..i]) {
+                Ok(_line) if start == 0 => {
+                    parse_method_path_protocol(_line, &mut hp.parsed_headers);
+                },
                 Ok(_line) => {
                     parse_header_line(_line, &mut hp.parsed_headers);
                 },
@@ -226,24 +267,30 @@ pub fn parse_headers(buffer: &Vec<u8>) -> RequestParser {
 
 pub fn parse_header_line(line: &str, parsed_headers: &mut HashMap<String, String>) {
     let lowerline = line.to_lowercase();
-    if lowerline.starts_with("get ")
-            || lowerline.starts_with("post ") 
-            || lowerline.starts_with("delete ") 
-            || lowerline.starts_with("put ") {
-        parse_method_path_protocol(line, parsed_headers);
-    } else if lowerline.starts_with("host: ") {
+    if lowerline.starts_with("host: ") {
         parse_host(lowerline, parsed_headers);
     } else if lowerline.starts_with("content-length: ") {
         parse_content_len(lowerline, parsed_headers);
     } else if lowerline.starts_with("content-type: ") {
-        parse_content_type(lowerline, parsed_headers);
+        // Original case is kept, multipart boundary is case-sensitive.
+        parse_content_type(line, parsed_headers);
     } else if lowerline.starts_with("accept-encoding: ") {
         parse_accept_encoding(lowerline, parsed_headers);
     } else if lowerline.starts_with("cookie: ") {
         parse_cookies(&lowerline, parsed_headers);
+    } else if lowerline.starts_with("upgrade: ") {
+        parse_token_list("upgrade", lowerline, parsed_headers);
+    } else if lowerline.starts_with("connection: ") {
+        parse_token_list("connection", lowerline, parsed_headers);
     }
 }
 
+fn parse_token_list(name: &str, s: String, r: &mut HashMap<String, String>) {
+    let tokens: Vec<&str> = s[name.len() + 1..].split(",").map(|v| v.trim())
+        .filter(|v| !v.is_empty()).collect();
+    r.insert(name.to_string(), tokens.join(","));
+}
+
 fn parse_method_path_protocol(s: &str, r: &mut HashMap<String, String>) {
     let parts: Vec<&str> = s.split(" ").collect();
     if parts.len() != 3 { return };
@@ -252,8 +299,8 @@ fn parse_method_path_protocol(s: &str, r: &mut HashMap<String, String>) {
     let path = parts[1];
     let protocol = parts[2];
     let protocol = protocol.to_lowercase();
-    if method != "get" && method != "post" && method != "put" && method != "delete" {
-        println!("Unsupported method: {}", method);
+    if !is_token(&method) {
+        println!("Invalid method: {}", method);
         return;
     }
     if protocol != "http/1.1" {
@@ -265,6 +312,11 @@ fn parse_method_path_protocol(s: &str, r: &mut HashMap<String, String>) {
     r.insert("protocol".to_string(), protocol.to_string());
 }
 
+/// RFC 9110 token, e.g. a method name.
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|c| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c))
+}
+
 fn parse_host(s: String, r: &mut HashMap<String, String>) {
     let parts: Vec<&str> = s.split("host: ").collect();
     if parts.len() != 2 && parts.len() != 1 {
@@ -308,8 +360,10 @@ fn parse_cookies(s: &str, r: &mut HashMap<String, String>) {
         }
     }
 }
-fn parse_content_type(s: String, r: &mut HashMap<String, String>) {
-    let parts: Vec<&str> = s.split("content-type: ").collect();
-    let v = parts[1];
+fn parse_content_type(s: &str, r: &mut HashMap<String, String>) {
+    let v = match s.split_once(':') {
+        Some((_, v)) => v.trim(),
+        None => return,
+    };
     r.insert("content-type".to_string(), v.to_string());
 }
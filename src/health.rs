@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use async_io::Timer;
+use miarh_saras_http::Request;
+use crate::app_pool::AppError;
+use crate::app_proto;
+use crate::app_resp::RespHead;
+use crate::conf::ServerConf;
+use crate::timeout::{timeout, Phase};
+use crate::upstream::{UpstreamAddr, UpstreamGroup};
+
+
+#[derive(Debug, Clone)]
+pub struct HealthConf {
+    /// Consecutive failures which open the circuit.
+    pub max_fails: u32,
+    /// How long an open circuit rejects requests.
+    pub fail_timeout: Duration,
+    /// Zero disables active checks.
+    pub check_interval: Duration,
+    pub check_timeout: Duration,
+    /// Path of a GET request sent by active checks, which must answer 2xx.
+    /// Empty means connect and handshake only.
+    pub check_path: String,
+    pub check_host: String,
+}
+
+impl HealthConf {
+    pub fn from_server(srv: &ServerConf) -> Self {
+        Self {
+            max_fails: srv.max_fails.max(1),
+            fail_timeout: Duration::from_secs(srv.fail_timeout_secs),
+            check_interval: Duration::from_secs(srv.health_check_interval_secs),
+            check_timeout: Duration::from_millis(srv.health_check_timeout_ms),
+            check_path: srv.health_check_path.to_string(),
+            check_host: srv.hostnames.first().cloned().unwrap_or_default(),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerState {
+    Closed,
+    /// Requests fail fast until the time passes.
+    Open(Instant),
+    /// A single trial request decides whether to close the circuit.
+    HalfOpen,
+}
+
+struct BreakerInner {
+    state: BreakerState,
+    fails: u32,
+    is_probing: bool,
+}
+
+pub struct CircuitBreaker {
+    inner: Mutex<BreakerInner>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new() -> Self {
+        Self { inner: Mutex::new(BreakerInner {
+            state: BreakerState::Closed,
+            fails: 0,
+            is_probing: false,
+        })}
+    }
+
+    pub fn state(&self) -> BreakerState {
+        let mut inner = self.inner.lock().unwrap();
+        Self::refresh(&mut inner);
+        inner.state
+    }
+
+    /// Whether a request could be sent now, without taking the trial slot.
+    pub fn is_available(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        Self::refresh(&mut inner);
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open(_) => false,
+            BreakerState::HalfOpen => !inner.is_probing,
+        }
+    }
+
+    /// Admits a request. Returns (admitted, is_trial).
+    pub fn acquire(&self) -> (bool, bool) {
+        let mut inner = self.inner.lock().unwrap();
+        Self::refresh(&mut inner);
+        match inner.state {
+            BreakerState::Closed => (true, false),
+            BreakerState::Open(_) => (false, false),
+            BreakerState::HalfOpen if inner.is_probing => (false, false),
+            BreakerState::HalfOpen => {
+                inner.is_probing = true;
+                (true, true)
+            },
+        }
+    }
+
+    /// Frees the trial slot of a request which neither succeeded nor failed,
+    /// e.g. because the client went away.
+    pub fn release_trial(&self) {
+        self.inner.lock().unwrap().is_probing = false;
+    }
+
+    /// Time left until an open circuit lets a trial request through.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self.state() {
+            BreakerState::Open(until) => Some(until.saturating_duration_since(Instant::now())),
+            _ => None,
+        }
+    }
+
+    pub fn succeeded(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let was_closed = inner.state == BreakerState::Closed;
+        inner.state = BreakerState::Closed;
+        inner.fails = 0;
+        inner.is_probing = false;
+        !was_closed
+    }
+
+    /// A passed health check closes no circuit by itself: an open one goes
+    /// half-open, so that a trial request decides. Returns true if it did.
+    pub fn check_passed(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        Self::refresh(&mut inner);
+        match inner.state {
+            BreakerState::Closed => {
+                inner.fails = 0;
+                false
+            },
+            BreakerState::Open(_) => {
+                inner.state = BreakerState::HalfOpen;
+                inner.is_probing = false;
+                true
+            },
+            BreakerState::HalfOpen => false,
+        }
+    }
+
+    /// Returns true if the failure opened the circuit.
+    pub fn failed(&self, conf: &HealthConf) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        Self::refresh(&mut inner);
+        inner.fails += 1;
+        let is_opening = match inner.state {
+            BreakerState::Closed => inner.fails >= conf.max_fails,
+            BreakerState::HalfOpen => true,
+            BreakerState::Open(_) => false,
+        };
+        if is_opening {
+            inner.state = BreakerState::Open(Instant::now() + conf.fail_timeout);
+            inner.fails = 0;
+            inner.is_probing = false;
+        }
+        is_opening
+    }
+
+    fn refresh(inner: &mut BreakerInner) {
+        if let BreakerState::Open(until) = inner.state {
+            if Instant::now() >= until {
+                inner.state = BreakerState::HalfOpen;
+                inner.is_probing = false;
+            }
+        }
+    }
+}
+
+
+/// Checks every upstream of the group each `check_interval`. Groups live
+/// as long as the process, and so do their checks.
+pub async fn run_checks(group: Arc<UpstreamGroup>) {
+    loop {
+        Timer::after(group.health.check_interval).await;
+        for upstream in &group.upstreams {
+            match check(upstream.addr(), &group.health).await {
+                Ok(()) => group.check_passed(upstream),
+                Err(e) => {
+                    println!("Health check of {} failed: {e}", upstream.addr());
+                    group.failed(upstream);
+                },
+            }
+        }
+    }
+}
+
+/// Connects to the upstream over a fresh connection, so that checks don't
+/// take pool slots, and optionally requests the health path.
+pub async fn check(addr: &UpstreamAddr, conf: &HealthConf) -> Result<(), AppError> {
+    let checking = async {
+        let mut stream = addr.connect().await.map_err(AppError::Connect)?;
+        app_proto::handshake(&mut stream).await?;
+        if conf.check_path.is_empty() {
+            return Ok(());
+        }
+        let req = Request {
+            method: "get".to_string(),
+            host: conf.check_host.to_string(),
+            path: conf.check_path.to_string(),
+            session_id: "".to_string(),
+            query: HashMap::new(),
+            body_string: "".to_string(),
+            route: HashMap::new(),
+            files: HashMap::new(),
+        };
+        app_proto::send_request(&mut stream, &req, &[]).await?;
+        let resp = app_proto::read_response(&mut stream).await?;
+        let end = memchr::memmem::find(&resp, b"\r\n\r\n").unwrap_or(resp.len());
+        let head = RespHead::parse(&resp[..end]).map_err(AppError::InvalidResponse)?;
+        if !(200..300).contains(&head.code) {
+            return Err(AppError::InvalidResponse(format!("health status {}", head.code)));
+        }
+        Ok(())
+    };
+    match timeout(conf.check_timeout, checking).await {
+        Some(r) => r,
+        None => Err(AppError::Timeout(Phase::FirstByte)),
+    }
+}
@@ -1,17 +1,24 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
 pub struct Resp {
     pub code: u16,
     pub text: String,
     pub content_type: String,
+    pub headers: Vec<(String, String)>,
 }
 
 impl Resp {
     pub fn get_resp(&self) -> String {
+        let headers: String = self.headers.iter()
+            .map(|(k, v)| format!("{k}: {v}\r\n")).collect();
         format!(
-            "HTTP/1.1 {}\r\n\
+            "HTTP/1.1 {} {}\r\n\
             Content-Length: {}\r\n\
             Content-Type: {}\r\n\
+            {}\
             \r\n{}",
-            self.code, self.text.len(), self.content_type, self.text
+            self.code, reason(self.code), self.text.len(), self.content_type, headers, self.text
         )
     }
 }
@@ -21,6 +28,85 @@ pub fn text_resp(code: u16, text: String) -> Resp {
         code: code,
         text: text,
         content_type: "text/html".to_string(),
+        headers: vec![],
+    }
+}
+
+pub fn redirect_resp(code: u16, location: &str) -> Resp {
+    let mut r = text_resp(code, String::new());
+    r.headers.push(("Location".to_string(), location.to_string()));
+    r
+}
+
+/// Reason phrase of the status codes miarh sends.
+pub fn reason(code: u16) -> &'static str {
+    match code {
+        200 => "OK",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Content",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+/// Host header as the hostname and the port, if any.
+pub fn split_host(host: &str) -> (&str, Option<u16>) {
+    match host.rsplit_once(':') {
+        Some((hostname, port)) if !hostname.is_empty() => match port.parse::<u16>() {
+            Ok(port) => (hostname, Some(port)),
+            Err(_) => (host, None),
+        },
+        _ => (host, None),
+    }
+}
+
+/// Absolute URL of a request target, without the default port of the
+/// scheme.
+pub fn url(scheme: &str, hostname: &str, port: Option<u16>, target: &str) -> String {
+    format!("{scheme}://{}{target}", authority(scheme, hostname, port))
+}
+
+/// Host header for a request, without the default port of the scheme.
+pub fn authority(scheme: &str, hostname: &str, port: Option<u16>) -> String {
+    match (scheme, port) {
+        (_, None) | ("https", Some(443)) | ("http", Some(80)) => hostname.to_string(),
+        (_, Some(port)) => format!("{hostname}:{port}"),
     }
 }
 
+/// Formats time as an IMF-fixdate, e.g. "Sun, 06 Nov 1994 08:49:37 GMT".
+pub fn http_date(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = (secs / 86400) as i64;
+    let (h, m, s) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+    let weekday = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"][(days % 7) as usize];
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let month = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                 "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"][(month - 1) as usize];
+    format!("{weekday}, {day:02} {month} {year} {h:02}:{m:02}:{s:02} GMT")
+}
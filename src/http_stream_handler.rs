@@ -1,9 +1,14 @@
+use std::cmp::min;
 use std::io::{Read, ErrorKind};
 use std::fs::File;
 use async_net::{TcpStream};
 use futures_lite::{AsyncReadExt, AsyncWriteExt};
+use crate::acme;
+use crate::conf::CONF;
 use crate::headers::{parse_headers, RequestParser};
 use crate::http;
+use crate::proxy::RawHead;
+use crate::stream_handler::StreamHandler;
 
 
 pub struct HttpStreamHandler {
@@ -19,22 +24,59 @@ impl HttpStreamHandler {
             buffer: Vec::<u8>::new(),
         }
     }
-    pub async fn process(&mut self) {
-        // the only purpose is to redirect all to https
+    /// Answers ACME challenges and redirects to HTTPS, or hands requests
+    /// over to StreamHandler for servers with serve_http.
+    pub async fn process(mut self) {
         self.read_headers().await;
         let mut hp: RequestParser = parse_headers(&self.buffer);
         hp.check_is_static().await;
-        if hp.is_valid() == false { return }
+        if hp.is_complete() == false { return }
         let host = hp.parsed_headers.get("host").unwrap();
         let path = hp.parsed_headers.get("path").unwrap();
+        if let Some(key_authorization) = acme::challenge_response(path).await {
+            let mut r = http::text_resp(200, key_authorization);
+            r.content_type = "text/plain".to_string();
+            self.write_resp(r.get_resp()).await;
+            return;
+        }
         if hp.is_static && hp.is_static_valid  && RequestParser::is_acme(&path).await {
             self.return_static(hp).await;
             return;
         }
-        let resp: String = format!("HTTP/1.1 301 Moved Permanently\r\n\
-            Location: https://{host}:443{path}\r\n\
-            Content-length: 0\r\n\r\n");
-        self.write_resp(resp).await;
+        let head_end = min(hp.headers_len + 1, self.buffer.len());
+        let raw = match RawHead::parse(&self.buffer[..head_end]) {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        let target = raw.target();
+        // The parsed Host has no port.
+        let (hostname, port) = http::split_host(raw.get("host").unwrap_or(host));
+        let hostname = &hostname.to_lowercase();
+        let (code, canonical, is_served, https_port) = {
+            let conf = CONF.read().await;
+            let srv = conf.server(hostname);
+            (
+                srv.map(|v| v.redirect_code).unwrap_or(301),
+                srv.and_then(|v| v.canonical_redirect(hostname)).map(|v| v.to_string()),
+                srv.map(|v| v.serve_http).unwrap_or(false),
+                conf.redirect_https_port(),
+            )
+        };
+        let location = match (is_served, canonical) {
+            (true, Some(canonical)) => http::url("http", &canonical, port, target),
+            (true, None) => {
+                let mut handler = StreamHandler::new(self.tcp_stream, None);
+                handler.buffer = self.buffer;
+                handler.process().await;
+                return;
+            },
+            // Straight to the canonical host, without another redirect.
+            (false, canonical) => {
+                let hostname = canonical.as_deref().unwrap_or(hostname);
+                http::url("https", hostname, Some(https_port), target)
+            },
+        };
+        self.write_resp(http::redirect_resp(code, &location).get_resp()).await;
     }
     pub async fn read_headers(&mut self) {
         let is_oneshot = true;
@@ -0,0 +1,79 @@
+// JSON Web Signatures of ACME requests, RFC 7515, signed with an
+// ES256 account key.
+
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use serde_json::{json, Value};
+
+
+/// Key ID of the account once it's registered, its public key before.
+pub enum Signer<'a> {
+    Jwk,
+    Kid(&'a str),
+}
+
+
+/// Base64url without padding.
+pub fn base64url(data: &[u8]) -> String {
+    openssl::base64::encode_block(data)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+pub fn generate_key() -> Result<PKey<Private>, ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    PKey::from_ec_key(EcKey::generate(&group)?)
+}
+
+/// Public part of a P-256 key. Members are in lexicographic order, as
+/// thumbprints need them.
+pub fn jwk(key: &PKey<Private>) -> Result<Value, ErrorStack> {
+    let ec = key.ec_key()?;
+    let mut ctx = BigNumContext::new()?;
+    let mut x = BigNum::new()?;
+    let mut y = BigNum::new()?;
+    ec.public_key().affine_coordinates(ec.group(), &mut x, &mut y, &mut ctx)?;
+    Ok(json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": base64url(&x.to_vec_padded(32)?),
+        "y": base64url(&y.to_vec_padded(32)?),
+    }))
+}
+
+/// JWK thumbprint, RFC 7638, the account part of key authorizations.
+pub fn thumbprint(jwk: &Value) -> Result<String, ErrorStack> {
+    // Members are inserted sorted, and serde_json writes no whitespace.
+    Ok(base64url(&hash(MessageDigest::sha256(), jwk.to_string().as_bytes())?))
+}
+
+/// Flattened JWS of `payload`, None for POST-as-GET requests.
+pub fn sign(key: &PKey<Private>, signer: Signer, nonce: &str, url: &str,
+            payload: Option<&Value>) -> Result<Value, ErrorStack> {
+    let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+    match signer {
+        Signer::Jwk => protected["jwk"] = jwk(key)?,
+        Signer::Kid(kid) => protected["kid"] = json!(kid),
+    }
+    let protected = base64url(protected.to_string().as_bytes());
+    let payload = match payload {
+        Some(v) => base64url(v.to_string().as_bytes()),
+        None => String::new(),
+    };
+    let digest = hash(MessageDigest::sha256(), format!("{protected}.{payload}").as_bytes())?;
+    let sig = EcdsaSig::sign(&digest, &*key.ec_key()?)?;
+    // R and S as fixed size big-endian numbers, not DER.
+    let mut signature = sig.r().to_vec_padded(32)?;
+    signature.extend(sig.s().to_vec_padded(32)?);
+    Ok(json!({
+        "protected": protected,
+        "payload": payload,
+        "signature": base64url(&signature),
+    }))
+}
@@ -1,17 +1,40 @@
 #![feature(io_error_more)]
+pub mod acme;
+pub mod app_pool;
+pub mod app_proto;
+pub mod app_resp;
 pub mod cache;
+pub mod cgi;
 pub mod compress;
 pub mod conf;
 pub mod epoll;
+pub mod fastcgi;
+pub mod form;
 pub mod headers;
+pub mod health;
 pub mod http;
 pub mod http_stream_handler;
+pub mod jws;
 pub mod listener;
 pub mod multipart;
 pub mod mime;
+pub mod proxy;
+pub mod scgi;
 pub mod spawn;
 pub mod static_handler;
 pub mod stream_handler;
+pub mod timeout;
+pub mod tls;
+pub mod tls_openssl;
+#[cfg(feature = "rustls")]
+pub mod tls_rustls;
+pub mod tunnel;
+pub mod upload;
+pub mod upstream;
+pub mod urlencoded;
+pub mod uwsgi;
+pub mod ws;
+pub mod ws_bridge;
 
 
 #[cfg(test)]
@@ -1,22 +1,25 @@
 use std::net::{Ipv4Addr, IpAddr, SocketAddr};
 use std::str::FromStr;
-use std::sync::{Arc};
 use std::os::unix::io::{AsRawFd};
+use std::time::Duration;
 use async_net::{TcpListener, TcpStream};
-use async_native_tls::{Identity, TlsAcceptor};
 use futures_lite::future;
 use qpidfile::Pidfile;
+use crate::acme;
 use crate::conf::CONF;
 use crate::epoll;
 use crate::spawn::spawn;
+use crate::tls;
 use crate::stream_handler::StreamHandler;
 use crate::http_stream_handler::HttpStreamHandler;
 
 
 pub struct Listener {
-    pub https_listener: TcpListener,
+    /// None in plain HTTP mode.
+    pub https_listener: Option<TcpListener>,
     pub http_listener: TcpListener,
-    pub tls_acceptor: Arc<TlsAcceptor>,
+    /// SIGHUP reloads the TLS certificates.
+    pub signal_fd: i32,
     pub epoll: epoll::Epoll,
 }
 
@@ -26,6 +29,8 @@ impl Listener {
         future::block_on(Listener::anew())
     }
     pub async fn anew() -> Self {
+        // First, before the executor and reactor threads start.
+        let signal_fd = epoll::signal_fd(&[libc::SIGHUP]).unwrap();
         let conf = CONF.read().await;
         let https_addr: SocketAddr = SocketAddr::new(
             IpAddr::V4(Ipv4Addr::from_str(&conf.ip).unwrap()), conf.https_port
@@ -33,15 +38,34 @@ impl Listener {
         let http_addr: SocketAddr = SocketAddr::new(
             IpAddr::V4(Ipv4Addr::from_str(&conf.ip).unwrap()), conf.http_port
         );
-        let tls_identity = Identity::from_pkcs12(
-            include_bytes!("../identity.pfx"), "").unwrap();
+        if !conf.plain_http {
+            let acme_managed = acme::managed(&conf);
+            if !acme_managed.is_empty() {
+                if let Err(e) = acme::ensure_placeholders(&conf) {
+                    panic!("{e}");
+                }
+                spawn(acme::run()).detach();
+            }
+            let tls_acceptors = match tls::Acceptors::from_conf(&conf) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            tls::install(tls_acceptors).await;
+            if conf.tls.watch_interval_secs > 0 {
+                spawn(tls::watch(Duration::from_secs(conf.tls.watch_interval_secs))).detach();
+            }
+        }
+        let https_listener = if conf.plain_http {
+            println!("Plain HTTP mode, serving on port {} only", conf.http_port);
+            None
+        } else {
+            Some(TcpListener::bind(https_addr).await.unwrap())
+        };
         Self {
-            https_listener: TcpListener::bind(https_addr).await.unwrap(),
+            https_listener,
             http_listener: TcpListener::bind(http_addr).await.unwrap(),
             epoll: epoll::Epoll::new().unwrap(),
-            tls_acceptor: Arc::new(TlsAcceptor::from(
-                native_tls::TlsAcceptor::new(tls_identity).unwrap()
-            )),
+            signal_fd,
         }
     }
     pub async fn main_loop(&mut self) {
@@ -49,9 +73,10 @@ impl Listener {
             Ok(v) => v,
             Err(e) => panic!("Unable to create pidfile: {e}")
         };
-        let https_fd: i32 = self.https_listener.as_raw_fd().clone();
+        let https_fd: Option<i32> = self.https_listener.as_ref().map(|v| v.as_raw_fd());
         let http_fd: i32 = self.http_listener.as_raw_fd().clone();
         self.epoll.reg_listeners(https_fd, http_fd).unwrap();
+        self.epoll.reg_signals(self.signal_fd).unwrap();
         let mut events = epoll::init_events();
         loop {
             let _ = self.epoll.wait(&mut events);
@@ -61,19 +86,31 @@ impl Listener {
                     self.accept_and_process_https().await;
                 } else if ev_id == epoll::EPOLL_HTTP_LISTENER_ID {
                     self.accept_and_process_http().await;
+                } else if ev_id == epoll::EPOLL_SIGNAL_ID {
+                    self.handle_signals();
                 } else {
                     println!("Unknown event");
                 }
             }
         }
     }
+    pub fn handle_signals(&self) {
+        while let Ok(signal) = epoll::read_signal(self.signal_fd) {
+            if signal == libc::SIGHUP as u32 && self.https_listener.is_some() {
+                println!("SIGHUP, reloading TLS certificates");
+                spawn(async { let _ = tls::reload().await; }).detach();
+            }
+        }
+    }
     pub async fn accept_and_process_https(&mut self) {
-        match self.https_listener.accept().await {
+        let https_listener = match &self.https_listener {
+            Some(v) => v,
+            None => return,
+        };
+        match https_listener.accept().await {
             Err(e) => println!("Unable to accept tcp stream: {e}"),
             Ok((https_tcp_stream, _addr)) => {
-                spawn(process_in_bg(
-                    https_tcp_stream, Arc::clone(&self.tls_acceptor)
-                )).detach();
+                spawn(process_in_bg(https_tcp_stream)).detach();
             }
         }
     }
@@ -87,19 +124,32 @@ impl Listener {
     }
 }
 
-async fn process_in_bg(
-    https_tcp_stream: TcpStream, tls_acceptor: Arc<TlsAcceptor>
-) {
+async fn process_in_bg(https_tcp_stream: TcpStream) {
+    let sni = tls::peek_sni(&https_tcp_stream).await;
+    let tls_acceptors = tls::current().await.expect("TLS acceptors are installed on start");
+    let tls_acceptor = match tls_acceptors.select(sni.as_deref()) {
+        Some(v) => v,
+        None => {
+            println!("TLS handshake refused, unknown hostname: {}", sni.unwrap_or_default());
+            tls::refuse(https_tcp_stream).await;
+            return;
+        },
+    };
     match tls_acceptor.accept(https_tcp_stream).await {
         Err(e) => println!("TLS err: {e}"),
         Ok(tls_stream) => {
-            let mut handler = StreamHandler::new(tls_stream);
+            let mut handler = StreamHandler::new(tls_stream, sni);
             handler.process().await;
         }
     }
 }
 
 async fn process_http_in_bg(tcp_stream: TcpStream) {
-    let mut handler = HttpStreamHandler::new(tcp_stream);
+    if CONF.read().await.plain_http {
+        let mut handler = StreamHandler::new(tcp_stream, None);
+        handler.process().await;
+        return;
+    }
+    let handler = HttpStreamHandler::new(tcp_stream);
     handler.process().await;
 }
@@ -1,84 +1,434 @@
-use memchr::memmem::Finder;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use memchr::memmem;
+use serde_json::Value;
+use crate::form::FormFields;
 use crate::headers::{RequestParser};
-use miarh_saras_http::RequestFile;
-
-
-
-pub async fn parse_multipart(hp: &mut RequestParser) {
-    let ctype = hp.parsed_headers.get("content-type").unwrap();
-    let boundary = format!("--{}", ctype.split("boundary=").nth(1).unwrap());
-    let finder_rnrn = Finder::new("\r\n\r\n");
-    let finder_boundary = Finder::new(&boundary);
-    let it = finder_boundary.find_iter(&hp.body);
-    let mut prev_pos = 0;
-    let mut result_str: String = "".into();
-    let mut idx = 0;
-    for pos in it {
-        let mut field_name = "";
-        let mut is_file = false;
-        let mut filename: Option<String> = None;
-        let mut content_type: String = "".to_string();
-        let content: Vec<u8>;
-        let row = &hp.body[prev_pos..pos];
-
-        let meta_start_finder = Finder::new("Content-Disposition");
-        for meta_start in meta_start_finder.find_iter(&row) {
-            let meta = &row[meta_start..];
-            let meta_end = finder_rnrn.find(&meta).unwrap();
-            let meta = &meta[..meta_end];
-            let meta = std::str::from_utf8(meta).unwrap();
-            field_name = meta.split(" name=\"").nth(1).unwrap();
-            field_name = field_name.split("\"").nth(0).unwrap();
-            filename = match meta.split(" filename=\"").nth(1) {
-                None => None,
-                Some(v) => {
-                    match v.split("\"").nth(0) {
-                        None => None,
-                        Some(v) => Some(v.to_string())
-                    }
-                }
+use crate::upload::{self, sanitize_filename, UploadRules, SNIFF_LEN};
+use crate::urlencoded::percent_decode;
+
+
+pub const MAX_PART_HEADERS_SIZE: usize = 4096;
+pub const MAX_BOUNDARY_LEN: usize = 70;
+
+static TMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+
+#[derive(Debug)]
+pub enum MultipartError {
+    FileTooLarge,
+    RequestTooLarge,
+    Malformed(String),
+    UnsupportedMediaType(String),
+    ContentMismatch(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MultipartError::FileTooLarge => write!(f, "Uploaded file is too large."),
+            MultipartError::RequestTooLarge => write!(f, "Request entity too large."),
+            MultipartError::Malformed(e) => write!(f, "Malformed multipart body: {e}"),
+            MultipartError::UnsupportedMediaType(e) => write!(f, "Unsupported upload: {e}"),
+            MultipartError::ContentMismatch(e) => write!(f, "Upload content mismatch: {e}"),
+            MultipartError::Io(e) => write!(f, "Multipart io err: {e}"),
+        }
+    }
+}
+
+impl From<io::Error> for MultipartError {
+    fn from(e: io::Error) -> Self {
+        MultipartError::Io(e)
+    }
+}
+
+impl MultipartError {
+    fn malformed(e: &str) -> Self {
+        MultipartError::Malformed(e.to_string())
+    }
+    pub fn http_code(&self) -> u16 {
+        match self {
+            MultipartError::FileTooLarge | MultipartError::RequestTooLarge => 413,
+            MultipartError::Malformed(_) => 400,
+            MultipartError::UnsupportedMediaType(_) => 415,
+            MultipartError::ContentMismatch(_) => 422,
+            MultipartError::Io(_) => 500,
+        }
+    }
+}
+
+
+/// Uploaded file stored under `tmp_dir`. The file is removed when this
+/// value is dropped, i.e. after the response has been sent.
+#[derive(Debug)]
+pub struct TmpFile {
+    pub path: PathBuf,
+}
+
+impl TmpFile {
+    pub fn create(tmp_dir: &str) -> Result<(Self, File), io::Error> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos()).unwrap_or(0);
+        let n = TMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let fname = format!("miarh-upload-{}-{n}-{nanos}", std::process::id());
+        let path = Path::new(tmp_dir).join(fname);
+        let f = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        Ok((TmpFile { path }, f))
+    }
+}
+
+impl Drop for TmpFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            println!("Unable to remove tmp file {}: {e}", self.path.display());
+        }
+    }
+}
+
+
+pub struct MultipartLimits {
+    pub tmp_dir: String,
+    pub max_file_size: usize,
+    pub max_fields_size: usize,
+    pub rules: UploadRules,
+}
+
+#[derive(PartialEq)]
+enum State {
+    Preamble,
+    AfterDelimiter,
+    PartHeaders,
+    PartBody,
+    Done,
+}
+
+struct Part {
+    field_name: String,
+    filename: Option<String>,
+    content_type: String,
+    size: usize,
+    head: Vec<u8>,
+    text: Vec<u8>,
+    file: Option<(TmpFile, File)>,
+}
+
+/// Incremental multipart/form-data parser. Body chunks are passed to
+/// `feed()` as they are read from the stream: text fields are collected
+/// into `body_string`, files are written to `tmp_dir` and described in
+/// `body_string` by their path, size and content type.
+pub struct MultipartStreamer {
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    state: State,
+    part: Option<Part>,
+    limits: MultipartLimits,
+    fields_size: usize,
+    fields: FormFields,
+    tmp_files: Vec<TmpFile>,
+}
+
+impl MultipartStreamer {
+    pub fn new(content_type: &str, limits: MultipartLimits
+               ) -> Result<Self, MultipartError> {
+        let (_, params) = parse_header_params(content_type);
+        let boundary = match param(&params, "boundary") {
+            Some(v) => v,
+            None => return Err(MultipartError::malformed("no boundary")),
+        };
+        if !is_valid_boundary(boundary) {
+            return Err(MultipartError::malformed("bad boundary"));
+        }
+        Ok(Self {
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            // The first delimiter is not preceded by CRLF.
+            buf: b"\r\n".to_vec(),
+            state: State::Preamble,
+            part: None,
+            limits,
+            fields_size: 0,
+            fields: FormFields::new(),
+            tmp_files: vec![],
+        })
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<(), MultipartError> {
+        self.buf.extend_from_slice(chunk);
+        loop {
+            let is_progress = match self.state {
+                State::Preamble => self.skip_preamble(),
+                State::AfterDelimiter => self.after_delimiter()?,
+                State::PartHeaders => self.part_headers()?,
+                State::PartBody => self.part_body()?,
+                State::Done => { self.buf.clear(); false },
             };
-            is_file = filename.is_some();
-            let default_ctype = "text/plain".to_string();
-            content_type = match meta.split("Content-Type: ").nth(1) {
-                None => default_ctype,
-                Some(v) => {
-                    match v.split("\"").nth(0) {
-                        None => default_ctype,
-                        Some(v) => v.to_string()
-                    }
+            if !is_progress { return Ok(()) }
+        }
+    }
+
+    pub fn finish(mut self, hp: &mut RequestParser) -> Result<(), MultipartError> {
+        if self.state != State::Done {
+            return Err(MultipartError::malformed("unexpected end of body"));
+        }
+        hp.body_string = self.fields.to_json_string();
+        hp.tmp_files.append(&mut self.tmp_files);
+        Ok(())
+    }
+
+    fn skip_preamble(&mut self) -> bool {
+        match memmem::find(&self.buf, &self.delimiter) {
+            Some(pos) => {
+                self.buf.drain(..pos + self.delimiter.len());
+                self.state = State::AfterDelimiter;
+                true
+            },
+            None => {
+                let keep = self.delimiter.len() - 1;
+                if self.buf.len() > keep {
+                    self.buf.drain(..self.buf.len() - keep);
                 }
-            };
+                false
+            },
         }
+    }
 
-        if pos != 0 {
-            let val_start = finder_rnrn.find(&row).unwrap();
-            let val_end = row.len() - 2;
-            content = row[val_start+4..val_end].to_vec();
-            let text: Option<String> = match content_type.as_str() {
-                "text/plain" => Some(std::str::from_utf8(&content)
-                                     .unwrap().to_string()),
-                _ => None,
-            };
+    fn after_delimiter(&mut self) -> Result<bool, MultipartError> {
+        if self.buf.starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(true);
+        }
+        // Delimiter may be followed by transport padding (RFC 2046).
+        let padding = self.buf.iter().take_while(|c| **c == b' ' || **c == b'\t').count();
+        if padding > MAX_PART_HEADERS_SIZE {
+            return Err(MultipartError::malformed("bad delimiter"));
+        }
+        if self.buf.len() < padding + 2 { return Ok(false) }
+        if self.buf[padding..].starts_with(b"\r\n") {
+            self.buf.drain(..padding + 2);
+            self.state = State::PartHeaders;
+        } else {
+            return Err(MultipartError::malformed("bad delimiter"));
+        }
+        Ok(true)
+    }
 
-            if is_file == false {
-                let comma = if idx > 0 { "," } else { "" };
-                if idx == 0 { result_str.push_str("{"); }
-                let v = text.unwrap();
-                let is_number = v.parse::<f64>().is_ok();
-                if is_number {
-                  result_str.push_str(&format!(r#"{comma}"{field_name}": {v} "#));
-                } else {
-                  result_str.push_str(&format!(r#"{comma}"{field_name}": "{v}" "#));
+    fn part_headers(&mut self) -> Result<bool, MultipartError> {
+        let end = match memmem::find(&self.buf, b"\r\n\r\n") {
+            Some(end) => end,
+            None if self.buf.len() > MAX_PART_HEADERS_SIZE => {
+                return Err(MultipartError::malformed("part headers too long"));
+            },
+            None => return Ok(false),
+        };
+        if end > MAX_PART_HEADERS_SIZE {
+            return Err(MultipartError::malformed("part headers too long"));
+        }
+        let mut part = parse_part_headers(&self.buf[..end])?;
+        self.buf.drain(..end + 4);
+        if let Some(filename) = &part.filename {
+            self.limits.rules.check_declared(filename, &part.content_type)?;
+            part.file = Some(TmpFile::create(&self.limits.tmp_dir)?);
+        }
+        self.part = Some(part);
+        self.state = State::PartBody;
+        Ok(true)
+    }
+
+    fn part_body(&mut self) -> Result<bool, MultipartError> {
+        match memmem::find(&self.buf, &self.delimiter) {
+            Some(pos) => {
+                let data: Vec<u8> = self.buf.drain(..pos).collect();
+                self.buf.drain(..self.delimiter.len());
+                self.write_part(&data)?;
+                self.finish_part()?;
+                self.state = State::AfterDelimiter;
+                Ok(true)
+            },
+            None => {
+                // Keep a tail which may be the beginning of a delimiter.
+                let keep = self.delimiter.len() - 1;
+                if self.buf.len() > keep {
+                    let data: Vec<u8> = self.buf.drain(..self.buf.len() - keep).collect();
+                    self.write_part(&data)?;
                 }
-            } else {
-              let f = RequestFile { name: filename.clone().unwrap(), content: content.clone() };
-              hp.files.insert(field_name.to_string(), f);
-            }
-            idx += 1;
+                Ok(false)
+            },
         }
-        prev_pos = pos;
     }
-    result_str.push_str("}");
-    hp.body_string = result_str;
+
+    fn write_part(&mut self, data: &[u8]) -> Result<(), MultipartError> {
+        let part = self.part.as_mut().unwrap();
+        part.size += data.len();
+        match part.file.as_mut() {
+            Some((_, f)) => {
+                if part.size > self.limits.max_file_size {
+                    return Err(MultipartError::FileTooLarge);
+                }
+                if part.head.len() < SNIFF_LEN {
+                    let n = (SNIFF_LEN - part.head.len()).min(data.len());
+                    part.head.extend_from_slice(&data[..n]);
+                }
+                f.write_all(data)?;
+            },
+            None => {
+                self.fields_size += data.len();
+                if self.fields_size > self.limits.max_fields_size {
+                    return Err(MultipartError::RequestTooLarge);
+                }
+                part.text.extend_from_slice(data);
+            },
+        }
+        Ok(())
+    }
+
+    fn finish_part(&mut self) -> Result<(), MultipartError> {
+        let part = self.part.take().unwrap();
+        match part.file {
+            Some((tmp_file, mut f)) => {
+                f.flush()?;
+                let filename = part.filename.unwrap_or_default();
+                self.limits.rules.check_content(&filename, &part.content_type, &part.head)?;
+                let content_type = match part.content_type.is_empty() {
+                    true => upload::detected_type(&filename, &part.head),
+                    false => part.content_type,
+                };
+                let meta = serde_json::json!({
+                    "filename": filename,
+                    "path": tmp_file.path.display().to_string(),
+                    "size": part.size,
+                    "content_type": content_type,
+                });
+                self.fields.insert(part.field_name, meta);
+                self.tmp_files.push(tmp_file);
+            },
+            None => {
+                let v = String::from_utf8(part.text)
+                    .map_err(|_| MultipartError::malformed("field value is not utf-8"))?;
+                self.fields.insert(part.field_name, Value::String(v));
+            },
+        }
+        Ok(())
+    }
+}
+
+fn parse_part_headers(meta: &[u8]) -> Result<Part, MultipartError> {
+    let meta = std::str::from_utf8(meta)
+        .map_err(|_| MultipartError::malformed("part headers are not utf-8"))?;
+    let mut disposition: Option<&str> = None;
+    // Empty if not declared, then files are typed by their content.
+    let mut content_type = String::new();
+    for line in meta.split("\r\n") {
+        let (name, value) = match line.split_once(':') {
+            Some(v) => v,
+            None => return Err(MultipartError::malformed("bad part header")),
+        };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-disposition" => disposition = Some(value.trim()),
+            "content-type" => content_type = value.trim().to_string(),
+            _ => {},
+        }
+    }
+    let disposition = match disposition {
+        Some(v) => v,
+        None => return Err(MultipartError::malformed("no content-disposition")),
+    };
+    let (kind, params) = parse_header_params(disposition);
+    if !kind.eq_ignore_ascii_case("form-data") {
+        return Err(MultipartError::malformed("content-disposition is not form-data"));
+    }
+    let field_name = match param(&params, "name") {
+        Some(v) if !v.is_empty() => v.to_string(),
+        _ => return Err(MultipartError::malformed("no field name")),
+    };
+    let filename = match param(&params, "filename*").and_then(decode_ext_value) {
+        Some(v) => Some(v),
+        None => param(&params, "filename").map(|v| v.to_string()),
+    };
+    let filename = filename.map(|v| sanitize_filename(&v));
+    Ok(Part {
+        field_name,
+        filename,
+        content_type,
+        size: 0,
+        head: vec![],
+        text: vec![],
+        file: None,
+    })
+}
+
+/// Splits a header value like `form-data; name="a;b"; filename=x` into
+/// the main value and a list of parameters. Parameter names are lowercased,
+/// quoted values are unquoted.
+pub fn parse_header_params(value: &str) -> (String, Vec<(String, String)>) {
+    let mut segments: Vec<String> = vec![];
+    let mut segment = String::new();
+    let mut is_quoted = false;
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => { is_quoted = !is_quoted; segment.push(c); },
+            // Browsers do not escape backslashes in filenames, so only
+            // \" and \\ are treated as escape sequences.
+            '\\' if is_quoted && matches!(chars.peek(), Some('"') | Some('\\')) => {
+                segment.push(c);
+                segment.push(chars.next().unwrap());
+            },
+            ';' if !is_quoted => segments.push(std::mem::take(&mut segment)),
+            _ => segment.push(c),
+        }
+    }
+    segments.push(segment);
+    let main = segments.remove(0).trim().to_string();
+    let mut params = vec![];
+    for segment in segments {
+        if let Some((k, v)) = segment.split_once('=') {
+            params.push((k.trim().to_ascii_lowercase(), unquote(v.trim())));
+        }
+    }
+    (main, params)
+}
+
+fn unquote(v: &str) -> String {
+    if v.len() < 2 || !v.starts_with('"') || !v.ends_with('"') {
+        return v.to_string();
+    }
+    let v = &v[1..v.len() - 1];
+    let mut result = String::with_capacity(v.len());
+    let mut chars = v.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' && matches!(chars.peek(), Some('"') | Some('\\')) {
+            result.push(chars.next().unwrap());
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+fn param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+}
+
+// RFC 8187 ext-value: charset'language'percent-encoded-value
+fn decode_ext_value(v: &str) -> Option<String> {
+    let mut it = v.splitn(3, '\'');
+    let (charset, _lang, value) = (it.next()?, it.next()?, it.next()?);
+    let bytes = percent_decode(value);
+    if charset.eq_ignore_ascii_case("utf-8") {
+        String::from_utf8(bytes).ok()
+    } else if charset.eq_ignore_ascii_case("iso-8859-1") {
+        Some(bytes.iter().map(|b| *b as char).collect())
+    } else {
+        None
+    }
+}
+
+// RFC 2046: 1 to 70 characters from bchars, not ending with a space.
+fn is_valid_boundary(b: &str) -> bool {
+    !b.is_empty() && b.len() <= MAX_BOUNDARY_LEN && !b.ends_with(' ')
+        && b.bytes().all(|c| c.is_ascii_alphanumeric() || b"'()+_,-./:=? ".contains(&c))
 }
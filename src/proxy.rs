@@ -0,0 +1,166 @@
+use std::net::IpAddr;
+use crate::app_resp::HOP_BY_HOP;
+use crate::tls::ClientCert;
+use crate::upstream::UpstreamAddr;
+
+
+/// Request headers which are set by miarh and never taken from the client.
+const FORWARDING: [&str; 7] = [
+    "x-forwarded-for", "x-forwarded-proto", "x-forwarded-host", "forwarded",
+    "x-client-cert-subject", "x-client-cert-san", "x-client-cert-fingerprint",
+];
+
+
+/// Client side of a proxied request, passed on to the backend.
+#[derive(Debug, Clone)]
+pub struct ForwardInfo {
+    pub client_ip: Option<IpAddr>,
+    /// Host header of the client, with the port if any.
+    pub host: String,
+    pub proto: String,
+    /// Verified client certificate, on servers with client_auth.
+    pub client_cert: Option<ClientCert>,
+}
+
+/// Request head as the client sent it.
+#[derive(Debug, Clone)]
+pub struct RawHead {
+    pub request_line: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RawHead {
+    pub fn parse(head: &[u8]) -> Result<Self, String> {
+        let head = std::str::from_utf8(head).map_err(|_| "head is not utf-8".to_string())?;
+        let mut lines = head.split("\r\n").filter(|v| !v.is_empty());
+        let request_line = lines.next().unwrap_or("").to_string();
+        if request_line.split(' ').count() != 3 {
+            return Err(format!("bad request line: {request_line}"));
+        }
+        let mut headers = vec![];
+        for line in lines {
+            match line.split_once(':') {
+                Some((k, v)) if !k.is_empty() && !k.contains(' ') => {
+                    headers.push((k.to_string(), v.trim().to_string()));
+                },
+                _ => return Err(format!("bad header line: {line}")),
+            }
+        }
+        Ok(Self { request_line, headers })
+    }
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+    pub fn method(&self) -> &str {
+        self.request_line.split(' ').next().unwrap_or("")
+    }
+    /// Path and query, as requested.
+    pub fn target(&self) -> &str {
+        self.request_line.split(' ').nth(1).unwrap_or("")
+    }
+    /// Request target without the query.
+    pub fn path(&self) -> &str {
+        self.target().split('?').next().unwrap_or("")
+    }
+}
+
+/// Builds the head sent to an HTTP backend: hop-by-hop and forwarding
+/// headers of the client are replaced, one request is sent per connection.
+pub fn request_head(raw: &RawHead, fwd: &ForwardInfo) -> Vec<u8> {
+    build_head(raw, fwd, None)
+}
+
+/// Head of a WebSocket handshake, sent to an HTTP backend or to the app.
+/// Upgrade is kept and Sec-WebSocket-* headers are passed as they are.
+pub fn upgrade_head(raw: &RawHead, fwd: &ForwardInfo) -> Vec<u8> {
+    build_head(raw, fwd, raw.get("upgrade"))
+}
+
+fn build_head(raw: &RawHead, fwd: &ForwardInfo, upgrade: Option<&str>) -> Vec<u8> {
+    let mut dropped: Vec<String> = raw.get("connection").unwrap_or("")
+        .split(',').map(|v| v.trim().to_lowercase()).filter(|v| !v.is_empty()).collect();
+    dropped.extend(HOP_BY_HOP.iter().map(|v| v.to_string()));
+    dropped.extend(FORWARDING.iter().map(|v| v.to_string()));
+    // The body is already on its way, nothing to continue.
+    dropped.push("expect".to_string());
+    dropped.push("proxy-connection".to_string());
+
+    let mut head = format!("{}\r\n", raw.request_line);
+    for (k, v) in &raw.headers {
+        if !dropped.contains(&k.to_lowercase()) {
+            head.push_str(&format!("{k}: {v}\r\n"));
+        }
+    }
+    if let Some(ip) = fwd.client_ip {
+        head.push_str(&format!("X-Forwarded-For: {ip}\r\n"));
+    }
+    head.push_str(&format!("X-Forwarded-Proto: {}\r\n", fwd.proto));
+    head.push_str(&format!("X-Forwarded-Host: {}\r\n", fwd.host));
+    head.push_str(&format!("Forwarded: {}\r\n", forwarded(fwd)));
+    if let Some(cert) = &fwd.client_cert {
+        head.push_str(&format!("X-Client-Cert-Subject: {}\r\n", cert.subject));
+        head.push_str(&format!("X-Client-Cert-San: {}\r\n", cert.san.join(", ")));
+        head.push_str(&format!("X-Client-Cert-Fingerprint: {}\r\n", cert.fingerprint));
+    }
+    match upgrade {
+        Some(v) => head.push_str(&format!("Upgrade: {v}\r\nConnection: Upgrade\r\n\r\n")),
+        None => head.push_str("Connection: close\r\n\r\n"),
+    }
+    head.into_bytes()
+}
+
+/// RFC 7239 Forwarded header value.
+pub fn forwarded(fwd: &ForwardInfo) -> String {
+    let mut parts = vec![];
+    match fwd.client_ip {
+        Some(IpAddr::V4(ip)) => parts.push(format!("for={ip}")),
+        Some(IpAddr::V6(ip)) => parts.push(format!("for=\"[{ip}]\"")),
+        None => parts.push("for=unknown".to_string()),
+    }
+    parts.push(format!("host={}", quote(&fwd.host)));
+    parts.push(format!("proto={}", fwd.proto));
+    parts.join(";")
+}
+
+fn quote(v: &str) -> String {
+    let is_token = !v.is_empty() && v.chars().all(|c| c.is_ascii_alphanumeric()
+        || "!#$%&'*+-.^_`|~".contains(c));
+    if is_token {
+        v.to_string()
+    } else {
+        format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Location prefixes which point at the backend or at the site over plain
+/// HTTP, and their public replacements.
+pub fn location_rewrites(backend: &UpstreamAddr, fwd: &ForwardInfo) -> Vec<(String, String)> {
+    let public = format!("{}://{}", fwd.proto, fwd.host);
+    let mut rewrites = vec![];
+    if let UpstreamAddr::Tcp(addr) = backend {
+        rewrites.push((format!("http://{addr}"), public.to_string()));
+    }
+    rewrites.push((format!("http://{}", fwd.host), public.to_string()));
+    let hostname = match fwd.host.rsplit_once(':') {
+        Some((v, port)) if port.chars().all(|c| c.is_ascii_digit()) => v,
+        _ => &fwd.host,
+    };
+    if hostname != fwd.host {
+        rewrites.push((format!("http://{hostname}"), public.to_string()));
+    }
+    rewrites
+}
+
+pub fn rewrite_location(location: &str, rewrites: &[(String, String)]) -> Option<String> {
+    for (from, to) in rewrites {
+        let rest = match location.get(..from.len()) {
+            Some(v) if v.eq_ignore_ascii_case(from) => &location[from.len()..],
+            _ => continue,
+        };
+        // Only whole authorities, "http://a.com" must not match "http://a.com.evil".
+        if rest.is_empty() || rest.starts_with(['/', '?', '#']) {
+            return Some(format!("{to}{rest}"));
+        }
+    }
+    None
+}
@@ -0,0 +1,29 @@
+// SCGI client, https://python.ca/scgi/protocol.txt
+//
+// The request is a netstring of NUL separated headers, CONTENT_LENGTH first,
+// followed by the raw body. The app answers with a CGI response and closes
+// the connection.
+
+/// Netstring with the request headers, the body follows it as is.
+pub fn request_head(env: &[(String, String)]) -> Vec<u8> {
+    let content_len = env.iter().find(|(k, _)| k == "CONTENT_LENGTH")
+        .map(|(_, v)| v.as_str()).unwrap_or("0");
+    let mut headers = vec![];
+    let mut push = |k: &str, v: &str| {
+        headers.extend_from_slice(k.as_bytes());
+        headers.push(0);
+        headers.extend_from_slice(v.as_bytes());
+        headers.push(0);
+    };
+    push("CONTENT_LENGTH", content_len);
+    push("SCGI", "1");
+    for (k, v) in env {
+        if k != "CONTENT_LENGTH" && k != "SCGI" {
+            push(k, v);
+        }
+    }
+    let mut out = format!("{}:", headers.len()).into_bytes();
+    out.extend(headers);
+    out.push(b',');
+    out
+}
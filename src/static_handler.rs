@@ -6,7 +6,8 @@ use crate::compress;
 use crate::mime;
 
 
-pub async fn get_static_file(hp: RequestParser) -> Option<Vec<u8>> {
+/// Response with the file, `headers` added.
+pub async fn get_static_file(hp: RequestParser, headers: &[(String, String)]) -> Option<Vec<u8>> {
 	let path = hp.get_header("static_path").split("?").next().unwrap().to_string();
 	let mut content: Vec<u8> = vec![];
 	let mut content_encoding = "";
@@ -40,11 +41,13 @@ pub async fn get_static_file(hp: RequestParser) -> Option<Vec<u8>> {
 		};
 	}
 	let content_len = format!("Content-Length: {}\r\n", content.len());
+	let extra_headers: String = headers.iter().map(|(k, v)| format!("{k}: {v}\r\n")).collect();
 	let headers = [
 		"HTTP/1.1 200 OK\r\n",
 		content_len.as_str(),
 		content_encoding,
 		mime_line.as_str(),
+		extra_headers.as_str(),
 		"\r\n"
 	];
 	let mut response = headers.join("").to_string().into_bytes();
@@ -1,35 +1,175 @@
-use std::io::{ErrorKind};
-use async_net::{TcpStream};
-use async_net::unix::{UnixStream};
-use async_native_tls::{TlsStream};
-use futures_lite::{AsyncReadExt, AsyncWriteExt};
+use std::cmp::min;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use async_net::TcpStream;
+use futures_lite::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use memchr::memmem;
+use crate::app_pool::{AppError, AppPool};
+use crate::app_proto::{self, FrameType, ProtoError};
+use crate::app_resp::{RespHead, RespOptions, RespProcessor, MAX_RESP_HEAD_SIZE};
 use crate::headers::{parse_headers, RequestParser};
+use crate::proxy::{self, ForwardInfo, RawHead};
 use miarh_saras_http::Request;
 use crate::http;
-use crate::multipart::parse_multipart;
-use crate::conf::CONF;
+use crate::multipart::{MultipartError, MultipartLimits, MultipartStreamer};
+use crate::upload::UploadRules;
+use crate::urlencoded::parse_urlencoded;
+use crate::cgi::{self, CgiProtocol};
+use crate::conf::{CgiConf, ServerConf, CONF};
+use crate::fastcgi::{self, Output, RecordType};
+use crate::scgi;
 use crate::static_handler;
+use crate::timeout::{timeout, Deadline, Phase, Timeouts};
+use crate::tls::{self, ClientCert, TlsStream};
+use crate::tunnel;
+use crate::ws;
+use crate::ws_bridge;
+use crate::upstream::{self, AppStream, UpstreamAddr, UpstreamGroup};
+use crate::uwsgi;
 
 
-pub struct StreamHandler {
-	pub tls_stream: TlsStream<TcpStream>,
+pub struct BackendConf {
+	pub group: Arc<UpstreamGroup>,
+	pub timeouts: Timeouts,
+	pub resp_headers: Vec<(String, String)>,
+}
+
+impl BackendConf {
+	/// Fails fast while the circuits of all upstreams are open. A half-open
+	/// circuit busy with its trial request is retried in a second.
+	pub fn check_available(&self) -> Result<(), AppError> {
+		if self.group.is_available() { return Ok(()) }
+		let secs = self.group.retry_after().map(|d| d.as_secs_f64().ceil() as u64);
+		Err(AppError::Unavailable(Some(secs.unwrap_or(1))))
+	}
+}
+
+/// FastCGI, SCGI or uwsgi server of a request.
+pub struct CgiBackend {
+	pub addr: UpstreamAddr,
+	pub conf: CgiConf,
+	pub server_port: u16,
+	pub timeouts: Timeouts,
+	pub resp_headers: Vec<(String, String)>,
+}
+
+/// Plain HTTP/1.1 backend of a proxied request.
+pub struct ProxyConf {
+	pub addr: UpstreamAddr,
+	pub timeouts: Timeouts,
+	pub resp_headers: Vec<(String, String)>,
+}
+
+/// Backend answer to a WebSocket handshake.
+pub struct Upgrade {
+	/// Response head and the bytes after it, as read so far. The whole
+	/// response if the upgrade was refused.
+	pub resp: Vec<u8>,
+	/// The backend connection, once it switched protocols.
+	pub stream: Option<AppStream>,
+}
+
+/// Client connection of a StreamHandler: TLS, or plain TCP for servers
+/// which serve HTTP.
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin {
+	fn peer_addr(&self) -> io::Result<SocketAddr>;
+	fn is_tls(&self) -> bool;
+	fn client_cert(&self) -> Option<ClientCert>;
+}
+
+impl ClientStream for TlsStream {
+	fn peer_addr(&self) -> io::Result<SocketAddr> {
+		self.get_ref().peer_addr()
+	}
+	fn is_tls(&self) -> bool {
+		true
+	}
+	fn client_cert(&self) -> Option<ClientCert> {
+		TlsStream::client_cert(self)
+	}
+}
+
+impl ClientStream for TcpStream {
+	fn peer_addr(&self) -> io::Result<SocketAddr> {
+		TcpStream::peer_addr(self)
+	}
+	fn is_tls(&self) -> bool {
+		false
+	}
+	fn client_cert(&self) -> Option<ClientCert> {
+		None
+	}
+}
+
+pub struct StreamHandler<S: ClientStream> {
+	pub stream: S,
 	pub buffer: Vec<u8>,
+	/// Hostname of the handshake, which picked the certificate and the CAs
+	/// of client certificates.
+	pub sni: Option<String>,
+	pub client_cert: Option<ClientCert>,
 }
 
-impl StreamHandler {
-	pub fn new(tls_stream: TlsStream<TcpStream>) -> Self {
+impl<S: ClientStream> StreamHandler<S> {
+	pub fn new(stream: S, sni: Option<String>) -> Self {
 		Self {
-			tls_stream: tls_stream,
+			client_cert: stream.client_cert(),
+			stream,
 			buffer: Vec::<u8>::new(),
+			sni,
 		}
 	}
 	pub async fn process(&mut self) {
-		self.read_headers().await;
+		// The HTTP listener hands over requests it has read already.
+		if self.buffer.is_empty() {
+			self.read_headers().await;
+		}
 		let mut hp: RequestParser = parse_headers(&self.buffer);
 		hp.check_is_static().await;
 		hp.check_is_multipart().await;
+		hp.check_is_urlencoded().await;
 		hp.parse_query();
-		if hp.is_valid() == false { return }
+		if hp.is_complete() == false { return }
+		let head_end = min(hp.headers_len + 1, self.buffer.len());
+		if let Ok(raw) = RawHead::parse(&self.buffer[..head_end]) {
+			let host = hp.get_header("host");
+			if self.redirect_to_canonical(&raw).await {
+				return;
+			}
+			if !self.check_client_cert(&host).await {
+				let text = "Client certificate required.".to_string();
+				return self.return_error_page(403, &host, text, vec![]).await;
+			}
+			if self.is_tls_reload_url(raw.path()).await {
+				return self.reload_tls(hp.method() == "post").await;
+			}
+			let r = if hp.is_websocket_upgrade() {
+				Some(self.websocket(&hp, raw).await)
+			} else if hp.is_static {
+				// Files of the site are served from disk, not by its
+				// backends, when it has static_dir or index_path.
+				None
+			} else if let Some(proxy) = self.proxy_conf(&host, raw.path()).await {
+				Some(self.proxy(&hp, raw, proxy).await)
+			} else if let Some(cgi) = self.cgi_conf(&host, raw.path()).await {
+				Some(self.cgi(&hp, raw, cgi).await)
+			} else {
+				None
+			};
+			match r {
+				None => {},
+				Some(Err(AppError::Streaming(_))) | Some(Ok(())) => return,
+				Some(Err(e)) => return self.return_app_err(e, &host).await,
+			}
+		}
+		if !hp.is_app_method() {
+			let allow = ("Allow".to_string(), "GET, POST, PUT, DELETE".to_string());
+			let text = "Method not allowed.".to_string();
+			return self.return_error_page(405, &hp.get_header("host"), text, vec![allow]).await;
+		}
 		if hp.is_static {
 			if hp.is_static_valid {
 				self.return_static(hp).await;
@@ -37,13 +177,28 @@ impl StreamHandler {
 			return;
 		}
 		if hp.method() == "post" || hp.method() == "put" {
-			self.read_post_body(&mut hp).await;
+			if hp.is_multipart {
+				if let Err(e) = self.read_multipart_body(&mut hp).await {
+					println!("{e}");
+					self.return_multipart_err(e).await;
+					return;
+				}
+			} else {
+				self.read_post_body(&mut hp).await;
+			}
+		}
+		let mut req: Request = hp.get_req();
+		if let Some(cert) = &self.client_cert {
+			req.route.insert("tls_client_subject".to_string(), cert.subject.to_string());
+			req.route.insert("tls_client_san".to_string(), cert.san.join(", "));
+			req.route.insert("tls_client_fingerprint".to_string(), cert.fingerprint.to_string());
 		}
-		let req: Request = hp.get_req();
-		match self.get_resp(req).await {
-			Err(e) => println!("{e}"),
-			Ok(resp) => self.write_resp(resp).await,
+		match self.stream_resp(req, &hp.body, hp.is_accept_brotli()).await {
+			Err(AppError::Streaming(_)) => {},
+			Err(e) => self.return_app_err(e, &hp.get_header("host")).await,
+			Ok(()) => {},
 		}
+		// Uploaded tmp files are removed here, when hp is dropped.
 	}
 	pub async fn read_headers(&mut self) {
 		let is_oneshot = true;
@@ -60,19 +215,61 @@ impl StreamHandler {
 
 		hp.body = self.buffer[hp.headers_len+1..].to_vec();
 
-		if hp.is_multipart {
-			parse_multipart(hp).await;
+		if hp.is_urlencoded {
+			parse_urlencoded(hp).await;
 		} else {
 		  hp.body_string = String::from_utf8(hp.body[..].to_vec()).unwrap();
 		}
 	}
+	pub async fn read_multipart_body(&mut self, hp: &mut RequestParser
+									 ) -> Result<(), MultipartError> {
+		let content_len = match hp.get_header("content-length").parse::<usize>() {
+			Ok(v) => v,
+			Err(_) => return Err(MultipartError::Malformed("no content-length".to_string())),
+		};
+		let conf = CONF.read().await;
+		if content_len > conf.max_upload_size_mb * 1024 * 1024 {
+			return Err(MultipartError::RequestTooLarge);
+		}
+		let limits = MultipartLimits {
+			tmp_dir: conf.tmp_dir.to_string(),
+			max_file_size: conf.max_upload_file_size_mb * 1024 * 1024,
+			max_fields_size: conf.max_request_size_mb * 1024 * 1024,
+			rules: match conf.server(&hp.get_header("host")) {
+				Some(srv) => UploadRules {
+					allowed_extensions: srv.upload_allowed_extensions.clone(),
+					allowed_mime_types: srv.upload_allowed_mime_types.clone(),
+				},
+				None => UploadRules::default(),
+			},
+		};
+		drop(conf);
+		let mut mp = MultipartStreamer::new(&hp.get_header("content-type"), limits)?;
+		let head = &self.buffer[min(hp.headers_len+1, self.buffer.len())..];
+		let head_len = min(head.len(), content_len);
+		mp.feed(&head[..head_len])?;
+		let mut bytes_left = content_len - head_len;
+		let mut buf = [0; 1024*32];
+		while bytes_left > 0 {
+			match self.stream.read(&mut buf).await {
+				Err(e) => return Err(MultipartError::Io(e)),
+				Ok(0) => break,
+				Ok(bytes_read) => {
+					let bytes_read = min(bytes_read, bytes_left);
+					mp.feed(&buf[..bytes_read])?;
+					bytes_left -= bytes_read;
+				}
+			}
+		}
+		mp.finish(hp)
+	}
 	pub async fn read(&mut self, is_oneshot: bool, bytes_left: usize) {
 		let conf = CONF.read().await;
 		let required_buffer_len = self.buffer.len() + bytes_left;
 		let mut buf = [0; 1024];
 		let mut is_done = false;
 		while is_done == false {
-			match self.tls_stream.read(&mut buf).await {
+			match self.stream.read(&mut buf).await {
 				Err(e) if e.kind() == ErrorKind::WouldBlock => {
 					println!("Stream read err: {e}");
 				}
@@ -97,50 +294,501 @@ impl StreamHandler {
 		}
 	}
 
-	pub async fn app_socket_path(&mut self, host: &String) -> Option<String> {
+	pub async fn app_conf(&mut self, host: &str) -> Option<BackendConf> {
 		let conf = CONF.read().await;
-		for srv in &conf.servers {
-			for hostname in &srv.hostnames {
-				if hostname == host {
-					return Some(srv.socket_path.to_string().clone());
-				}
-			}
+		let srv = conf.server(host)?;
+		Some(BackendConf {
+			group: upstream::get_group(srv).await,
+			timeouts: Timeouts::from_server(srv),
+			resp_headers: resp_headers(srv, self.stream.is_tls()),
+		})
+	}
+
+	/// Backend of a request which is proxied as plain HTTP, if any.
+	pub async fn proxy_conf(&self, host: &str, path: &str) -> Option<ProxyConf> {
+		let conf = CONF.read().await;
+		if path.starts_with(&conf.acme_challenge_url) { return None }
+		let srv = conf.server(host)?;
+		Some(ProxyConf {
+			addr: UpstreamAddr::parse(srv.proxy_for(path)?),
+			timeouts: Timeouts::from_server(srv),
+			resp_headers: resp_headers(srv, self.stream.is_tls()),
+		})
+	}
+
+	/// Forwards the request to a plain HTTP backend and streams its response
+	/// through the same post-processing as app responses.
+	pub async fn proxy(&mut self, hp: &RequestParser, raw: RawHead, proxy: ProxyConf
+					   ) -> Result<(), AppError> {
+		let (body_head, bytes_left) = self.raw_body_head(hp, &raw).await?;
+		let fwd = self.forward_info(&raw);
+		let mut out = proxy::request_head(&raw, &fwd);
+		out.extend(body_head);
+		let mut resp = RespProcessor::new(RespOptions {
+			is_accept_brotli: hp.is_accept_brotli(),
+			headers: proxy.resp_headers.clone(),
+			location_rewrites: proxy::location_rewrites(&proxy.addr, &fwd),
+			is_cgi: false,
+			is_head: raw.method().eq_ignore_ascii_case("head"),
+		});
+		let deadline = proxy.timeouts.start();
+		let mut phase = Phase::Connect;
+		let r = self.stream_exchange(out, bytes_left, &proxy.addr, &mut resp, &deadline,
+									&mut phase).await;
+		match &r {
+			Err(e) => println!("Proxy {} failed at {phase} phase: {e}", proxy.addr),
+			Ok(()) => println!("{} {}{} {}", hp.get_header("method"), fwd.host, raw.path(),
+							   resp.status().unwrap_or(0)),
+		}
+		r.map_err(|e| if phase == Phase::Read { AppError::Streaming(Box::new(e)) } else { e })
+	}
+
+	/// Exchange with a backend which takes the raw body after `out` and
+	/// answers on the same stream until it closes the connection.
+	async fn stream_exchange(&mut self, out: Vec<u8>, bytes_left: usize,
+							 addr: &UpstreamAddr, resp: &mut RespProcessor,
+							 deadline: &Deadline<'_>, phase: &mut Phase) -> Result<(), AppError> {
+		let mut stream = connect(addr, deadline, phase).await?;
+		self.forward_body(&mut stream, out, bytes_left, <[u8]>::to_vec, deadline, phase).await?;
+		*phase = Phase::FirstByte;
+		let mut buf = vec![0; 1024 * 32];
+		loop {
+			let n = match timeout(deadline.phase(*phase), stream.read(&mut buf)).await {
+				None => return Err(AppError::Timeout(*phase)),
+				Some(r) => r.map_err(AppError::Io)?,
+			};
+			// The backend closes the connection after the response.
+			let out = if n == 0 { resp.finish() } else { resp.feed(&buf[..n]) };
+			self.send_to_client(out.map_err(AppError::InvalidResponse)?, phase).await?;
+			if n == 0 { break }
+		}
+		Ok(())
+	}
+
+	/// CGI-style backend of a request, if any.
+	pub async fn cgi_conf(&self, host: &str, path: &str) -> Option<CgiBackend> {
+		let conf = CONF.read().await;
+		if path.starts_with(&conf.acme_challenge_url) { return None }
+		let srv = conf.server(host)?;
+		let cgi = srv.cgi.as_ref()?;
+		Some(CgiBackend {
+			addr: UpstreamAddr::parse(&cgi.pass),
+			conf: cgi.clone(),
+			server_port: if self.stream.is_tls() { conf.https_port } else { conf.http_port },
+			timeouts: Timeouts::from_server(srv),
+			resp_headers: resp_headers(srv, self.stream.is_tls()),
+		})
+	}
+
+	/// Runs the request on a FastCGI (php-fpm), SCGI or uwsgi server.
+	pub async fn cgi(&mut self, hp: &RequestParser, raw: RawHead, cgi: CgiBackend
+					 ) -> Result<(), AppError> {
+		let (body_head, bytes_left) = self.raw_body_head(hp, &raw).await?;
+		let fwd = self.forward_info(&raw);
+		let env = cgi::cgi_env(&raw, &fwd, &cgi.conf, cgi.server_port)
+			.map_err(AppError::BadRequest)?;
+		let protocol = cgi.conf.protocol;
+		let mut resp = RespProcessor::new(RespOptions {
+			is_accept_brotli: hp.is_accept_brotli(),
+			headers: cgi.resp_headers.clone(),
+			location_rewrites: vec![],
+			// uWSGI apps answer with a status line, the others with a CGI head.
+			is_cgi: protocol != CgiProtocol::Uwsgi,
+			is_head: raw.method().eq_ignore_ascii_case("head"),
+		});
+		let deadline = cgi.timeouts.start();
+		let mut phase = Phase::Connect;
+		let r = match protocol {
+			CgiProtocol::Fastcgi => {
+				let mut out = fastcgi::begin_request(&env);
+				out.extend(fastcgi::encode_stream(RecordType::Stdin, &body_head));
+				self.fastcgi_exchange(out, bytes_left, &cgi.addr, &mut resp, &deadline,
+									  &mut phase).await
+			},
+			CgiProtocol::Scgi | CgiProtocol::Uwsgi => {
+				let mut out = match protocol {
+					CgiProtocol::Scgi => scgi::request_head(&env),
+					_ => uwsgi::request_head(&env).map_err(AppError::BadRequest)?,
+				};
+				out.extend(body_head);
+				self.stream_exchange(out, bytes_left, &cgi.addr, &mut resp, &deadline,
+									 &mut phase).await
+			},
+		};
+		match &r {
+			Err(e) => println!("{protocol} {} failed at {phase} phase: {e}", cgi.addr),
+			Ok(()) => println!("{} {}{} {}", hp.get_header("method"), fwd.host, raw.path(),
+							   resp.status().unwrap_or(0)),
 		}
-		return None;
-	}
-
-
-	pub async fn get_resp(&mut self, req: Request) -> Result<Vec<u8>, &str> {
-		if let Some(socket_path) = self.app_socket_path(&req.host).await {
-			match UnixStream::connect(&socket_path).await {
-				Ok(mut unixstream) => {
-					let data : Vec<u8> = bincode::serialize(&req).unwrap();
-					let _ = unixstream.write_all(&data).await.unwrap();
-					let _ = unixstream.flush().await;
-					let _ = unixstream.close().await;
-					let mut resp: Vec<u8> = vec![];
-					let mut buf = [0; 1024*32];
-					loop {
-						match unixstream.read(&mut buf).await {
-							Err(e) => println!("Err reading unixstream: {e}"),
-							Ok(bytes_read) => {
-								if bytes_read == 0 { break; }
-								resp.extend_from_slice(&buf);
-							}
-						}
-					}
-					return Ok(resp);
+		r.map_err(|e| if phase == Phase::Read { AppError::Streaming(Box::new(e)) } else { e })
+	}
+
+	async fn fastcgi_exchange(&mut self, out: Vec<u8>, bytes_left: usize,
+							  addr: &UpstreamAddr, resp: &mut RespProcessor,
+							  deadline: &Deadline<'_>, phase: &mut Phase) -> Result<(), AppError> {
+		let mut stream = connect(addr, deadline, phase).await?;
+		let stdin = |v: &[u8]| fastcgi::encode_stream(RecordType::Stdin, v);
+		self.forward_body(&mut stream, out, bytes_left, stdin, deadline, phase).await?;
+		let sending = async {
+			stream.write_all(&fastcgi::end_stdin()).await?;
+			stream.flush().await
+		};
+		match timeout(deadline.phase(*phase), sending).await {
+			None => return Err(AppError::Timeout(*phase)),
+			Some(r) => r.map_err(AppError::Io)?,
+		}
+		*phase = Phase::FirstByte;
+		loop {
+			let output = match timeout(deadline.phase(*phase), fastcgi::read_output(&mut stream)).await {
+				None => return Err(AppError::Timeout(*phase)),
+				Some(r) => r?,
+			};
+			let out = match &output {
+				Output::Stdout(data) => resp.feed(data),
+				Output::End => resp.finish(),
+			};
+			self.send_to_client(out.map_err(AppError::InvalidResponse)?, phase).await?;
+			if output == Output::End { break }
+		}
+		Ok(())
+	}
+
+	/// Forwards a WebSocket handshake to the HTTP backend of the path, or
+	/// to the app. After a 101 response, bytes are tunneled both ways until
+	/// either side closes or there's no traffic for the idle timeout.
+	/// WebSockets on websocket_paths are terminated by miarh instead.
+	pub async fn websocket(&mut self, hp: &RequestParser, raw: RawHead) -> Result<(), AppError> {
+		let host = hp.get_header("host");
+		let (name, max_conns, idle_timeout, headers, is_endpoint) = {
+			let conf = CONF.read().await;
+			let srv = conf.server(&host).ok_or(AppError::NoServer)?;
+			(srv.name.to_string(), srv.websocket_max_connections,
+			 Duration::from_secs(srv.websocket_idle_timeout_secs), resp_headers(srv, self.stream.is_tls()),
+			 srv.websocket_paths.iter().any(|v| v == raw.path()))
+		};
+		let _slot = tunnel::acquire(&name, max_conns).await.ok_or(AppError::TooManyTunnels)?;
+		if is_endpoint {
+			return self.ws_endpoint(&host, raw, idle_timeout).await;
+		}
+		let fwd = self.forward_info(&raw);
+		let head = proxy::upgrade_head(&raw, &fwd);
+		let upgrade = match self.proxy_conf(&host, raw.path()).await {
+			Some(proxy) => upgrade_proxy(&head, &proxy).await?,
+			None => self.upgrade_app(&head, &host, &hp.get_header("session_id")).await?,
+		};
+		let mut phase = Phase::FirstByte;
+		let mut stream = match upgrade.stream {
+			Some(v) => v,
+			None => {
+				// Refused, e.g. 403, sent as any other response.
+				let mut resp = RespProcessor::new(RespOptions {
+					is_accept_brotli: hp.is_accept_brotli(),
+					headers,
+					location_rewrites: vec![],
+					is_cgi: false,
+					is_head: false,
+				});
+				let mut out = resp.feed(&upgrade.resp).map_err(AppError::InvalidResponse)?;
+				out.extend(resp.finish().map_err(AppError::InvalidResponse)?);
+				println!("GET {host}{} {}", raw.path(), resp.status().unwrap_or(0));
+				return self.send_to_client(out, &mut phase).await;
+			},
+		};
+		self.send_to_client(upgrade.resp, &mut phase).await?;
+		println!("GET {host}{} 101", raw.path());
+		if let Err(e) = tunnel::pipe(&mut self.stream, &mut stream, idle_timeout).await {
+			println!("WebSocket {host}{} closed: {e}", raw.path());
+		}
+		Ok(())
+	}
+
+	/// Does the handshake and serves the WebSocket, its messages go to the
+	/// app over the server's bridge.
+	async fn ws_endpoint(&mut self, host: &str, raw: RawHead, idle_timeout: Duration
+						 ) -> Result<(), AppError> {
+		let (name, group, connect_timeout, max_len) = {
+			let conf = CONF.read().await;
+			let srv = conf.server(host).ok_or(AppError::NoServer)?;
+			(srv.name.to_string(), upstream::get_group(srv).await,
+			 Duration::from_millis(srv.connect_timeout_ms), srv.websocket_max_message_kb * 1024)
+		};
+		let resp = match ws::handshake_resp(raw.get("sec-websocket-key"),
+											raw.get("sec-websocket-version")) {
+			Ok(v) => v,
+			Err((code, text)) => {
+				let headers = match code {
+					426 => vec![("Sec-WebSocket-Version".to_string(), "13".to_string())],
+					_ => vec![],
+				};
+				self.return_error_page(code, host, text, headers).await;
+				return Ok(());
+			},
+		};
+		// Only valid handshakes connect to the app.
+		let bridge = ws_bridge::get_bridge(&name, &group, connect_timeout).await?;
+		let fwd = self.forward_info(&raw);
+		let conn = bridge.open(proxy::upgrade_head(&raw, &fwd)).await?;
+		let conn_id = conn.id;
+		let mut phase = Phase::FirstByte;
+		if let Err(e) = self.send_to_client(resp, &mut phase).await {
+			bridge.close(conn_id, ws_bridge::CLOSE_ABNORMAL).await;
+			return Err(e);
+		}
+		println!("GET {host}{} 101, WebSocket {conn_id}", raw.path());
+		let code = ws_bridge::serve(&mut self.stream, &bridge, conn, idle_timeout,
+									max_len).await;
+		bridge.close(conn_id, code).await;
+		Ok(())
+	}
+
+	async fn upgrade_app(&mut self, head: &[u8], host: &str, session_id: &str
+						 ) -> Result<Upgrade, AppError> {
+		let backend = self.app_conf(host).await.ok_or(AppError::NoServer)?;
+		backend.check_available()?;
+		let deadline = backend.timeouts.start();
+		let mut tried = vec![];
+		let mut last_err = None;
+		while let Some(picked) = backend.group.pick(session_id, &tried) {
+			let upstream = &picked.upstream;
+			tried.push(upstream.idx);
+			let mut phase = Phase::Connect;
+			let r = upgrade_exchange(&upstream.pool, head, &deadline, &mut phase).await;
+			match &r {
+				Err(e) => {
+					println!("Backend {} failed at {phase} phase: {e}", upstream.addr());
+					if e.is_upstream_failure() { backend.group.failed(upstream) }
 				},
+				Ok(_) => backend.group.succeeded(upstream),
+			}
+			match r {
+				Err(e) if phase == Phase::Connect => last_err = Some(e),
+				r => return r,
+			}
+		}
+		Err(last_err.unwrap_or(AppError::Unavailable(None)))
+	}
+
+	/// Body bytes which came with the head, and the number of bytes left
+	/// to read from the client, for backends which get the raw body.
+	async fn raw_body_head(&self, hp: &RequestParser, raw: &RawHead
+						   ) -> Result<(Vec<u8>, usize), AppError> {
+		if raw.get("transfer-encoding").is_some() {
+			return Err(AppError::LengthRequired);
+		}
+		let content_len = hp.get_header("content-length").parse::<usize>().unwrap_or(0);
+		if content_len > CONF.read().await.max_upload_size_mb * 1024 * 1024 {
+			return Err(AppError::TooLarge);
+		}
+		let body_start = min(hp.headers_len + 1, self.buffer.len());
+		let body_head = &self.buffer[body_start..min(body_start + content_len, self.buffer.len())];
+		Ok((body_head.to_vec(), content_len - body_head.len()))
+	}
+
+	fn forward_info(&self, raw: &RawHead) -> ForwardInfo {
+		ForwardInfo {
+			client_ip: self.stream.peer_addr().ok().map(|v| v.ip()),
+			host: raw.get("host").unwrap_or("").to_string(),
+			proto: if self.stream.is_tls() { "https" } else { "http" }.to_string(),
+			client_cert: self.client_cert.clone(),
+		}
+	}
+
+	/// Sends `out` to the backend, then the rest of the client body,
+	/// framed by `wrap`, as it arrives.
+	async fn forward_body(&mut self, stream: &mut AppStream, mut out: Vec<u8>,
+						  mut bytes_left: usize, wrap: impl Fn(&[u8]) -> Vec<u8>,
+						  deadline: &Deadline<'_>, phase: &mut Phase) -> Result<(), AppError> {
+		*phase = Phase::Write;
+		let mut buf = vec![0; 1024 * 32];
+		loop {
+			let sending = async {
+				stream.write_all(&out).await?;
+				stream.flush().await
+			};
+			match timeout(deadline.phase(*phase), sending).await {
+				None => return Err(AppError::Timeout(*phase)),
+				Some(r) => r.map_err(AppError::Io)?,
+			}
+			if bytes_left == 0 { return Ok(()) }
+			let max = min(buf.len(), bytes_left);
+			let n = self.stream.read(&mut buf[..max]).await.map_err(AppError::Client)?;
+			if n == 0 {
+				return Err(AppError::Client(ErrorKind::UnexpectedEof.into()));
+			}
+			bytes_left -= n;
+			out = wrap(&buf[..n]);
+		}
+	}
+
+	/// Writes a part of the response. From the first written byte on,
+	/// errors can only close the connection.
+	async fn send_to_client(&mut self, out: Vec<u8>, phase: &mut Phase) -> Result<(), AppError> {
+		if out.is_empty() { return Ok(()) }
+		*phase = Phase::Read;
+		self.stream.write_all(&out).await.map_err(AppError::Client)?;
+		self.stream.flush().await.map_err(AppError::Client)
+	}
+
+	/// Sends the request to the app and streams its response to the client
+	/// frame by frame, as it arrives. Upstreams which fail before the request
+	/// is sent are retried with the next available one.
+	pub async fn stream_resp(&mut self, req: Request, body: &[u8], is_accept_brotli: bool
+							 ) -> Result<(), AppError> {
+		let backend = match self.app_conf(&req.host).await {
+			Some(v) => v,
+			None => return Err(AppError::NoServer),
+		};
+		backend.check_available()?;
+		let mut resp = RespProcessor::new(RespOptions {
+			is_accept_brotli,
+			headers: backend.resp_headers,
+			location_rewrites: vec![],
+			is_cgi: false,
+			is_head: false,
+		});
+		let deadline = backend.timeouts.start();
+		let mut tried = vec![];
+		let mut last_err = None;
+		while let Some(picked) = backend.group.pick(&req.session_id, &tried) {
+			let upstream = &picked.upstream;
+			tried.push(upstream.idx);
+			let mut phase = Phase::Connect;
+			let r = self.exchange(&upstream.pool, &req, body, &mut resp, &deadline,
+								  &mut phase).await;
+			match &r {
 				Err(e) => {
-					println!("Can't connect to app server: {e}");
-				}
+					println!("Backend {} failed at {phase} phase: {e}", upstream.addr());
+					if e.is_upstream_failure() { backend.group.failed(upstream) }
+				},
+				Ok(()) => {
+					println!("{} {}{} {}", req.method, req.host, req.path,
+							 resp.status().unwrap_or(0));
+					backend.group.succeeded(upstream);
+				},
+			}
+			match r {
+				Err(e) if phase == Phase::Connect => last_err = Some(e),
+				Err(e) if phase == Phase::Read => return Err(AppError::Streaming(Box::new(e))),
+				r => return r,
+			}
+		}
+		Err(last_err.unwrap_or(AppError::Unavailable(None)))
+	}
+
+	async fn exchange(&mut self, pool: &AppPool, req: &Request, body: &[u8],
+					  resp: &mut RespProcessor, deadline: &Deadline<'_>,
+					  phase: &mut Phase) -> Result<(), AppError> {
+		let mut conn = match timeout(deadline.phase(*phase), pool.get()).await {
+			None => return Err(AppError::Timeout(*phase)),
+			Some(r) => r?,
+		};
+		*phase = Phase::Write;
+		let sending = app_proto::send_request(&mut conn.stream, req, body);
+		match timeout(deadline.phase(*phase), sending).await {
+			None => return Err(AppError::Timeout(*phase)),
+			Some(r) => r?,
+		}
+		*phase = Phase::FirstByte;
+		loop {
+			let reading = app_proto::read_frame(&mut conn.stream);
+			let frame = match timeout(deadline.phase(*phase), reading).await {
+				None => return Err(AppError::Timeout(*phase)),
+				Some(r) => r?,
+			};
+			let out = match frame.kind {
+				FrameType::Body => resp.feed(&frame.payload),
+				FrameType::End => resp.finish(),
+				kind => return Err(ProtoError::Unexpected(kind).into()),
+			};
+			let out = out.map_err(AppError::InvalidResponse)?;
+			if !out.is_empty() {
+				// From here on the response is partially sent and
+				// errors can only close the connection.
+				*phase = Phase::Read;
+				// write_all() waits for the client, so a slow client
+				// slows down reading from the app.
+				self.stream.write_all(&out).await.map_err(AppError::Client)?;
+				self.stream.flush().await.map_err(AppError::Client)?;
+			}
+			if frame.kind == FrameType::End { break }
+		}
+		pool.release(conn).await;
+		Ok(())
+	}
+
+	/// Client auth of the server of `host`. The certificate is kept for
+	/// the server whose CAs verified it only, the one named by SNI.
+	pub async fn check_client_cert(&mut self, host: &str) -> bool {
+		let conf = CONF.read().await;
+		let srv = conf.server(host);
+		let auth = srv.and_then(|v| v.client_auth.as_ref());
+		let sni_srv = self.sni.as_deref().and_then(|v| conf.server(v));
+		let is_verified_for_srv = match (srv, sni_srv) {
+			(Some(srv), Some(sni_srv)) => auth.is_some() && srv.name == sni_srv.name,
+			_ => false,
+		};
+		if !is_verified_for_srv {
+			self.client_cert = None;
+		}
+		match auth {
+			Some(auth) => tls::client_allowed(self.client_cert.as_ref(), auth),
+			None => true,
+		}
+	}
+
+	/// Redirects requests for other hostnames of a server with a
+	/// canonical_host, keeping the scheme and port.
+	pub async fn redirect_to_canonical(&mut self, raw: &RawHead) -> bool {
+		// The parsed Host has no port.
+		let (hostname, port) = http::split_host(raw.get("host").unwrap_or(""));
+		let hostname = hostname.to_lowercase();
+		let (code, canonical, hsts) = {
+			let conf = CONF.read().await;
+			let srv = match conf.server(&hostname) {
+				Some(v) => v,
+				None => return false,
+			};
+			match srv.canonical_redirect(&hostname) {
+				Some(v) => (srv.redirect_code, v.to_string(), hsts_header(srv)),
+				None => return false,
 			}
+		};
+		let scheme = if self.stream.is_tls() { "https" } else { "http" };
+		let mut r = http::redirect_resp(code, &http::url(scheme, &canonical, port, raw.target()));
+		if self.stream.is_tls() {
+			r.headers.extend(hsts);
 		}
-		Err("Can't get a response.")
+		let _ = self.stream.write_all(r.get_resp().as_bytes()).await;
+		true
+	}
+
+	/// Strict-Transport-Security of the server of `host`, over TLS only.
+	async fn hsts_headers(&self, host: &str) -> Vec<(String, String)> {
+		if !self.stream.is_tls() { return vec![] }
+		let conf = CONF.read().await;
+		conf.server(host).and_then(hsts_header).into_iter().collect()
+	}
+
+	pub async fn is_tls_reload_url(&self, path: &str) -> bool {
+		let conf = CONF.read().await;
+		!conf.plain_http && !conf.tls.reload_url.is_empty() && conf.tls.reload_url == path
 	}
 
-	pub async fn write_resp(&mut self, resp: Vec<u8>) {
-		let _ = self.tls_stream.write_all(&resp).await;
+	/// Admin call reloading the TLS certificates, allowed from localhost only.
+	pub async fn reload_tls(&mut self, is_post: bool) {
+		let is_local = self.stream.peer_addr()
+			.map(|addr| addr.ip().is_loopback()).unwrap_or(false);
+		let r = if !is_local {
+			http::text_resp(403, "Forbidden.".to_string())
+		} else if !is_post {
+			http::text_resp(405, "Method not allowed.".to_string())
+		} else {
+			match tls::reload().await {
+				Ok(()) => http::text_resp(200, "TLS certificates reloaded.".to_string()),
+				Err(e) => http::text_resp(500, e.to_string()),
+			}
+		};
+		let _ = self.stream.write_all(r.get_resp().as_bytes()).await;
 	}
 
 	pub async fn return_html_test(&mut self) {
@@ -150,21 +798,160 @@ impl StreamHandler {
 			Hello, world\
 			\r\n\r\n";
 		let resp = resp.to_string().into_bytes();
-		let _ = self.tls_stream.write_all(&resp).await;
+		let _ = self.stream.write_all(&resp).await;
 	}
 
 	pub async fn return_static(&mut self, hp: RequestParser) {
-		match static_handler::get_static_file(hp).await {
-			Some(r) => { let _ = self.tls_stream.write_all(&r).await; },
+		let headers = self.hsts_headers(&hp.get_header("host")).await;
+		match static_handler::get_static_file(hp, &headers).await {
+			Some(r) => { let _ = self.stream.write_all(&r).await; },
 			None => self.return_404().await,
 		};
 	}
 	pub async fn return_404(&mut self) {
 		let r = http::text_resp(404, "Not found".to_string());
-		let _ = self.tls_stream.write_all(&r.get_resp().as_bytes()).await;
+		let _ = self.stream.write_all(&r.get_resp().as_bytes()).await;
+	}
+	pub async fn return_app_err(&mut self, e: AppError, host: &str) {
+		let headers = match e.retry_after() {
+			Some(secs) => vec![("Retry-After".to_string(), secs.max(1).to_string())],
+			None => vec![],
+		};
+		self.return_error_page(e.http_code(), host, e.to_string(), headers).await;
+	}
+	pub async fn return_error_page(&mut self, code: u16, host: &str, text: String,
+								   headers: Vec<(String, String)>) {
+		let page = {
+			let conf = CONF.read().await;
+			conf.server(host).and_then(|srv| srv.error_pages.get(&code.to_string()).cloned())
+		};
+		let page = match page {
+			Some(path) => Some(blocking::unblock(move || fs::read_to_string(path)).await),
+			None => None,
+		};
+		let text = match page {
+			Some(Ok(v)) => v,
+			Some(Err(e)) => { println!("Error page read err: {e}"); text },
+			None => text,
+		};
+		let mut r = http::text_resp(code, text);
+		r.headers = headers;
+		r.headers.extend(self.hsts_headers(host).await);
+		let _ = self.stream.write_all(r.get_resp().as_bytes()).await;
+	}
+	pub async fn return_multipart_err(&mut self, e: MultipartError) {
+		let r = http::text_resp(e.http_code(), e.to_string());
+		let _ = self.stream.write_all(&r.get_resp().as_bytes()).await;
 	}
 	pub async fn return_413_entity_too_large(&mut self) {
 		let r = http::text_resp(413, "Request entity too large.".to_string());
-		let _ = self.tls_stream.write_all(&r.get_resp().as_bytes()).await;
+		let _ = self.stream.write_all(&r.get_resp().as_bytes()).await;
+	}
+}
+
+/// Per-server response headers, sorted to be applied in a stable order,
+/// and HSTS over TLS.
+fn resp_headers(srv: &ServerConf, is_tls: bool) -> Vec<(String, String)> {
+	let mut headers: Vec<(String, String)> = srv.response_headers.iter()
+		.map(|(k, v)| (k.to_string(), v.to_string())).collect();
+	headers.sort();
+	if is_tls {
+		headers.extend(hsts_header(srv));
 	}
+	headers
+}
+
+fn hsts_header(srv: &ServerConf) -> Option<(String, String)> {
+	let hsts = srv.hsts.as_ref()?;
+	Some(("Strict-Transport-Security".to_string(), hsts.header_value()))
+}
+
+async fn connect(addr: &UpstreamAddr, deadline: &Deadline<'_>, phase: &mut Phase
+				 ) -> Result<AppStream, AppError> {
+	*phase = Phase::Connect;
+	match timeout(deadline.phase(*phase), addr.connect()).await {
+		None => Err(AppError::Timeout(*phase)),
+		Some(r) => r.map_err(AppError::Connect),
+	}
+}
+
+/// Sends a WebSocket handshake to an HTTP backend. A refused upgrade is
+/// read up to its Content-Length, the backend may keep the connection open.
+async fn upgrade_proxy(head: &[u8], proxy: &ProxyConf) -> Result<Upgrade, AppError> {
+	let deadline = proxy.timeouts.start();
+	let mut phase = Phase::Connect;
+	let mut stream = connect(&proxy.addr, &deadline, &mut phase).await?;
+	phase = Phase::Write;
+	let sending = async {
+		stream.write_all(head).await?;
+		stream.flush().await
+	};
+	match timeout(deadline.phase(phase), sending).await {
+		None => return Err(AppError::Timeout(phase)),
+		Some(r) => r.map_err(AppError::Io)?,
+	}
+	phase = Phase::FirstByte;
+	let mut resp = vec![];
+	let (head, head_len) = loop {
+		if let Some(end) = memmem::find(&resp, b"\r\n\r\n") {
+			break (RespHead::parse(&resp[..end]).map_err(AppError::InvalidResponse)?, end + 4);
+		}
+		if resp.len() > MAX_RESP_HEAD_SIZE {
+			return Err(AppError::InvalidResponse("response head is too large".to_string()));
+		}
+		if read_some(&mut stream, &mut resp, &deadline, phase).await? == 0 {
+			return Err(AppError::InvalidResponse("no response head".to_string()));
+		}
+	};
+	if head.code == 101 {
+		return Ok(Upgrade { resp, stream: Some(stream) });
+	}
+	let resp_len = head.get("content-length").and_then(|v| v.parse::<usize>().ok())
+		.map(|v| head_len + v);
+	phase = Phase::Read;
+	while resp_len.map(|v| resp.len() < v).unwrap_or(true) {
+		if read_some(&mut stream, &mut resp, &deadline, phase).await? == 0 { break }
+	}
+	if let Some(v) = resp_len {
+		resp.truncate(v);
+	}
+	Ok(Upgrade { resp, stream: None })
+}
+
+async fn upgrade_exchange(pool: &AppPool, head: &[u8], deadline: &Deadline<'_>,
+						  phase: &mut Phase) -> Result<Upgrade, AppError> {
+	let mut conn = match timeout(deadline.phase(*phase), pool.get()).await {
+		None => return Err(AppError::Timeout(*phase)),
+		Some(r) => r?,
+	};
+	*phase = Phase::Write;
+	match timeout(deadline.phase(*phase), app_proto::send_upgrade(&mut conn.stream, head)).await {
+		None => return Err(AppError::Timeout(*phase)),
+		Some(r) => r?,
+	}
+	*phase = Phase::FirstByte;
+	let resp = match timeout(deadline.phase(*phase), app_proto::read_response(&mut conn.stream)).await {
+		None => return Err(AppError::Timeout(*phase)),
+		Some(r) => r?,
+	};
+	let end = memmem::find(&resp, b"\r\n\r\n")
+		.ok_or(AppError::InvalidResponse("no response head".to_string()))?;
+	let head = RespHead::parse(&resp[..end]).map_err(AppError::InvalidResponse)?;
+	if head.code == 101 {
+		return Ok(Upgrade { resp, stream: Some(conn.into_stream()) });
+	}
+	pool.release(conn).await;
+	Ok(Upgrade { resp, stream: None })
+}
+
+/// Appends what the stream has to `buf`, 0 at EOF.
+async fn read_some(stream: &mut AppStream, buf: &mut Vec<u8>, deadline: &Deadline<'_>,
+				   phase: Phase) -> Result<usize, AppError> {
+	let mut chunk = vec![0; 1024 * 32];
+	let n = match timeout(deadline.phase(phase), stream.read(&mut chunk)).await {
+		None => return Err(AppError::Timeout(phase)),
+		Some(r) => r.map_err(AppError::Io)?,
+	};
+	buf.extend_from_slice(&chunk[..n]);
+	Ok(n)
 }
@@ -0,0 +1,86 @@
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use async_io::Timer;
+use futures_lite::future;
+use crate::conf::ServerConf;
+
+
+/// Stage of a backend exchange, used to report where it failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Connect,
+    Write,
+    FirstByte,
+    Read,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Phase::Connect => write!(f, "connect"),
+            Phase::Write => write!(f, "write"),
+            Phase::FirstByte => write!(f, "first byte"),
+            Phase::Read => write!(f, "read"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub write: Duration,
+    pub first_byte: Duration,
+    pub read: Duration,
+    /// Zero means no limit, e.g. for server-sent events.
+    pub total: Duration,
+}
+
+impl Timeouts {
+    pub fn from_server(srv: &ServerConf) -> Self {
+        Self {
+            connect: Duration::from_millis(srv.connect_timeout_ms),
+            write: Duration::from_millis(srv.write_timeout_ms),
+            first_byte: Duration::from_millis(srv.first_byte_timeout_ms),
+            read: Duration::from_millis(srv.read_timeout_ms),
+            total: Duration::from_millis(srv.total_timeout_ms),
+        }
+    }
+    /// Timeout of a phase, cut down to what is left of the total timeout.
+    pub fn phase(&self, phase: Phase, started: Instant) -> Duration {
+        let limit = match phase {
+            Phase::Connect => self.connect,
+            Phase::Write => self.write,
+            Phase::FirstByte => self.first_byte,
+            Phase::Read => self.read,
+        };
+        if self.total.is_zero() {
+            return limit;
+        }
+        let left = self.total.saturating_sub(started.elapsed());
+        limit.min(left)
+    }
+    pub fn start(&self) -> Deadline<'_> {
+        Deadline { timeouts: self, started: Instant::now() }
+    }
+}
+
+/// Timeouts of one backend exchange, counted from its start.
+pub struct Deadline<'a> {
+    timeouts: &'a Timeouts,
+    started: Instant,
+}
+
+impl Deadline<'_> {
+    pub fn phase(&self, phase: Phase) -> Duration {
+        self.timeouts.phase(phase, self.started)
+    }
+}
+
+/// Returns None if `f` didn't complete in `d`.
+pub async fn timeout<T>(d: Duration, f: impl Future<Output = T>) -> Option<T> {
+    future::or(
+        async { Some(f.await) },
+        async { Timer::after(d).await; None },
+    ).await
+}
@@ -0,0 +1,733 @@
+// TLS identity of the HTTPS listener, loaded at runtime so that renewed
+// certificates need no rebuild. Handshakes are done by native-tls, or by
+// rustls with the rustls feature. Servers which ask for client
+// certificates get OpenSSL directly, native-tls can't ask for them.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use async_io::Timer;
+use async_lock::RwLock;
+use async_native_tls::Identity;
+use async_net::TcpStream;
+use futures_lite::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use native_tls::Protocol;
+use once_cell::sync::Lazy;
+use openssl::hash::MessageDigest;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::X509;
+use serde::Deserialize;
+use crate::conf::{ClientAuthConf, Conf, TlsConf, CONF};
+use crate::timeout::timeout;
+use crate::tls_openssl;
+#[cfg(feature = "rustls")]
+use crate::tls_rustls;
+
+
+/// Bytes peeked for the ClientHello, which is usually well below 2K.
+const CLIENT_HELLO_MAX_LEN: usize = 32 * 1024;
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Fatal unrecognized_name alert.
+const UNRECOGNIZED_NAME_ALERT: [u8; 7] = [21, 3, 1, 0, 2, 2, 112];
+/// Renewals write several files, wait for the last one before reloading.
+const WATCH_SETTLE: Duration = Duration::from_secs(2);
+
+/// Acceptors of new handshakes. Reloads swap them, connections keep the
+/// acceptor they started with.
+static ACCEPTORS: Lazy<RwLock<Option<Arc<Acceptors>>>> = Lazy::new(|| {
+    RwLock::new(None)
+});
+
+
+#[derive(Debug)]
+pub enum TlsError {
+    NotConfigured,
+    Read(String, io::Error),
+    Pkcs12(String, String),
+    Pem(String, String),
+    /// The private key is not the one of the certificate in the file.
+    KeyMismatch(String),
+    Acceptor(String),
+    /// Handshake settings the backend can't do or doesn't know.
+    Options(String),
+    /// Certificate of a server, by name.
+    Server(String, Box<TlsError>),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::NotConfigured => write!(f, "No TLS certificate, set tls.cert_path and \
+                tls.key_path, or tls.pkcs12_path in miarh.toml"),
+            TlsError::Read(path, e) if e.kind() == io::ErrorKind::NotFound => {
+                write!(f, "TLS file not found: {path}")
+            },
+            TlsError::Read(path, e) => write!(f, "Can't read TLS file {path}: {e}"),
+            TlsError::Pkcs12(path, e) => write!(f, "Bad PKCS#12 file {path}: {e}"),
+            TlsError::Pem(path, e) => write!(f, "Bad PEM file {path}: {e}"),
+            TlsError::KeyMismatch(path) => {
+                write!(f, "Private key doesn't match the certificate of {path}")
+            },
+            TlsError::Acceptor(e) => write!(f, "Can't set up TLS: {e}"),
+            TlsError::Options(e) => write!(f, "Bad TLS settings: {e}"),
+            TlsError::Server(name, e) => write!(f, "Server {name}: {e}"),
+        }
+    }
+}
+
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsBackend {
+    /// OpenSSL through native-tls.
+    #[default]
+    Native,
+    /// Needs the rustls cargo feature.
+    Rustls,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    /// rustls only, native-tls can't require it.
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuthMode {
+    /// Handshakes without a certificate of the CAs fail.
+    #[default]
+    Required,
+    /// Handshakes go on without a certificate, the app decides.
+    Optional,
+}
+
+/// Certificate chain, leaf first, and its key, checked to match.
+pub struct CertKey {
+    pub chain: Vec<X509>,
+    pub key: PKey<Private>,
+}
+
+/// Verified client certificate, as passed on to the backends.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientCert {
+    /// RFC 4514 distinguished name, e.g. "CN=alice,O=Example".
+    pub subject: String,
+    /// Subject alternative names, e.g. "DNS:a.example" or "email:a@example".
+    pub san: Vec<String>,
+    /// SHA-256 of the DER certificate, lowercase hex.
+    pub fingerprint: String,
+}
+
+impl ClientCert {
+    pub fn from_x509(cert: &X509) -> Self {
+        // Most significant part last in the certificate, first in the name.
+        let subject = cert.subject_name().entries()
+            .map(|entry| {
+                let key = entry.object().nid().short_name().unwrap_or("?");
+                let value = entry.data().as_utf8().map(|v| v.to_string()).unwrap_or_default();
+                format!("{key}={}", escape_dn_value(&value))
+            })
+            .collect::<Vec<_>>();
+        let subject = subject.into_iter().rev().collect::<Vec<_>>().join(",");
+        let mut san = vec![];
+        for name in cert.subject_alt_names().iter().flatten() {
+            if let Some(v) = name.dnsname() {
+                san.push(format!("DNS:{v}"));
+            } else if let Some(v) = name.email() {
+                san.push(format!("email:{v}"));
+            } else if let Some(v) = name.uri() {
+                san.push(format!("URI:{v}"));
+            } else if let Some(ip) = name.ipaddress().and_then(ip_addr) {
+                san.push(format!("IP:{ip}"));
+            }
+        }
+        // They end up in header values.
+        san.retain(|v| !v.chars().any(|c| c.is_control()));
+        let fingerprint = cert.digest(MessageDigest::sha256())
+            .map(|v| v.iter().map(|b| format!("{b:02x}")).collect())
+            .unwrap_or_default();
+        Self { subject, san, fingerprint }
+    }
+}
+
+/// Whether a request may go to a server with client auth: any certificate
+/// which passed the handshake if there are no subject patterns, no
+/// certificate only if it's optional.
+pub fn client_allowed(cert: Option<&ClientCert>, auth: &ClientAuthConf) -> bool {
+    match cert {
+        None => auth.mode == ClientAuthMode::Optional,
+        Some(cert) => auth.allowed_subjects.is_empty()
+            || auth.allowed_subjects.iter().any(|p| glob_match(p, &cert.subject)),
+    }
+}
+
+/// RFC 4514 escapes, and control characters as hex pairs.
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        if ",+\"<>;\\".contains(c) {
+            escaped.push('\\');
+            escaped.push(c);
+        } else if c.is_control() {
+            for b in c.to_string().bytes() {
+                escaped.push_str(&format!("\\{b:02X}"));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+fn ip_addr(octets: &[u8]) -> Option<std::net::IpAddr> {
+    match octets.len() {
+        4 => Some(<[u8; 4]>::try_from(octets).ok()?.into()),
+        16 => Some(<[u8; 16]>::try_from(octets).ok()?.into()),
+        _ => None,
+    }
+}
+
+/// `*` matches any text within a value, including none. It stops at the
+/// next separating comma and takes escapes whole, so an escaped comma
+/// can't pass for a separator.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (head, tail) = match pattern.split_once('*') {
+        Some(v) => v,
+        None => return pattern == text,
+    };
+    let rest = match text.strip_prefix(head) {
+        Some(v) => v,
+        None => return false,
+    };
+    let mut ends = vec![0];
+    let mut chars = rest.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        if c == ',' {
+            break;
+        }
+        if c == '\\' {
+            chars.next();
+        }
+        ends.push(chars.peek().map(|(i, _)| *i).unwrap_or(rest.len()));
+    }
+    ends.into_iter().any(|i| glob_match(tail, &rest[i..]))
+}
+
+
+pub enum TlsAcceptor {
+    Native(async_native_tls::TlsAcceptor),
+    Openssl(openssl::ssl::SslAcceptor),
+    #[cfg(feature = "rustls")]
+    Rustls(futures_rustls::TlsAcceptor),
+}
+
+impl TlsAcceptor {
+    pub async fn accept(&self, stream: TcpStream) -> io::Result<TlsStream> {
+        match self {
+            TlsAcceptor::Native(v) => v.accept(stream).await
+                .map(TlsStream::Native)
+                .map_err(io::Error::other),
+            TlsAcceptor::Openssl(v) => tls_openssl::accept(v, stream).await.map(TlsStream::Openssl),
+            #[cfg(feature = "rustls")]
+            TlsAcceptor::Rustls(v) => v.accept(stream).await.map(TlsStream::Rustls),
+        }
+    }
+}
+
+/// Connection after the handshake, of either backend.
+pub enum TlsStream {
+    Native(async_native_tls::TlsStream<TcpStream>),
+    Openssl(tls_openssl::SslStream),
+    #[cfg(feature = "rustls")]
+    Rustls(futures_rustls::server::TlsStream<TcpStream>),
+}
+
+impl TlsStream {
+    pub fn get_ref(&self) -> &TcpStream {
+        match self {
+            TlsStream::Native(v) => v.get_ref(),
+            TlsStream::Openssl(v) => v.get_ref(),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(v) => v.get_ref().0,
+        }
+    }
+    /// Certificate the client sent and the handshake verified. native-tls
+    /// never asks for one.
+    pub fn client_cert(&self) -> Option<ClientCert> {
+        match self {
+            TlsStream::Native(_) => None,
+            TlsStream::Openssl(v) => v.client_cert(),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(v) => {
+                let der = v.get_ref().1.peer_certificates()?.first()?;
+                X509::from_der(der).ok().map(|cert| ClientCert::from_x509(&cert))
+            },
+        }
+    }
+}
+
+impl AsyncRead for TlsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            TlsStream::Native(v) => Pin::new(v).poll_read(cx, buf),
+            TlsStream::Openssl(v) => Pin::new(v).poll_read(cx, buf),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(v) => Pin::new(v).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TlsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            TlsStream::Native(v) => Pin::new(v).poll_write(cx, buf),
+            TlsStream::Openssl(v) => Pin::new(v).poll_write(cx, buf),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(v) => Pin::new(v).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TlsStream::Native(v) => Pin::new(v).poll_flush(cx),
+            TlsStream::Openssl(v) => Pin::new(v).poll_flush(cx),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(v) => Pin::new(v).poll_flush(cx),
+        }
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TlsStream::Native(v) => Pin::new(v).poll_close(cx),
+            TlsStream::Openssl(v) => Pin::new(v).poll_close(cx),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(v) => Pin::new(v).poll_close(cx),
+        }
+    }
+}
+
+
+/// Server name of a ClientHello.
+#[derive(Debug, PartialEq)]
+pub enum Sni {
+    /// More bytes are needed.
+    Incomplete,
+    /// No server_name extension, or not a ClientHello at all.
+    Missing,
+    /// Lowercase, without a trailing dot.
+    Name(String),
+}
+
+
+/// Acceptors by SNI hostname: the servers' own certificates, the
+/// top-level one for the rest.
+pub struct Acceptors {
+    default: Option<Arc<TlsAcceptor>>,
+    by_hostname: HashMap<String, Arc<TlsAcceptor>>,
+    reject_unknown: bool,
+}
+
+impl Acceptors {
+    /// The top-level certificate may be left out, with `pkcs12_path = ""`,
+    /// if every server has its own. Unknown names are refused then.
+    pub fn from_conf(conf: &Conf) -> Result<Self, TlsError> {
+        let default = match acceptor(&conf.tls, &conf.tls) {
+            Ok(v) => Some(Arc::new(v)),
+            Err(TlsError::NotConfigured) => None,
+            Err(e) => return Err(e),
+        };
+        let mut by_hostname = HashMap::new();
+        for srv in &conf.servers {
+            let server_err = |e| TlsError::Server(srv.name.to_string(), Box::new(e));
+            let acceptor = match (&srv.tls, &srv.client_auth, &default) {
+                (tls, Some(auth), _) => {
+                    let tls = tls.as_ref().unwrap_or(&conf.tls);
+                    Arc::new(client_auth_acceptor(tls, &conf.tls, auth).map_err(server_err)?)
+                },
+                (Some(tls), None, _) => Arc::new(acceptor(tls, &conf.tls).map_err(server_err)?),
+                (None, None, Some(v)) => Arc::clone(v),
+                (None, None, None) => return Err(server_err(TlsError::NotConfigured)),
+            };
+            for hostname in &srv.hostnames {
+                by_hostname.insert(hostname.to_lowercase(), Arc::clone(&acceptor));
+            }
+        }
+        Ok(Self { default, by_hostname, reject_unknown: conf.tls.reject_unknown_hostnames })
+    }
+
+    /// None if the handshake is refused.
+    pub fn select(&self, hostname: Option<&str>) -> Option<Arc<TlsAcceptor>> {
+        if let Some(v) = hostname.and_then(|h| self.by_hostname.get(h)) {
+            return Some(Arc::clone(v));
+        }
+        if self.reject_unknown {
+            return None;
+        }
+        self.default.clone()
+    }
+}
+
+
+pub async fn current() -> Option<Arc<Acceptors>> {
+    ACCEPTORS.read().await.clone()
+}
+
+pub async fn install(acceptors: Acceptors) {
+    *ACCEPTORS.write().await = Some(Arc::new(acceptors));
+}
+
+/// Loads the certificates of the conf again. On errors the old ones stay.
+pub async fn reload() -> Result<(), TlsError> {
+    let acceptors = Acceptors::from_conf(&*CONF.read().await);
+    match acceptors {
+        Ok(v) => {
+            install(v).await;
+            println!("TLS certificates reloaded");
+            Ok(())
+        },
+        Err(e) => {
+            println!("TLS reload failed, keeping the old certificates: {e}");
+            Err(e)
+        },
+    }
+}
+
+/// Reloads the certificates when their files change, checked each
+/// `interval`.
+pub async fn watch(interval: Duration) {
+    let mut files = TlsFiles::new(&*CONF.read().await);
+    loop {
+        Timer::after(interval).await;
+        if !files.changed() {
+            continue;
+        }
+        Timer::after(WATCH_SETTLE).await;
+        files.changed();
+        let _ = reload().await;
+    }
+}
+
+/// Certificate and key files of a conf, with their mtime and size as
+/// last seen.
+pub struct TlsFiles {
+    paths: Vec<String>,
+    stamps: Vec<Option<(SystemTime, u64)>>,
+}
+
+impl TlsFiles {
+    pub fn new(conf: &Conf) -> Self {
+        let tls_confs = std::iter::once(&conf.tls)
+            .chain(conf.servers.iter().filter_map(|srv| srv.tls.as_ref()));
+        let mut paths = vec![];
+        for tls in tls_confs {
+            if !tls.cert_path.is_empty() {
+                paths.push(tls.cert_path.to_string());
+                paths.push(tls.key_path.to_string());
+            } else if !tls.pkcs12_path.is_empty() {
+                paths.push(tls.pkcs12_path.to_string());
+            }
+        }
+        for auth in conf.servers.iter().filter_map(|srv| srv.client_auth.as_ref()) {
+            paths.push(auth.ca_path.to_string());
+        }
+        let stamps = stamps(&paths);
+        Self { paths, stamps }
+    }
+    /// Whether any file changed since the last call.
+    pub fn changed(&mut self) -> bool {
+        let stamps = stamps(&self.paths);
+        let changed = stamps != self.stamps;
+        self.stamps = stamps;
+        changed
+    }
+}
+
+fn stamps(paths: &[String]) -> Vec<Option<(SystemTime, u64)>> {
+    paths.iter()
+        .map(|path| fs::metadata(path).ok().and_then(|m| Some((m.modified().ok()?, m.len()))))
+        .collect()
+}
+
+
+/// Acceptor of the certificate of `conf`, with the handshake settings of
+/// `opts`, the top-level conf.
+pub fn acceptor(conf: &TlsConf, opts: &TlsConf) -> Result<TlsAcceptor, TlsError> {
+    backend_acceptor(conf, opts, None)
+}
+
+/// Acceptor which asks for client certificates of the CAs of `auth`.
+pub fn client_auth_acceptor(conf: &TlsConf, opts: &TlsConf, auth: &ClientAuthConf
+                            ) -> Result<TlsAcceptor, TlsError> {
+    backend_acceptor(conf, opts, Some(auth))
+}
+
+fn backend_acceptor(conf: &TlsConf, opts: &TlsConf, auth: Option<&ClientAuthConf>
+                    ) -> Result<TlsAcceptor, TlsError> {
+    // Clients would speak what was negotiated, e.g. h2, to an HTTP/1.1 server.
+    if let Some(v) = opts.alpn.iter().find(|v| *v != "http/1.1") {
+        return Err(TlsError::Options(format!("ALPN protocol {v} is not supported, only http/1.1")));
+    }
+    match opts.backend {
+        TlsBackend::Native => native_acceptor(conf, opts, auth),
+        #[cfg(feature = "rustls")]
+        TlsBackend::Rustls => {
+            tls_rustls::acceptor(&load_cert_key(conf)?, opts, auth).map(TlsAcceptor::Rustls)
+        },
+        #[cfg(not(feature = "rustls"))]
+        TlsBackend::Rustls => {
+            Err(TlsError::Options("miarh is built without the rustls feature".to_string()))
+        },
+    }
+}
+
+fn native_acceptor(conf: &TlsConf, opts: &TlsConf, auth: Option<&ClientAuthConf>
+                   ) -> Result<TlsAcceptor, TlsError> {
+    let min_version = match opts.min_version {
+        TlsVersion::Tls12 => Protocol::Tlsv12,
+        TlsVersion::Tls13 => {
+            return Err(TlsError::Options("min_version 1.3 needs the rustls backend".to_string()));
+        },
+    };
+    let defaults = TlsConf::default();
+    if !opts.cipher_suites.is_empty() || opts.key_log || opts.alpn != defaults.alpn
+            || opts.session_tickets != defaults.session_tickets {
+        return Err(TlsError::Options(
+            "cipher_suites, alpn, session_tickets and key_log need the rustls backend".to_string()));
+    }
+    if let Some(auth) = auth {
+        return tls_openssl::acceptor(&load_cert_key(conf)?, auth).map(TlsAcceptor::Openssl);
+    }
+    let acceptor = native_tls::TlsAcceptor::builder(load_identity(conf)?)
+        .min_protocol_version(Some(min_version))
+        .build();
+    match acceptor {
+        Ok(v) => Ok(TlsAcceptor::Native(async_native_tls::TlsAcceptor::from(v))),
+        Err(e) => Err(TlsError::Acceptor(e.to_string())),
+    }
+}
+
+/// PEM chain and key if cert_path is set, otherwise the PKCS#12 bundle.
+/// Both are checked for a key which matches the certificate.
+pub fn load_cert_key(conf: &TlsConf) -> Result<CertKey, TlsError> {
+    if !conf.cert_path.is_empty() {
+        return load_pem(&conf.cert_path, &conf.key_path);
+    }
+    if !conf.pkcs12_path.is_empty() {
+        return load_pkcs12(&conf.pkcs12_path, &conf.pkcs12_password);
+    }
+    Err(TlsError::NotConfigured)
+}
+
+pub fn load_identity(conf: &TlsConf) -> Result<Identity, TlsError> {
+    let cert_key = load_cert_key(conf)?;
+    let to_pem = || -> Result<(Vec<u8>, Vec<u8>), openssl::error::ErrorStack> {
+        let mut chain = vec![];
+        for cert in &cert_key.chain {
+            chain.extend(cert.to_pem()?);
+        }
+        // native-tls takes PKCS#8 keys only.
+        Ok((chain, cert_key.key.private_key_to_pem_pkcs8()?))
+    };
+    let (chain, key) = to_pem().map_err(|e| TlsError::Acceptor(e.to_string()))?;
+    Identity::from_pkcs8(&chain, &key).map_err(|e| TlsError::Acceptor(e.to_string()))
+}
+
+/// CA certificates which client certificates must be issued by.
+pub fn load_client_cas(auth: &ClientAuthConf) -> Result<Vec<X509>, TlsError> {
+    if auth.ca_path.is_empty() {
+        return Err(TlsError::Options("client_auth.ca_path is not set".to_string()));
+    }
+    let cas = X509::stack_from_pem(&read(&auth.ca_path)?)
+        .map_err(|e| TlsError::Pem(auth.ca_path.to_string(), e.to_string()))?;
+    if cas.is_empty() {
+        return Err(TlsError::Pem(auth.ca_path.to_string(), "no certificates".to_string()));
+    }
+    Ok(cas)
+}
+
+fn read(path: &str) -> Result<Vec<u8>, TlsError> {
+    fs::read(path).map_err(|e| TlsError::Read(path.to_string(), e))
+}
+
+fn load_pkcs12(path: &str, password: &str) -> Result<CertKey, TlsError> {
+    let der = read(path)?;
+    let bad = |e: &str| TlsError::Pkcs12(path.to_string(), e.to_string());
+    let pkcs12 = Pkcs12::from_der(&der).map_err(|_| bad("not a PKCS#12 file"))?;
+    let parsed = pkcs12.parse2(password).map_err(|_| bad("wrong password or damaged file"))?;
+    let (cert, key) = match (parsed.cert, parsed.pkey) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, _) => return Err(bad("no certificate")),
+        (_, None) => return Err(bad("no private key")),
+    };
+    check_key(path, &cert, &key)?;
+    // OpenSSL gives the intermediates in reverse order.
+    let mut chain = vec![cert];
+    chain.extend(parsed.ca.into_iter().flatten().rev());
+    Ok(CertKey { chain, key })
+}
+
+fn load_pem(cert_path: &str, key_path: &str) -> Result<CertKey, TlsError> {
+    if key_path.is_empty() {
+        return Err(TlsError::Pem(cert_path.to_string(), "tls.key_path is not set".to_string()));
+    }
+    let chain_pem = read(cert_path)?;
+    let key_pem = read(key_path)?;
+    let chain = X509::stack_from_pem(&chain_pem)
+        .map_err(|e| TlsError::Pem(cert_path.to_string(), e.to_string()))?;
+    let cert = match chain.first() {
+        Some(v) => v,
+        None => return Err(TlsError::Pem(cert_path.to_string(), "no certificates".to_string())),
+    };
+    // RSA, EC or PKCS#8 keys.
+    let key = PKey::private_key_from_pem(&key_pem)
+        .map_err(|_| TlsError::Pem(key_path.to_string(), "no unencrypted private key".to_string()))?;
+    check_key(cert_path, cert, &key)?;
+    Ok(CertKey { chain, key })
+}
+
+// OpenSSL silently drops a key which doesn't match the certificate, and
+// every handshake fails later on.
+fn check_key(path: &str, cert: &X509, key: &PKey<Private>) -> Result<(), TlsError> {
+    match cert.public_key() {
+        Ok(public) if public.public_eq(key) => Ok(()),
+        _ => Err(TlsError::KeyMismatch(path.to_string())),
+    }
+}
+
+
+/// Server name the client asks for. The ClientHello is peeked, not read,
+/// so the handshake still gets it.
+pub async fn peek_sni(stream: &TcpStream) -> Option<String> {
+    let mut buf = vec![0; CLIENT_HELLO_MAX_LEN];
+    let started = Instant::now();
+    loop {
+        let left = CLIENT_HELLO_TIMEOUT.saturating_sub(started.elapsed());
+        let n = match timeout(left, stream.peek(&mut buf)).await {
+            Some(Ok(n)) if n > 0 => n,
+            _ => return None,
+        };
+        match parse_sni(&buf[..n]) {
+            Sni::Name(v) => return Some(v),
+            Sni::Missing => return None,
+            Sni::Incomplete if n == buf.len() || left.is_zero() => return None,
+            // Peek returns at once while anything is buffered, so wait
+            // for the rest of a ClientHello split over several segments.
+            Sni::Incomplete => Timer::after(Duration::from_millis(5)).await,
+        };
+    }
+}
+
+/// Sends the unrecognized_name alert before the connection is dropped.
+pub async fn refuse(mut stream: TcpStream) {
+    let _ = stream.write_all(&UNRECOGNIZED_NAME_ALERT).await;
+}
+
+/// Server name of the ClientHello at the start of `data`. The handshake
+/// message may span several records.
+pub fn parse_sni(data: &[u8]) -> Sni {
+    let mut handshake = vec![];
+    let mut rest = data;
+    loop {
+        if handshake.len() >= 4 {
+            // Type 1 is ClientHello.
+            if handshake[0] != 1 {
+                return Sni::Missing;
+            }
+            let len = 4 + u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if handshake.len() >= len {
+                return parse_client_hello(&handshake[4..len]);
+            }
+        }
+        if rest.len() < 5 {
+            return Sni::Incomplete;
+        }
+        // Content type 22 is handshake.
+        if rest[0] != 22 {
+            return Sni::Missing;
+        }
+        let len = 5 + u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        if rest.len() < len {
+            return Sni::Incomplete;
+        }
+        handshake.extend_from_slice(&rest[5..len]);
+        rest = &rest[len..];
+    }
+}
+
+fn parse_client_hello(body: &[u8]) -> Sni {
+    let mut r = Reader { data: body };
+    // Version and random, then session id, cipher suites and compression
+    // methods.
+    let extensions = r.take(34)
+        .and_then(|_| r.take_u8_len())
+        .and_then(|_| r.take_u16_len())
+        .and_then(|_| r.take_u8_len())
+        .and_then(|_| r.take_u16_len());
+    let mut extensions = match extensions {
+        Some(v) => Reader { data: v },
+        None => return Sni::Missing,
+    };
+    while let (Some(kind), Some(data)) = (extensions.u16(), extensions.take_u16_len()) {
+        // Type 0 is server_name.
+        if kind != 0 {
+            continue;
+        }
+        let mut names = Reader { data };
+        let mut names = match names.take_u16_len() {
+            Some(v) => Reader { data: v },
+            None => return Sni::Missing,
+        };
+        // Name type 0 is host_name, the only one defined.
+        while let (Some(kind), Some(name)) = (names.u8(), names.take_u16_len()) {
+            if kind != 0 {
+                continue;
+            }
+            return match std::str::from_utf8(name) {
+                Ok(v) if v.is_ascii() => {
+                    Sni::Name(v.trim_end_matches('.').to_ascii_lowercase())
+                },
+                _ => Sni::Missing,
+            };
+        }
+        return Sni::Missing;
+    }
+    Sni::Missing
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.data.len() < n {
+            return None;
+        }
+        let (v, rest) = self.data.split_at(n);
+        self.data = rest;
+        Some(v)
+    }
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|v| v[0])
+    }
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|v| u16::from_be_bytes([v[0], v[1]]))
+    }
+    fn take_u8_len(&mut self) -> Option<&'a [u8]> {
+        let n = self.u8()?;
+        self.take(n as usize)
+    }
+    fn take_u16_len(&mut self) -> Option<&'a [u8]> {
+        let n = self.u16()?;
+        self.take(n as usize)
+    }
+}
@@ -0,0 +1,137 @@
+// OpenSSL handshakes for servers which ask for client certificates, used
+// by the native backend. OpenSSL calls are made on the non-blocking
+// socket and wait for the reactor when they would block.
+
+use std::io::{self, Read, Write};
+use std::net;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use async_io::Async;
+use async_net::TcpStream;
+use futures_lite::{future, ready, AsyncRead, AsyncWrite};
+use openssl::error::ErrorStack;
+use openssl::ssl::{self, ErrorCode, Ssl, SslAcceptor, SslMethod, SslVerifyMode};
+use openssl::x509::X509VerifyResult;
+use crate::conf::ClientAuthConf;
+use crate::tls::{self, CertKey, ClientAuthMode, ClientCert, TlsError};
+
+
+/// Session resumption with client certificates needs a context id.
+const SESSION_ID_CONTEXT: &[u8] = b"miarh";
+
+
+pub fn acceptor(cert_key: &CertKey, auth: &ClientAuthConf) -> Result<SslAcceptor, TlsError> {
+    let cas = tls::load_client_cas(auth)?;
+    let build = || -> Result<SslAcceptor, ErrorStack> {
+        // TLS 1.2 and up, like native-tls.
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+        builder.set_private_key(&cert_key.key)?;
+        builder.set_certificate(&cert_key.chain[0])?;
+        for cert in &cert_key.chain[1..] {
+            builder.add_extra_chain_cert(cert.clone())?;
+        }
+        for ca in &cas {
+            builder.cert_store_mut().add_cert(ca.clone())?;
+            // Clients pick their certificate by the CA names they're sent.
+            builder.add_client_ca(ca)?;
+        }
+        builder.set_verify(match auth.mode {
+            ClientAuthMode::Required => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            ClientAuthMode::Optional => SslVerifyMode::PEER,
+        });
+        builder.set_session_id_context(SESSION_ID_CONTEXT)?;
+        Ok(builder.build())
+    };
+    build().map_err(|e| TlsError::Acceptor(e.to_string()))
+}
+
+pub async fn accept(acceptor: &SslAcceptor, stream: TcpStream) -> io::Result<SslStream> {
+    let socket = Socket(Arc::from(stream.clone()));
+    let ssl = Ssl::new(acceptor.context()).map_err(io::Error::other)?;
+    let inner = ssl::SslStream::new(ssl, socket).map_err(io::Error::other)?;
+    let mut stream = SslStream { inner, tcp: stream };
+    future::poll_fn(|cx| stream.poll_ssl(cx, |s| s.accept())).await?;
+    Ok(stream)
+}
+
+
+/// Reads and writes of the raw socket, WouldBlock when it's not ready.
+struct Socket(Arc<Async<net::TcpStream>>);
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.get_ref().read(buf)
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.get_ref().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+pub struct SslStream {
+    inner: ssl::SslStream<Socket>,
+    tcp: TcpStream,
+}
+
+impl SslStream {
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.tcp
+    }
+    pub fn client_cert(&self) -> Option<ClientCert> {
+        let ssl = self.inner.ssl();
+        if ssl.verify_result() != X509VerifyResult::OK {
+            return None;
+        }
+        ssl.peer_certificate().map(|cert| ClientCert::from_x509(&cert))
+    }
+    /// Runs `f` until the socket is ready for what OpenSSL wants.
+    fn poll_ssl<T>(&mut self, cx: &mut Context<'_>,
+                   mut f: impl FnMut(&mut ssl::SslStream<Socket>) -> Result<T, ssl::Error>
+                   ) -> Poll<io::Result<T>> {
+        loop {
+            match f(&mut self.inner) {
+                Ok(v) => return Poll::Ready(Ok(v)),
+                Err(e) if e.code() == ErrorCode::WANT_READ => {
+                    ready!(self.inner.get_ref().0.poll_readable(cx))?;
+                },
+                Err(e) if e.code() == ErrorCode::WANT_WRITE => {
+                    ready!(self.inner.get_ref().0.poll_writable(cx))?;
+                },
+                Err(e) => {
+                    return Poll::Ready(Err(e.into_io_error().unwrap_or_else(io::Error::other)));
+                },
+            }
+        }
+    }
+}
+
+impl AsyncRead for SslStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_ssl(cx, |s| match s.ssl_read(buf) {
+            // Closed by close_notify, or without it.
+            Err(e) if e.code() == ErrorCode::ZERO_RETURN => Ok(0),
+            Err(e) if e.code() == ErrorCode::SYSCALL && e.io_error().is_none() => Ok(0),
+            r => r,
+        })
+    }
+}
+
+impl AsyncWrite for SslStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_ssl(cx, |s| s.ssl_write(buf))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Records go to the socket as they are written.
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_ssl(cx, |s| s.shutdown().map(|_| ()))
+    }
+}
@@ -0,0 +1,78 @@
+// rustls acceptor, built with the rustls feature: control over protocol
+// versions, cipher suites, ALPN, session tickets and client certificates.
+
+use std::sync::Arc;
+use futures_rustls::rustls::crypto::ring;
+use futures_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use futures_rustls::rustls::server::WebPkiClientVerifier;
+use futures_rustls::rustls::version::{TLS12, TLS13};
+use futures_rustls::rustls::{
+    KeyLogFile, RootCertStore, ServerConfig, SupportedCipherSuite, SupportedProtocolVersion,
+};
+use futures_rustls::TlsAcceptor;
+use crate::conf::{ClientAuthConf, TlsConf};
+use crate::tls::{self, CertKey, ClientAuthMode, TlsError, TlsVersion};
+
+
+pub fn acceptor(cert_key: &CertKey, opts: &TlsConf, auth: Option<&ClientAuthConf>
+                ) -> Result<TlsAcceptor, TlsError> {
+    let mut provider = ring::default_provider();
+    if !opts.cipher_suites.is_empty() {
+        provider.cipher_suites = cipher_suites(&opts.cipher_suites)?;
+    }
+    let provider = Arc::new(provider);
+    let versions: &[&SupportedProtocolVersion] = match opts.min_version {
+        TlsVersion::Tls12 => &[&TLS13, &TLS12],
+        TlsVersion::Tls13 => &[&TLS13],
+    };
+    let acceptor_err = |e: String| TlsError::Acceptor(e);
+    let chain = cert_key.chain.iter()
+        .map(|cert| cert.to_der().map(CertificateDer::from))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| acceptor_err(e.to_string()))?;
+    let key = cert_key.key.private_key_to_pkcs8().map_err(|e| acceptor_err(e.to_string()))?;
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key));
+    // Fails if no suite is left for the versions.
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_protocol_versions(versions)
+        .map_err(|e| TlsError::Options(e.to_string()))?;
+    let builder = match auth {
+        None => builder.with_no_client_auth(),
+        Some(auth) => {
+            let mut roots = RootCertStore::empty();
+            for ca in tls::load_client_cas(auth)? {
+                let der = ca.to_der().map_err(|e| acceptor_err(e.to_string()))?;
+                roots.add(CertificateDer::from(der)).map_err(|e| acceptor_err(e.to_string()))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match auth.mode {
+                ClientAuthMode::Required => verifier,
+                ClientAuthMode::Optional => verifier.allow_unauthenticated(),
+            };
+            builder.with_client_cert_verifier(verifier.build().map_err(|e| acceptor_err(e.to_string()))?)
+        },
+    };
+    let mut config = builder
+        .with_single_cert(chain, key)
+        .map_err(|e| acceptor_err(e.to_string()))?;
+    config.alpn_protocols = opts.alpn.iter().map(|v| v.as_bytes().to_vec()).collect();
+    if opts.session_tickets {
+        config.ticketer = ring::Ticketer::new().map_err(|e| acceptor_err(e.to_string()))?;
+    }
+    if opts.key_log {
+        config.key_log = Arc::new(KeyLogFile::new());
+    }
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Suites by their IANA names, e.g. "TLS13_AES_128_GCM_SHA256".
+fn cipher_suites(names: &[String]) -> Result<Vec<SupportedCipherSuite>, TlsError> {
+    names.iter()
+        .map(|name| {
+            ring::ALL_CIPHER_SUITES.iter()
+                .find(|suite| format!("{:?}", suite.suite()) == *name)
+                .copied()
+                .ok_or_else(|| TlsError::Options(format!("unknown cipher suite: {name}")))
+        })
+        .collect()
+}
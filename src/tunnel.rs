@@ -0,0 +1,95 @@
+// Tunnels of upgraded connections, e.g. WebSockets: after the backend
+// switched protocols, bytes are copied both ways as they arrive.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use async_lock::Mutex;
+use futures_lite::{future, io as lite_io, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use once_cell::sync::Lazy;
+use crate::timeout::timeout;
+
+
+/// Open tunnels by server name.
+static OPEN: Lazy<Mutex<HashMap<String, Arc<AtomicUsize>>>> = Lazy::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+
+/// An open tunnel of a server, counted until dropped.
+pub struct TunnelSlot {
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for TunnelSlot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// None if the server already has `max` open tunnels.
+pub async fn acquire(server: &str, max: usize) -> Option<TunnelSlot> {
+    let open = Arc::clone(OPEN.lock().await.entry(server.to_string()).or_default());
+    if open.fetch_add(1, Ordering::SeqCst) >= max {
+        open.fetch_sub(1, Ordering::SeqCst);
+        return None;
+    }
+    Some(TunnelSlot { open })
+}
+
+pub async fn open_count(server: &str) -> usize {
+    match OPEN.lock().await.get(server) {
+        Some(v) => v.load(Ordering::SeqCst),
+        None => 0,
+    }
+}
+
+/// Copies bytes between `client` and `backend` until both directions are
+/// done or there's no traffic in either direction for `idle`. EOF from one
+/// side shuts down writing to the other, which may still answer.
+pub async fn pipe<A, B>(client: A, backend: B, idle: Duration) -> io::Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_r, mut client_w) = lite_io::split(client);
+    let (mut backend_r, mut backend_w) = lite_io::split(backend);
+    let started = Instant::now();
+    // Millis since `started` of the last traffic, in either direction.
+    let last_active = AtomicU64::new(0);
+    let up = copy(&mut client_r, &mut backend_w, idle, started, &last_active);
+    let down = copy(&mut backend_r, &mut client_w, idle, started, &last_active);
+    future::try_zip(up, down).await?;
+    Ok(())
+}
+
+async fn copy<R, W>(r: &mut R, w: &mut W, idle: Duration, started: Instant,
+                    last_active: &AtomicU64) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; 1024 * 32];
+    loop {
+        let last = Duration::from_millis(last_active.load(Ordering::SeqCst));
+        let quiet = started.elapsed().saturating_sub(last);
+        if quiet >= idle {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "tunnel idle timeout"));
+        }
+        // The other direction may have been active meanwhile, check again.
+        let n = match timeout(idle - quiet, r.read(&mut buf)).await {
+            None => continue,
+            Some(r) => r?,
+        };
+        if n == 0 {
+            // The other side may have closed the connection already.
+            let _ = w.close().await;
+            return Ok(());
+        }
+        w.write_all(&buf[..n]).await?;
+        w.flush().await?;
+        last_active.store(started.elapsed().as_millis() as u64, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,185 @@
+use std::path::Path;
+use unicode_normalization::UnicodeNormalization;
+use crate::mime;
+use crate::multipart::MultipartError;
+
+
+pub const MAX_FILENAME_LEN: usize = 255;
+pub const SNIFF_LEN: usize = 16;
+
+
+/// Per-server upload restrictions. Empty lists allow everything.
+#[derive(Debug, Default, Clone)]
+pub struct UploadRules {
+    pub allowed_extensions: Vec<String>,
+    pub allowed_mime_types: Vec<String>,
+}
+
+impl UploadRules {
+    /// Checks the filename and the declared Content-Type of a part,
+    /// before any of its content is read. Parts without a Content-Type
+    /// are left to check_content().
+    pub fn check_declared(&self, filename: &str, content_type: &str
+                          ) -> Result<(), MultipartError> {
+        if !self.allowed_extensions.is_empty() {
+            let ext = extension(filename).unwrap_or_default();
+            let is_allowed = self.allowed_extensions.iter()
+                .any(|v| v.trim_start_matches('.').eq_ignore_ascii_case(&ext));
+            if !is_allowed {
+                return Err(MultipartError::UnsupportedMediaType(
+                    format!("extension '{ext}' is not allowed")));
+            }
+        }
+        if content_type.is_empty() { return Ok(()) }
+        self.check_mime_type(&essence(content_type))
+    }
+
+    /// Checks the first bytes of an uploaded file against its extension
+    /// and its declared Content-Type.
+    pub fn check_content(&self, filename: &str, content_type: &str, head: &[u8]
+                         ) -> Result<(), MultipartError> {
+        if content_type.is_empty() {
+            self.check_mime_type(&detected_type(filename, head))?;
+        }
+        let sniffed = match sniff_mimetype(head) {
+            None => return Ok(()),
+            Some(v) => v,
+        };
+        self.check_mime_type(sniffed)?;
+        if let Some(expected) = mime::get_mimetype(&filename.to_string()) {
+            if !is_compatible(sniffed, &expected) {
+                return Err(MultipartError::ContentMismatch(
+                    format!("'{filename}' looks like {sniffed}")));
+            }
+        }
+        // Only specific declared types are compared with the content.
+        let declared = essence(content_type);
+        if !declared.is_empty() && declared != "application/octet-stream"
+                && !is_compatible(sniffed, &declared) {
+            return Err(MultipartError::ContentMismatch(
+                format!("declared {declared}, looks like {sniffed}")));
+        }
+        Ok(())
+    }
+
+    fn check_mime_type(&self, mime_type: &str) -> Result<(), MultipartError> {
+        if !self.allowed_mime_types.is_empty()
+                && !self.allowed_mime_types.iter().any(|v| v.eq_ignore_ascii_case(mime_type)) {
+            return Err(MultipartError::UnsupportedMediaType(
+                format!("type '{mime_type}' is not allowed")));
+        }
+        Ok(())
+    }
+}
+
+/// Makes a client-supplied filename safe to show and store: basename only,
+/// NFC-normalized, without control and bidi formatting characters, and at
+/// most MAX_FILENAME_LEN bytes long.
+pub fn sanitize_filename(name: &str) -> String {
+    let basename = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let name: String = basename.nfc()
+        .filter(|c| !c.is_control() && !is_format_char(*c))
+        .collect();
+    let name = name.trim().trim_start_matches('.').trim();
+    if name.is_empty() {
+        return "file".to_string();
+    }
+    if name.len() <= MAX_FILENAME_LEN {
+        return name.to_string();
+    }
+    let ext = match name.rfind('.') {
+        Some(idx) if name.len() - idx <= 16 => &name[idx..],
+        _ => "",
+    };
+    let mut stem_len = MAX_FILENAME_LEN - ext.len();
+    while !name.is_char_boundary(stem_len) { stem_len -= 1; }
+    format!("{}{ext}", &name[..stem_len])
+}
+
+fn is_format_char(c: char) -> bool {
+    matches!(c, '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}'
+             | '\u{2066}'..='\u{2069}' | '\u{FEFF}')
+}
+
+fn extension(filename: &str) -> Option<String> {
+    Path::new(filename).extension()
+        .map(|v| v.to_string_lossy().to_lowercase())
+}
+
+fn essence(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or("").trim().to_lowercase()
+}
+
+/// Type of a file uploaded without a Content-Type: by its magic bytes,
+/// otherwise by its extension.
+pub fn detected_type(filename: &str, head: &[u8]) -> String {
+    match sniff_mimetype(head) {
+        Some(v) => v.to_string(),
+        None => mime::get_mimetype(&filename.to_string())
+            .unwrap_or_else(|| "application/octet-stream".to_string()),
+    }
+}
+
+/// Detects a file type by its magic bytes. Only types which can be
+/// recognized reliably are listed.
+pub fn sniff_mimetype(head: &[u8]) -> Option<&'static str> {
+    let starts = |magic: &[u8]| head.starts_with(magic);
+    let m = if starts(b"\x89PNG\r\n\x1a\n") {
+        "image/png"
+    } else if starts(b"\xff\xd8\xff") {
+        "image/jpeg"
+    } else if starts(b"GIF87a") || starts(b"GIF89a") {
+        "image/gif"
+    } else if starts(b"RIFF") && head.len() >= 12 && &head[8..12] == b"WEBP" {
+        "image/webp"
+    } else if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        match &head[8..12] {
+            b"avif" | b"avis" => "image/avif",
+            b"3gp4" | b"3gp5" | b"3gp6" => "video/3gpp",
+            _ => "video/mp4",
+        }
+    } else if starts(b"\x00\x00\x01\x00") {
+        "image/vnd.microsoft.icon"
+    } else if starts(b"%PDF-") {
+        "application/pdf"
+    } else if starts(b"PK\x03\x04") || starts(b"PK\x05\x06") {
+        "application/zip"
+    } else if starts(b"\x1f\x8b") {
+        "application/gzip"
+    } else if starts(b"BZh") {
+        "application/x-bzip2"
+    } else if starts(b"7z\xbc\xaf\x27\x1c") {
+        "application/x-7z-compressed"
+    } else if starts(b"Rar!\x1a\x07") {
+        "application/x-rar-compressed"
+    } else if starts(b"OggS") {
+        "audio/ogg"
+    } else if starts(b"ID3") {
+        "audio/mpeg"
+    } else if starts(b"\x1a\x45\xdf\xa3") {
+        "video/webm"
+    } else if starts(b"wOFF") {
+        "font/woff"
+    } else if starts(b"wOF2") {
+        "font/woff2"
+    } else if starts(b"MZ") {
+        "application/x-msdownload"
+    } else if starts(b"\x7fELF") {
+        "application/x-executable"
+    } else {
+        return None;
+    };
+    Some(m)
+}
+
+fn is_compatible(sniffed: &str, expected: &str) -> bool {
+    if sniffed == expected { return true }
+    match sniffed {
+        // docx, xlsx, odt etc. are zip containers.
+        "application/zip" => expected.starts_with("application/vnd."),
+        "audio/ogg" => matches!(expected, "video/ogg" | "audio/opus"),
+        "video/mp4" => matches!(expected, "audio/aac" | "video/3gpp"),
+        "application/gzip" => expected == "application/x-gzip",
+        _ => false,
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use async_lock::Mutex;
+use async_net::TcpStream;
+use async_net::unix::UnixStream;
+use futures_lite::{AsyncRead, AsyncWrite};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use crate::app_pool::{self, AppPool, PoolConf};
+use crate::conf::ServerConf;
+use crate::health::{self, CircuitBreaker, HealthConf};
+use crate::spawn::spawn;
+
+
+/// Virtual nodes per upstream on the consistent hashing ring.
+const RING_VNODES: usize = 160;
+
+static GROUPS: Lazy<Mutex<HashMap<String, Arc<UpstreamGroup>>>> = Lazy::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpstreamAddr {
+    Unix(String),
+    Tcp(String),
+}
+
+impl UpstreamAddr {
+    pub fn parse(s: &str) -> Self {
+        let s = s.trim();
+        if let Some(v) = s.strip_prefix("unix:") {
+            UpstreamAddr::Unix(v.to_string())
+        } else if let Some(v) = s.strip_prefix("tcp:").or_else(|| s.strip_prefix("http://")) {
+            let v = v.trim_end_matches('/');
+            UpstreamAddr::Tcp(v.to_string())
+        } else if s.starts_with('/') || s.starts_with('.') {
+            UpstreamAddr::Unix(s.to_string())
+        } else {
+            UpstreamAddr::Tcp(s.to_string())
+        }
+    }
+
+    pub async fn connect(&self) -> io::Result<AppStream> {
+        match self {
+            UpstreamAddr::Unix(path) => Ok(AppStream::Unix(UnixStream::connect(path).await?)),
+            UpstreamAddr::Tcp(addr) => {
+                let stream = TcpStream::connect(addr.as_str()).await?;
+                stream.set_nodelay(true)?;
+                Ok(AppStream::Tcp(stream))
+            },
+        }
+    }
+}
+
+impl fmt::Display for UpstreamAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpstreamAddr::Unix(v) => write!(f, "unix:{v}"),
+            UpstreamAddr::Tcp(v) => write!(f, "tcp:{v}"),
+        }
+    }
+}
+
+
+/// Connection to an app server over a unix socket or TCP.
+pub enum AppStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl AsyncRead for AppStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]
+                 ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AppStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            AppStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for AppStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]
+                  ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AppStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            AppStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AppStream::Unix(s) => Pin::new(s).poll_flush(cx),
+            AppStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AppStream::Unix(s) => Pin::new(s).poll_close(cx),
+            AppStream::Tcp(s) => Pin::new(s).poll_close(cx),
+        }
+    }
+}
+
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    LeastConn,
+    /// Consistent hashing on session_id, requests without a session
+    /// are balanced round-robin.
+    Hash,
+}
+
+pub struct Upstream {
+    pub idx: usize,
+    pub pool: Arc<AppPool>,
+    active: AtomicUsize,
+    pub breaker: CircuitBreaker,
+}
+
+impl Upstream {
+    pub fn addr(&self) -> &UpstreamAddr {
+        &self.pool.conf.addr
+    }
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+    pub fn is_available(&self) -> bool {
+        self.breaker.is_available()
+    }
+}
+
+/// Upstream chosen for a request, counted as active until dropped.
+pub struct Picked {
+    pub upstream: Arc<Upstream>,
+    /// The trial request of a half-open circuit.
+    pub is_trial: bool,
+}
+
+impl Drop for Picked {
+    fn drop(&mut self) {
+        self.upstream.active.fetch_sub(1, Ordering::SeqCst);
+        if self.is_trial {
+            self.upstream.breaker.release_trial();
+        }
+    }
+}
+
+/// App servers of one site. Each upstream has a circuit breaker which
+/// opens after `max_fails` consecutive failures of requests or health checks.
+pub struct UpstreamGroup {
+    pub balance: Balance,
+    pub health: HealthConf,
+    pub upstreams: Vec<Arc<Upstream>>,
+    /// (hash, upstream idx), sorted by hash.
+    ring: Vec<(u64, usize)>,
+    next: AtomicUsize,
+}
+
+pub async fn get_group(srv: &ServerConf) -> Arc<UpstreamGroup> {
+    let mut groups = GROUPS.lock().await;
+    if let Some(group) = groups.get(&srv.name) {
+        return Arc::clone(group);
+    }
+    let mut pools = vec![];
+    for addr in srv.upstreams() {
+        pools.push(app_pool::get_pool(&srv.name, PoolConf::new(UpstreamAddr::parse(&addr), srv)).await);
+    }
+    let group = Arc::new(UpstreamGroup::new(srv.balance, HealthConf::from_server(srv), pools));
+    if !group.health.check_interval.is_zero() {
+        spawn(health::run_checks(Arc::clone(&group))).detach();
+    }
+    groups.insert(srv.name.to_string(), Arc::clone(&group));
+    group
+}
+
+impl UpstreamGroup {
+    pub fn new(balance: Balance, health: HealthConf, pools: Vec<Arc<AppPool>>) -> Self {
+        let upstreams: Vec<Arc<Upstream>> = pools.into_iter().enumerate()
+            .map(|(idx, pool)| Arc::new(Upstream {
+                idx,
+                pool,
+                active: AtomicUsize::new(0),
+                breaker: CircuitBreaker::new(),
+            }))
+            .collect();
+        let mut ring = vec![];
+        if balance == Balance::Hash {
+            for upstream in &upstreams {
+                for i in 0..RING_VNODES {
+                    ring.push((hash(format!("{}#{i}", upstream.addr()).as_bytes()), upstream.idx));
+                }
+            }
+            ring.sort();
+        }
+        Self { balance, health, upstreams, ring, next: AtomicUsize::new(0) }
+    }
+
+    /// Chooses an available upstream which is not in `tried`.
+    pub fn pick(&self, session_id: &str, tried: &[usize]) -> Option<Picked> {
+        let mut skip = tried.to_vec();
+        loop {
+            let is_usable = |u: &Upstream| !skip.contains(&u.idx) && u.is_available();
+            let upstream = match self.balance {
+                Balance::Hash if !session_id.is_empty() => self.pick_hashed(session_id, is_usable),
+                Balance::LeastConn => self.pick_least_conn(is_usable),
+                _ => self.pick_round_robin(is_usable),
+            }?;
+            // Another request may have taken the trial slot meanwhile.
+            let (is_admitted, is_trial) = upstream.breaker.acquire();
+            if !is_admitted {
+                skip.push(upstream.idx);
+                continue;
+            }
+            upstream.active.fetch_add(1, Ordering::SeqCst);
+            return Some(Picked { upstream, is_trial });
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.upstreams.iter().any(|u| u.is_available())
+    }
+
+    /// Time until the first open circuit lets a request through.
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.upstreams.iter().filter_map(|u| u.breaker.retry_after()).min()
+    }
+
+    fn pick_round_robin(&self, is_usable: impl Fn(&Upstream) -> bool) -> Option<Arc<Upstream>> {
+        let n = self.upstreams.len();
+        let start = self.next.fetch_add(1, Ordering::SeqCst);
+        (0..n).map(|i| &self.upstreams[(start + i) % n])
+            .find(|u| is_usable(u))
+            .cloned()
+    }
+
+    fn pick_least_conn(&self, is_usable: impl Fn(&Upstream) -> bool) -> Option<Arc<Upstream>> {
+        let n = self.upstreams.len();
+        // Rotating the start spreads requests among equally loaded upstreams.
+        let start = self.next.fetch_add(1, Ordering::SeqCst);
+        (0..n).map(|i| &self.upstreams[(start + i) % n])
+            .filter(|u| is_usable(u))
+            .min_by_key(|u| u.active())
+            .cloned()
+    }
+
+    fn pick_hashed(&self, key: &str, is_usable: impl Fn(&Upstream) -> bool
+                   ) -> Option<Arc<Upstream>> {
+        if self.ring.is_empty() { return None }
+        let h = hash(key.as_bytes());
+        let start = self.ring.partition_point(|(v, _)| *v < h);
+        // Walking the ring moves a session to the next upstream only
+        // while its own one is unavailable.
+        (0..self.ring.len()).map(|i| self.ring[(start + i) % self.ring.len()].1)
+            .map(|idx| &self.upstreams[idx])
+            .find(|u| is_usable(u))
+            .cloned()
+    }
+
+    pub fn succeeded(&self, upstream: &Upstream) {
+        if upstream.breaker.succeeded() {
+            println!("Upstream {} is back, circuit closed", upstream.addr());
+        }
+    }
+
+    pub fn check_passed(&self, upstream: &Upstream) {
+        if upstream.breaker.check_passed() {
+            println!("Upstream {} passed a health check, circuit half-open", upstream.addr());
+        }
+    }
+
+    pub fn failed(&self, upstream: &Upstream) {
+        if upstream.breaker.failed(&self.health) {
+            println!("Upstream {} is down, circuit open for {:?}",
+                     upstream.addr(), self.health.fail_timeout);
+        }
+    }
+}
+
+/// FNV-1a with a final mix, stable between builds and runs.
+fn hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in data {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h
+}
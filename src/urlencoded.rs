@@ -0,0 +1,50 @@
+use serde_json::Value;
+use crate::form::FormFields;
+use crate::headers::{RequestParser};
+
+
+
+pub async fn parse_urlencoded(hp: &mut RequestParser) {
+    let mut fields = FormFields::new();
+    let body = String::from_utf8_lossy(&hp.body).to_string();
+    for kv in body.split("&") {
+        if kv.is_empty() { continue }
+        let mut kv_it = kv.splitn(2, "=");
+        let k = decode(kv_it.next().unwrap_or(""));
+        let v = decode(kv_it.next().unwrap_or(""));
+        fields.insert(k, Value::String(v));
+    }
+    hp.body_string = fields.to_json_string();
+}
+
+pub fn decode(s: &str) -> String {
+    String::from_utf8_lossy(&percent_decode(&s.replace('+', " "))).to_string()
+}
+
+pub fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match (hex_val(bytes[i+1]), hex_val(bytes[i+2])) {
+                    (Some(h), Some(l)) => { decoded.push(h << 4 | l); i += 2; },
+                    _ => decoded.push(b'%'),
+                }
+            },
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    decoded
+}
+
+fn hex_val(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
@@ -0,0 +1,30 @@
+// uwsgi protocol client, https://uwsgi-docs.readthedocs.io/en/latest/Protocol.html
+//
+// A 4 byte header (modifier1, little endian size of the vars, modifier2),
+// the CGI vars as length prefixed strings, then the raw body. The app
+// answers with a plain HTTP response and closes the connection.
+
+/// modifier1 of WSGI requests, the default of uWSGI.
+pub const MODIFIER_WSGI: u8 = 0;
+pub const HEADER_LEN: usize = 4;
+
+
+/// Packet header and vars, the body follows them as is. The vars of
+/// a packet are limited to 64K.
+pub fn request_head(env: &[(String, String)]) -> Result<Vec<u8>, String> {
+    let mut vars = vec![];
+    for (k, v) in env {
+        for s in [k, v] {
+            let len = u16::try_from(s.len()).map_err(|_| format!("uwsgi var is too large: {k}"))?;
+            vars.extend_from_slice(&len.to_le_bytes());
+            vars.extend_from_slice(s.as_bytes());
+        }
+    }
+    let size = u16::try_from(vars.len()).map_err(|_| "uwsgi vars are too large".to_string())?;
+    let mut out = Vec::with_capacity(HEADER_LEN + vars.len());
+    out.push(MODIFIER_WSGI);
+    out.extend_from_slice(&size.to_le_bytes());
+    out.push(0);
+    out.extend(vars);
+    Ok(out)
+}
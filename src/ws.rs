@@ -0,0 +1,327 @@
+// WebSocket protocol, server side, RFC 6455.
+
+use std::fmt;
+use std::io;
+use futures_lite::{AsyncRead, AsyncReadExt};
+
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Control frames carry at most 125 bytes.
+pub const MAX_CONTROL_LEN: usize = 125;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+/// Close frame without a code, never sent.
+pub const CLOSE_NO_STATUS: u16 = 1005;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_POLICY: u16 = 1008;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Opcode {
+    Continuation = 0,
+    Text = 1,
+    Binary = 2,
+    Close = 8,
+    Ping = 9,
+    Pong = 10,
+}
+
+impl Opcode {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Opcode::Continuation),
+            1 => Some(Opcode::Text),
+            2 => Some(Opcode::Binary),
+            8 => Some(Opcode::Close),
+            9 => Some(Opcode::Ping),
+            10 => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+    pub fn is_control(&self) -> bool {
+        *self as u8 >= 8
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug)]
+pub enum WsError {
+    Io(io::Error),
+    Protocol(String),
+    InvalidUtf8,
+    TooLarge(usize),
+}
+
+impl WsError {
+    /// Code of the close frame sent because of the error.
+    pub fn close_code(&self) -> u16 {
+        match self {
+            WsError::Io(_) => CLOSE_INTERNAL_ERROR,
+            WsError::Protocol(_) => CLOSE_PROTOCOL_ERROR,
+            WsError::InvalidUtf8 => CLOSE_INVALID_DATA,
+            WsError::TooLarge(_) => CLOSE_TOO_BIG,
+        }
+    }
+}
+
+impl fmt::Display for WsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WsError::Io(e) => write!(f, "WebSocket io err: {e}"),
+            WsError::Protocol(e) => write!(f, "WebSocket protocol err: {e}"),
+            WsError::InvalidUtf8 => write!(f, "WebSocket text message is not utf-8"),
+            WsError::TooLarge(v) => write!(f, "WebSocket message is too large: {v}"),
+        }
+    }
+}
+
+impl From<io::Error> for WsError {
+    fn from(e: io::Error) -> Self {
+        WsError::Io(e)
+    }
+}
+
+
+/// Sec-WebSocket-Accept of a Sec-WebSocket-Key.
+pub fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+/// 101 response to a valid handshake, otherwise the status code and
+/// text of the refusal.
+pub fn handshake_resp(key: Option<&str>, version: Option<&str>) -> Result<Vec<u8>, (u16, String)> {
+    if version != Some("13") {
+        return Err((426, "Unsupported WebSocket version.".to_string()));
+    }
+    let key = match key {
+        // 16 random bytes, base64 encoded.
+        Some(v) if v.len() == 24 && v.ends_with("==") => v,
+        _ => return Err((400, "Bad Sec-WebSocket-Key.".to_string())),
+    };
+    let resp = format!("HTTP/1.1 101 Switching Protocols\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Accept: {}\r\n\r\n", accept_key(key));
+    Ok(resp.into_bytes())
+}
+
+/// Unmasked, unfragmented frame, as servers send them.
+pub fn encode_frame(opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(10 + payload.len());
+    frame.push(0x80 | opcode as u8);
+    match payload.len() {
+        n if n < 126 => frame.push(n as u8),
+        n if n <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(n as u16).to_be_bytes());
+        },
+        n => {
+            frame.push(127);
+            frame.extend_from_slice(&(n as u64).to_be_bytes());
+        },
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+pub fn encode_message(msg: &Message) -> Vec<u8> {
+    match msg {
+        Message::Text(v) => encode_frame(Opcode::Text, v.as_bytes()),
+        Message::Binary(v) => encode_frame(Opcode::Binary, v),
+    }
+}
+
+pub fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+    let mut end = reason.len().min(MAX_CONTROL_LEN - 2);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+    payload
+}
+
+/// Code of a close frame, CLOSE_NO_STATUS if it has none.
+pub fn parse_close(payload: &[u8]) -> Result<u16, WsError> {
+    let code = match payload {
+        [] => return Ok(CLOSE_NO_STATUS),
+        [_] => return Err(WsError::Protocol("bad close frame".to_string())),
+        [hi, lo, ..] => u16::from_be_bytes([*hi, *lo]),
+    };
+    // 1004-1006 and 1015 are reserved for reporting, never sent.
+    let is_valid = matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999);
+    if !is_valid {
+        return Err(WsError::Protocol(format!("bad close code: {code}")));
+    }
+    if std::str::from_utf8(&payload[2..]).is_err() {
+        return Err(WsError::InvalidUtf8);
+    }
+    Ok(code)
+}
+
+/// Reads a client frame. Client frames must be masked, payloads longer
+/// than `max_len` are refused before they are read.
+pub async fn read_frame<R: AsyncRead + Unpin>(r: &mut R, max_len: usize) -> Result<Frame, WsError> {
+    let mut header = [0; 2];
+    r.read_exact(&mut header).await?;
+    let fin = header[0] & 0x80 != 0;
+    if header[0] & 0x70 != 0 {
+        return Err(WsError::Protocol("reserved bits are set".to_string()));
+    }
+    let opcode = match Opcode::from_u8(header[0] & 0x0f) {
+        Some(v) => v,
+        None => return Err(WsError::Protocol(format!("unknown opcode: {}", header[0] & 0x0f))),
+    };
+    if header[1] & 0x80 == 0 {
+        return Err(WsError::Protocol("client frame is not masked".to_string()));
+    }
+    let len = match header[1] & 0x7f {
+        126 => {
+            let mut v = [0; 2];
+            r.read_exact(&mut v).await?;
+            u16::from_be_bytes(v) as u64
+        },
+        127 => {
+            let mut v = [0; 8];
+            r.read_exact(&mut v).await?;
+            u64::from_be_bytes(v)
+        },
+        v => v as u64,
+    };
+    if opcode.is_control() && (!fin || len > MAX_CONTROL_LEN as u64) {
+        return Err(WsError::Protocol("bad control frame".to_string()));
+    }
+    if len > max_len as u64 {
+        return Err(WsError::TooLarge(len as usize));
+    }
+    let mut mask = [0; 4];
+    r.read_exact(&mut mask).await?;
+    let mut payload = vec![0; len as usize];
+    r.read_exact(&mut payload).await?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok(Frame { fin, opcode, payload })
+}
+
+
+/// Joins the frames of fragmented messages. Control frames may come
+/// between fragments and are not passed here.
+pub struct Assembler {
+    max_len: usize,
+    opcode: Option<Opcode>,
+    buf: Vec<u8>,
+}
+
+impl Assembler {
+    pub fn new(max_len: usize) -> Self {
+        Self { max_len, opcode: None, buf: vec![] }
+    }
+
+    /// The message, once its last frame is pushed.
+    pub fn push(&mut self, frame: Frame) -> Result<Option<Message>, WsError> {
+        let opcode = match (frame.opcode, self.opcode) {
+            (Opcode::Continuation, Some(v)) => v,
+            (Opcode::Continuation, None) => {
+                return Err(WsError::Protocol("continuation without a message".to_string()));
+            },
+            (v, None) => v,
+            (_, Some(_)) => {
+                return Err(WsError::Protocol("new message before the last one ended".to_string()));
+            },
+        };
+        if self.buf.len() + frame.payload.len() > self.max_len {
+            return Err(WsError::TooLarge(self.buf.len() + frame.payload.len()));
+        }
+        self.buf.extend(frame.payload);
+        if !frame.fin {
+            self.opcode = Some(opcode);
+            return Ok(None);
+        }
+        self.opcode = None;
+        let data = std::mem::take(&mut self.buf);
+        match opcode {
+            Opcode::Text => match String::from_utf8(data) {
+                Ok(v) => Ok(Some(Message::Text(v))),
+                Err(_) => Err(WsError::InvalidUtf8),
+            },
+            _ => Ok(Some(Message::Binary(data))),
+        }
+    }
+}
+
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, v) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([v[0], v[1], v[2], v[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (v, x) in h.iter_mut().zip([a, b, c, d, e]) {
+            *v = v.wrapping_add(x);
+        }
+    }
+    let mut out = [0; 20];
+    for (i, v) in h.iter().enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&v.to_be_bytes());
+    }
+    out
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - i * 6)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
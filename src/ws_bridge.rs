@@ -0,0 +1,392 @@
+// Bridge of the WebSockets which miarh terminates itself to the app. One
+// app connection per server carries the events of all of them, tagged
+// with connection ids:
+//
+//     WS_OPEN     [conn id: u64 BE][raw HTTP request head]
+//     WS_MESSAGE  [count: u16 BE][conn id: u64 BE]*[is text: u8][data]
+//     WS_CLOSE    [count: u16 BE][conn id: u64 BE]*[close code: u16 BE]
+//
+// miarh sends events of one connection. The app sends WS_MESSAGE and
+// WS_CLOSE to any number of connections, e.g. all subscribers of a topic.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use async_channel::{Receiver, Sender};
+use async_io::Timer;
+use async_lock::Mutex;
+use futures_lite::{future, io as lite_io, AsyncRead, AsyncWrite, AsyncWriteExt};
+use once_cell::sync::Lazy;
+use crate::app_pool::AppError;
+use crate::app_proto::{self, Frame, FrameType, ProtoError};
+use crate::spawn::spawn;
+use crate::timeout::timeout;
+use crate::upstream::{AppStream, UpstreamGroup};
+use crate::ws::{self, Assembler, Message, Opcode, WsError};
+
+
+/// Frames queued for a client, it's closed once the queue is full.
+pub const CONN_QUEUE_LEN: usize = 256;
+const APP_QUEUE_LEN: usize = 1024;
+/// Time for the client to answer our close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Reported to the app for connections closed without a close frame.
+pub const CLOSE_ABNORMAL: u16 = 1006;
+
+/// Bridge of each server. Its slot stays locked while the bridge connects,
+/// so that only WebSockets of that server wait for the app.
+static BRIDGES: Lazy<Mutex<HashMap<String, Arc<BridgeSlot>>>> = Lazy::new(|| {
+    Mutex::new(HashMap::new())
+});
+/// Connection ids are unique for the life of the process.
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WsEvent {
+    Open { conn_id: u64, head: Vec<u8> },
+    Message { conn_ids: Vec<u64>, msg: Message },
+    Close { conn_ids: Vec<u64>, code: u16 },
+}
+
+impl WsEvent {
+    /// The event as an app socket frame.
+    pub fn encode(&self) -> Vec<u8> {
+        let ids = |conn_ids: &[u64]| {
+            let mut payload = (conn_ids.len() as u16).to_be_bytes().to_vec();
+            for id in conn_ids {
+                payload.extend_from_slice(&id.to_be_bytes());
+            }
+            payload
+        };
+        match self {
+            WsEvent::Open { conn_id, head } => {
+                let mut payload = conn_id.to_be_bytes().to_vec();
+                payload.extend_from_slice(head);
+                app_proto::encode_frame(FrameType::WsOpen, &payload)
+            },
+            WsEvent::Message { conn_ids, msg } => {
+                let mut payload = ids(conn_ids);
+                match msg {
+                    Message::Text(v) => { payload.push(1); payload.extend_from_slice(v.as_bytes()) },
+                    Message::Binary(v) => { payload.push(0); payload.extend_from_slice(v) },
+                }
+                app_proto::encode_frame(FrameType::WsMessage, &payload)
+            },
+            WsEvent::Close { conn_ids, code } => {
+                let mut payload = ids(conn_ids);
+                payload.extend_from_slice(&code.to_be_bytes());
+                app_proto::encode_frame(FrameType::WsClose, &payload)
+            },
+        }
+    }
+
+    pub fn decode(frame: &Frame) -> Result<Self, ProtoError> {
+        let bad = || ProtoError::Encoding(format!("bad {:?} frame", frame.kind));
+        let p = &frame.payload;
+        let ids = || -> Result<(Vec<u64>, &[u8]), ProtoError> {
+            let count = u16::from_be_bytes([*p.first().ok_or_else(bad)?, *p.get(1).ok_or_else(bad)?]);
+            let end = 2 + count as usize * 8;
+            let ids = p.get(2..end).ok_or_else(bad)?.chunks(8)
+                .map(|v| u64::from_be_bytes(v.try_into().unwrap())).collect();
+            Ok((ids, &p[end..]))
+        };
+        match frame.kind {
+            FrameType::WsOpen if p.len() >= 8 => Ok(WsEvent::Open {
+                conn_id: u64::from_be_bytes(p[..8].try_into().unwrap()),
+                head: p[8..].to_vec(),
+            }),
+            FrameType::WsMessage => {
+                let (conn_ids, rest) = ids()?;
+                let msg = match rest.split_first() {
+                    Some((1, data)) => Message::Text(String::from_utf8(data.to_vec()).map_err(|_| bad())?),
+                    Some((_, data)) => Message::Binary(data.to_vec()),
+                    None => return Err(bad()),
+                };
+                Ok(WsEvent::Message { conn_ids, msg })
+            },
+            FrameType::WsClose => match ids()? {
+                (conn_ids, [hi, lo]) => Ok(WsEvent::Close { conn_ids, code: u16::from_be_bytes([*hi, *lo]) }),
+                _ => Err(bad()),
+            },
+            kind => Err(ProtoError::Unexpected(kind)),
+        }
+    }
+}
+
+/// What a connection sends to its client.
+#[derive(Debug, Clone)]
+pub enum Outgoing {
+    Message(Message),
+    Ping,
+    Pong(Vec<u8>),
+    Close(u16),
+    /// Ends the connection without a close frame, e.g. after EOF.
+    Abort,
+}
+
+/// A registered connection.
+pub struct Conn {
+    pub id: u64,
+    pub tx: Sender<Outgoing>,
+    pub rx: Receiver<Outgoing>,
+}
+
+pub struct Bridge {
+    server: String,
+    conns: Mutex<HashMap<u64, Sender<Outgoing>>>,
+    to_app: Sender<Vec<u8>>,
+    is_alive: AtomicBool,
+}
+
+type BridgeSlot = Mutex<Option<Arc<Bridge>>>;
+
+/// Bridge of the server, connected to one of its available upstreams on
+/// first use and after the app connection was lost.
+pub async fn get_bridge(server: &str, group: &UpstreamGroup, connect_timeout: Duration
+                        ) -> Result<Arc<Bridge>, AppError> {
+    let slot = Arc::clone(BRIDGES.lock().await.entry(server.to_string()).or_default());
+    let mut slot = slot.lock().await;
+    if let Some(bridge) = slot.as_ref().filter(|v| v.is_alive()) {
+        return Ok(Arc::clone(bridge));
+    }
+    // Kept until connected, a half-open trial waits for the result.
+    let picked = match group.pick("", &[]) {
+        Some(v) => v,
+        None => return Err(AppError::Unavailable(None)),
+    };
+    let addr = picked.upstream.addr();
+    let connecting = async {
+        let mut stream = addr.connect().await.map_err(AppError::Connect)?;
+        app_proto::handshake(&mut stream).await?;
+        Ok::<_, AppError>(stream)
+    };
+    let r = match timeout(connect_timeout, connecting).await {
+        None => Err(AppError::Connect(std::io::ErrorKind::TimedOut.into())),
+        Some(r) => r,
+    };
+    let stream = match r {
+        Ok(v) => {
+            group.succeeded(&picked.upstream);
+            v
+        },
+        Err(e) => {
+            println!("WebSocket bridge of {server} can't connect to {addr}: {e}");
+            if e.is_upstream_failure() { group.failed(&picked.upstream) }
+            return Err(e);
+        },
+    };
+    println!("WebSocket bridge of {server} connected to {addr}");
+    let bridge = Bridge::start(server, stream);
+    *slot = Some(Arc::clone(&bridge));
+    Ok(bridge)
+}
+
+impl Bridge {
+    /// Runs the bridge over an app connection after the handshake.
+    pub fn start(server: &str, stream: AppStream) -> Arc<Self> {
+        let (to_app, from_conns) = async_channel::bounded(APP_QUEUE_LEN);
+        let bridge = Arc::new(Self {
+            server: server.to_string(),
+            conns: Mutex::new(HashMap::new()),
+            to_app,
+            is_alive: AtomicBool::new(true),
+        });
+        spawn(run(Arc::clone(&bridge), stream, from_conns)).detach();
+        bridge
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.is_alive.load(Ordering::SeqCst)
+    }
+
+    /// Registers a connection and tells the app about it.
+    pub async fn open(&self, head: Vec<u8>) -> Result<Conn, AppError> {
+        let id = NEXT_CONN_ID.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = async_channel::bounded(CONN_QUEUE_LEN);
+        self.conns.lock().await.insert(id, tx.clone());
+        if !self.send(&WsEvent::Open { conn_id: id, head }).await {
+            self.conns.lock().await.remove(&id);
+            return Err(AppError::Unavailable(None));
+        }
+        Ok(Conn { id, tx, rx })
+    }
+
+    /// Unregisters a connection, `code` is what the client closed it with.
+    pub async fn close(&self, conn_id: u64, code: u16) {
+        if self.conns.lock().await.remove(&conn_id).is_some() {
+            self.send(&WsEvent::Close { conn_ids: vec![conn_id], code }).await;
+        }
+    }
+
+    /// False if the app connection is gone.
+    pub async fn send(&self, event: &WsEvent) -> bool {
+        self.to_app.send(event.encode()).await.is_ok()
+    }
+
+    pub async fn conn_count(&self) -> usize {
+        self.conns.lock().await.len()
+    }
+
+    async fn dispatch(&self, event: WsEvent) {
+        let (conn_ids, out) = match event {
+            WsEvent::Message { conn_ids, msg } => (conn_ids, Outgoing::Message(msg)),
+            WsEvent::Close { conn_ids, code } => (conn_ids, Outgoing::Close(code)),
+            WsEvent::Open { .. } => return,
+        };
+        let conns = self.conns.lock().await;
+        for id in conn_ids {
+            let tx = match conns.get(&id) {
+                Some(v) if !v.is_closed() => v,
+                _ => continue,
+            };
+            if tx.try_send(out.clone()).is_err() {
+                // Can't keep up with its messages, the writer closes it.
+                // It stays registered until close() tells the app.
+                println!("WebSocket {id} of {} is too slow, closing", self.server);
+                tx.close();
+            }
+        }
+    }
+}
+
+async fn run(bridge: Arc<Bridge>, stream: AppStream, from_conns: Receiver<Vec<u8>>) {
+    let (mut r, mut w) = lite_io::split(stream);
+    let writing = async {
+        while let Ok(frame) = from_conns.recv().await {
+            w.write_all(&frame).await?;
+            if from_conns.is_empty() {
+                w.flush().await?;
+            }
+        }
+        Ok(())
+    };
+    let reading = async {
+        loop {
+            let frame = app_proto::read_frame(&mut r).await?;
+            bridge.dispatch(WsEvent::decode(&frame)?).await;
+        }
+    };
+    if let Err::<(), ProtoError>(e) = future::or(writing, reading).await {
+        println!("WebSocket bridge of {} failed: {e}", bridge.server);
+    }
+    bridge.is_alive.store(false, Ordering::SeqCst);
+    from_conns.close();
+    // The app lost the state of all connections.
+    for (_, tx) in bridge.conns.lock().await.drain() {
+        let _ = tx.try_send(Outgoing::Close(ws::CLOSE_INTERNAL_ERROR));
+    }
+}
+
+/// Serves a WebSocket after the handshake: client messages go to the app,
+/// app messages to the client. Sends a ping after `idle` / 2 of silence
+/// and closes after `idle`. Returns the close code for the app.
+pub async fn serve<S>(stream: S, bridge: &Bridge, conn: Conn, idle: Duration, max_len: usize) -> u16
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Conn { id, tx, rx } = conn;
+    let (mut r, mut w) = lite_io::split(stream);
+    let started = Instant::now();
+    let millis = || started.elapsed().as_millis() as u64;
+    // Millis since `started` of the last client frame.
+    let last_active = AtomicU64::new(0);
+    // Millis since `started` when miarh started the closing handshake.
+    let closing_since = AtomicU64::new(u64::MAX);
+
+    let reading = async {
+        let mut assembler = Assembler::new(max_len);
+        let fail = |e: WsError| {
+            println!("WebSocket {id} of {}: {e}", bridge.server);
+            let _ = tx.try_send(Outgoing::Close(e.close_code()));
+            e.close_code()
+        };
+        loop {
+            let frame = match ws::read_frame(&mut r, max_len).await {
+                Ok(v) => v,
+                Err(WsError::Io(_)) => {
+                    let _ = tx.try_send(Outgoing::Abort);
+                    return CLOSE_ABNORMAL;
+                },
+                Err(e) => return fail(e),
+            };
+            last_active.store(millis(), Ordering::SeqCst);
+            match frame.opcode {
+                Opcode::Ping => { let _ = tx.send(Outgoing::Pong(frame.payload)).await; },
+                Opcode::Pong => {},
+                Opcode::Close => {
+                    let code = match ws::parse_close(&frame.payload) {
+                        Ok(v) => v,
+                        Err(e) => return fail(e),
+                    };
+                    // Echoed unless miarh closed first, then the writer is done.
+                    let echo = if code == ws::CLOSE_NO_STATUS { ws::CLOSE_NORMAL } else { code };
+                    let _ = tx.send(Outgoing::Close(echo)).await;
+                    return code;
+                },
+                _ => match assembler.push(frame) {
+                    Ok(None) => {},
+                    Ok(Some(msg)) => {
+                        if !bridge.send(&WsEvent::Message { conn_ids: vec![id], msg }).await {
+                            let _ = tx.send(Outgoing::Close(ws::CLOSE_INTERNAL_ERROR)).await;
+                            return ws::CLOSE_INTERNAL_ERROR;
+                        }
+                    },
+                    Err(e) => return fail(e),
+                },
+            }
+        }
+    };
+    let writing = async {
+        loop {
+            // The bridge closes the queue of connections which are too slow.
+            let out = rx.recv().await.unwrap_or(Outgoing::Close(ws::CLOSE_POLICY));
+            let frame = match &out {
+                Outgoing::Message(msg) => ws::encode_message(msg),
+                Outgoing::Ping => ws::encode_frame(Opcode::Ping, &[]),
+                Outgoing::Pong(v) => ws::encode_frame(Opcode::Pong, v),
+                Outgoing::Close(code) => {
+                    let _ = closing_since.compare_exchange(u64::MAX, millis(), Ordering::SeqCst,
+                                                           Ordering::SeqCst);
+                    // Codes from the app which can't be sent.
+                    let code = match ws::parse_close(&code.to_be_bytes()) {
+                        Ok(_) => *code,
+                        Err(_) => ws::CLOSE_NORMAL,
+                    };
+                    ws::encode_frame(Opcode::Close, &ws::close_payload(code, ""))
+                },
+                Outgoing::Abort => return,
+            };
+            if w.write_all(&frame).await.is_err() || w.flush().await.is_err() { return }
+            if matches!(out, Outgoing::Close(_)) { return }
+        }
+    };
+    let watching = async {
+        let idle_ms = idle.as_millis() as u64;
+        let tick = (idle / 4).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        let mut is_pinged = false;
+        loop {
+            Timer::after(tick).await;
+            let closing = closing_since.load(Ordering::SeqCst);
+            if closing != u64::MAX {
+                if millis().saturating_sub(closing) >= CLOSE_TIMEOUT.as_millis() as u64 { return }
+                continue;
+            }
+            let quiet = millis().saturating_sub(last_active.load(Ordering::SeqCst));
+            if quiet >= idle_ms {
+                let _ = closing_since.compare_exchange(u64::MAX, millis(), Ordering::SeqCst,
+                                                       Ordering::SeqCst);
+                let _ = tx.try_send(Outgoing::Close(ws::CLOSE_GOING_AWAY));
+            } else if quiet >= idle_ms / 2 && !is_pinged {
+                is_pinged = tx.try_send(Outgoing::Ping).is_ok();
+            } else if quiet < idle_ms / 2 {
+                is_pinged = false;
+            }
+        }
+    };
+    future::or(
+        async { future::zip(reading, writing).await.0 },
+        async { watching.await; ws::CLOSE_GOING_AWAY },
+    ).await
+}
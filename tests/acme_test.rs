@@ -0,0 +1,241 @@
+use std::path::PathBuf;
+use async_net::TcpListener;
+use futures_lite::{future, AsyncReadExt, AsyncWriteExt};
+use openssl::bn::BigNum;
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::{hash, MessageDigest};
+use openssl::x509::{X509, X509Req};
+use serde_json::Value;
+use miarh::acme::{self, AcmeClient, Managed};
+use miarh::conf::{AcmeConf, Conf};
+use miarh::jws::{self, Signer};
+use miarh::tls;
+
+
+fn decode(v: &str) -> Vec<u8> {
+    let mut v = v.replace('-', "+").replace('_', "/");
+    while !v.len().is_multiple_of(4) {
+        v.push('=');
+    }
+    openssl::base64::decode_block(&v).unwrap()
+}
+
+fn tmp_path(name: &str) -> String {
+    let path: PathBuf = std::env::temp_dir()
+        .join(format!("miarh-acme-test-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path.display().to_string()
+}
+
+#[test]
+fn signs_requests() {
+    assert_eq!("-_8", jws::base64url(&[0xfb, 0xff]));
+    let key = jws::generate_key().unwrap();
+    let jwk = jws::jwk(&key).unwrap().to_string();
+    assert!(jwk.starts_with(r#"{"crv":"P-256","kty":"EC","x":""#), "{jwk}");
+    let thumbprint = jws::thumbprint(&jws::jwk(&key).unwrap()).unwrap();
+    assert_eq!(jws::base64url(&hash(MessageDigest::sha256(), jwk.as_bytes()).unwrap()), thumbprint);
+
+    let payload = serde_json::json!({ "termsOfServiceAgreed": true });
+    let body = jws::sign(&key, Signer::Jwk, "nonce1", "https://ca/new-acct", Some(&payload)).unwrap();
+    let protected: Value = serde_json::from_slice(&decode(body["protected"].as_str().unwrap())).unwrap();
+    assert_eq!("ES256", protected["alg"]);
+    assert_eq!("nonce1", protected["nonce"]);
+    assert_eq!("https://ca/new-acct", protected["url"]);
+    assert_eq!("EC", protected["jwk"]["kty"]);
+    assert_eq!(payload.to_string().as_bytes(), &decode(body["payload"].as_str().unwrap())[..]);
+    let signature = decode(body["signature"].as_str().unwrap());
+    assert_eq!(64, signature.len());
+    let sig = EcdsaSig::from_private_components(
+        BigNum::from_slice(&signature[..32]).unwrap(),
+        BigNum::from_slice(&signature[32..]).unwrap(),
+    ).unwrap();
+    let signed = format!("{}.{}", body["protected"].as_str().unwrap(), body["payload"].as_str().unwrap());
+    let digest = hash(MessageDigest::sha256(), signed.as_bytes()).unwrap();
+    assert!(sig.verify(&digest, &*key.ec_key().unwrap()).unwrap());
+
+    // POST-as-GET has an empty payload, known accounts sign with their URL.
+    let body = jws::sign(&key, Signer::Kid("https://ca/acct/1"), "nonce2", "https://ca/order/1", None).unwrap();
+    let protected: Value = serde_json::from_slice(&decode(body["protected"].as_str().unwrap())).unwrap();
+    assert_eq!("https://ca/acct/1", protected["kid"]);
+    assert!(protected.get("jwk").is_none());
+    assert_eq!("", body["payload"]);
+}
+
+#[test]
+fn parses_responses() {
+    let resp = acme::parse_resp(b"HTTP/1.1 201 Created\r\n\
+        Replay-Nonce: abc\r\n\
+        Location: https://ca/acct/1\r\n\
+        Transfer-Encoding: chunked\r\n\r\n\
+        4\r\n{\"a\"\r\n5;ext=1\r\n:\"b\"}\r\n0\r\n\r\n").unwrap();
+    assert_eq!(201, resp.head.code);
+    assert_eq!(Some("abc"), resp.head.get("replay-nonce"));
+    assert_eq!("https://ca/acct/1", resp.location().unwrap());
+    assert_eq!("b", resp.json().unwrap()["a"]);
+    assert!(resp.problem().is_none());
+
+    // HEAD has the length of a body it doesn't send.
+    let resp = acme::parse_resp(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n").unwrap();
+    assert!(resp.body.is_empty());
+
+    let resp = acme::parse_resp(b"HTTP/1.1 400 Bad Request\r\n\
+        Content-Type: application/problem+json\r\n\
+        Content-Length: 69\r\n\r\n\
+        {\"type\":\"urn:ietf:params:acme:error:badNonce\",\"detail\":\"stale nonce\"}").unwrap();
+    match resp.problem() {
+        Some(acme::AcmeError::Problem { status, kind, detail }) => {
+            assert_eq!(400, status);
+            assert_eq!("urn:ietf:params:acme:error:badNonce", kind);
+            assert_eq!("stale nonce", detail);
+        },
+        _ => panic!("no problem"),
+    }
+    assert!(acme::parse_resp(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nz\r\n").is_err());
+}
+
+fn acme_conf(default_cert: &str, own_cert: &str) -> Conf {
+    toml::from_str(&format!(r#"
+        ip = "127.0.0.1"
+        https_port = 4430
+        http_port = 8000
+        acme_challenge_dir = ""
+        acme_challenge_url = "/.well-known/acme-challenge/"
+        index_url = "/"
+        tmp_dir = "/tmp"
+        max_request_size_mb = 10
+        [tls]
+        cert_path = "{default_cert}.crt"
+        key_path = "{default_cert}.key"
+        acme = true
+        [[servers]]
+        name = "a"
+        hostnames = ["a.example", "www.a.example"]
+        static_dir = ""
+        dev_static_dir = ""
+        index_path = ""
+        [servers.tls]
+        cert_path = "{own_cert}.crt"
+        key_path = "{own_cert}.key"
+        acme = true
+        [[servers]]
+        name = "b"
+        hostnames = ["b.example"]
+        static_dir = ""
+        dev_static_dir = ""
+        index_path = ""
+        [[servers]]
+        name = "c"
+        hostnames = ["c.example"]
+        static_dir = ""
+        dev_static_dir = ""
+        index_path = ""
+    "#)).unwrap()
+}
+
+#[test]
+fn manages_certificates_of_the_conf() {
+    let default_cert = tmp_path("default");
+    let own_cert = tmp_path("own");
+    let conf = acme_conf(&default_cert, &own_cert);
+    let managed = acme::managed(&conf);
+    assert_eq!(vec![
+        Managed {
+            cert_path: format!("{default_cert}.crt"),
+            key_path: format!("{default_cert}.key"),
+            hostnames: vec!["b.example".to_string(), "c.example".to_string()],
+        },
+        Managed {
+            cert_path: format!("{own_cert}.crt"),
+            key_path: format!("{own_cert}.key"),
+            hostnames: vec!["a.example".to_string(), "www.a.example".to_string()],
+        },
+    ], managed);
+    assert!(acme::needs_renewal(&managed[0], 30));
+
+    // Placeholders let the listener start before anything is issued.
+    acme::ensure_placeholders(&conf).unwrap();
+    assert!(tls::Acceptors::from_conf(&conf).is_ok());
+    assert!(acme::needs_renewal(&managed[0], 30));
+    assert!(!acme::needs_renewal(&managed[0], 0));
+    let mut more_names = managed[0].clone();
+    more_names.hostnames.push("d.example".to_string());
+    assert!(acme::needs_renewal(&more_names, 0));
+}
+
+#[test]
+fn builds_csr() {
+    let key = jws::generate_key().unwrap();
+    let hostnames = vec!["a.example".to_string(), "www.a.example".to_string()];
+    let csr = X509Req::from_der(&acme::csr(&key, &hostnames).unwrap()).unwrap();
+    assert!(csr.verify(&key).unwrap());
+    let cn = csr.subject_name().entries().next().unwrap();
+    assert_eq!("a.example", cn.data().as_utf8().unwrap().to_string());
+    assert_eq!(1, csr.extensions().unwrap().len());
+}
+
+/// Answers HTTP-01 challenges like the HTTP port of miarh does.
+async fn serve_challenges(listener: TcpListener) {
+    loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0; 4096];
+        let n = stream.read(&mut buf).await.unwrap_or(0);
+        let head = String::from_utf8_lossy(&buf[..n]).to_string();
+        let path = head.split(' ').nth(1).unwrap_or("");
+        let token = path.strip_prefix("/.well-known/acme-challenge/").unwrap_or("");
+        let resp = match acme::key_authorization(token).await {
+            Some(v) => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{v}", v.len()),
+            None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(),
+        };
+        let _ = stream.write_all(resp.as_bytes()).await;
+    }
+}
+
+/// Full issuance against a local Pebble ACME server, skipped unless
+/// MIARH_TEST_ACME_DIRECTORY is set. Pebble validates HTTP-01 on port
+/// 5002 of the hostname, which pebble-challtestsrv resolves to 127.0.0.1:
+///
+///   pebble-challtestsrv -defaultIPv4 127.0.0.1 &
+///   pebble -config test/config/pebble-config.json -dnsserver 127.0.0.1:8053 &
+///   MIARH_TEST_ACME_DIRECTORY=https://localhost:14000/dir \
+///   MIARH_TEST_ACME_CA=test/certs/pebble.minica.pem cargo test --test acme_test
+#[test]
+fn issues_certificates_with_pebble() {
+    let directory_url = match std::env::var("MIARH_TEST_ACME_DIRECTORY") {
+        Ok(v) => v,
+        Err(_) => return println!("MIARH_TEST_ACME_DIRECTORY is not set, skipped"),
+    };
+    let conf = AcmeConf {
+        directory_url,
+        contact_email: "admin@miarh.test".to_string(),
+        account_key_path: tmp_path("account.key"),
+        ca_cert_path: std::env::var("MIARH_TEST_ACME_CA").unwrap_or_default(),
+        ..AcmeConf::default()
+    };
+    let http_port = std::env::var("MIARH_TEST_ACME_HTTP_PORT").unwrap_or("5002".to_string());
+    future::block_on(async {
+        let listener = TcpListener::bind(format!("0.0.0.0:{http_port}")).await.unwrap();
+        let issuing = async {
+            let hostnames = vec!["miarh.test".to_string(), "www.miarh.test".to_string()];
+            let mut client = AcmeClient::new(&conf).await.unwrap();
+            let (chain, key) = client.obtain(&hostnames).await.unwrap();
+            let chain = X509::stack_from_pem(&chain).unwrap();
+            assert!(chain[0].public_key().unwrap().public_eq(&key));
+
+            // Renewal writes the files, the account key is reused.
+            let cert_path = tmp_path("issued");
+            let managed = Managed {
+                cert_path: format!("{cert_path}.crt"),
+                key_path: format!("{cert_path}.key"),
+                hostnames,
+            };
+            let (renewed, errors) = acme::renew_due(&conf, std::slice::from_ref(&managed)).await;
+            assert!(errors.is_empty(), "{}", errors[0]);
+            assert_eq!(1, renewed);
+            assert!(!acme::needs_renewal(&managed, 0));
+            let (renewed, _) = acme::renew_due(&conf, &[managed]).await;
+            assert_eq!(0, renewed);
+        };
+        future::or(issuing, serve_challenges(listener)).await;
+    });
+}
@@ -0,0 +1,80 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use async_net::unix::UnixListener;
+use futures_lite::future;
+use miarh::app_pool::{AppError, AppPool, PoolConf};
+use miarh::app_proto;
+use miarh::upstream::UpstreamAddr;
+
+
+fn serve_app(socket_path: &str, accepted: Arc<AtomicUsize>) {
+    let _ = std::fs::remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path).unwrap();
+    std::thread::spawn(move || future::block_on(async {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            accepted.fetch_add(1, Ordering::SeqCst);
+            app_proto::accept_handshake(&mut stream).await.unwrap();
+            std::thread::spawn(move || future::block_on(async {
+                while let Ok((req, _)) = app_proto::read_request(&mut stream).await {
+                    app_proto::send_response(&mut stream, req.path.as_bytes()).await.unwrap();
+                }
+            }));
+        }
+    }));
+}
+
+#[test]
+fn reuses_connections_and_limits_queue() {
+    let socket_path = std::env::temp_dir()
+        .join(format!("miarh-pool-test-{}.sock", std::process::id()))
+        .display().to_string();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    serve_app(&socket_path, Arc::clone(&accepted));
+    let pool = AppPool::new(PoolConf {
+        addr: UpstreamAddr::Unix(socket_path.to_string()),
+        size: 1,
+        max_idle: Duration::from_secs(60),
+        queue_limit: 0,
+    });
+    future::block_on(async {
+        let conn = pool.get().await.unwrap();
+        assert!(matches!(pool.get().await, Err(AppError::Saturated)));
+        pool.release(conn).await;
+        for _ in 0..3 {
+            let conn = pool.get().await.unwrap();
+            pool.release(conn).await;
+        }
+    });
+    assert_eq!(1, accepted.load(Ordering::SeqCst));
+    let _ = std::fs::remove_file(&socket_path);
+}
+
+#[test]
+fn cancelled_waits_leave_queue() {
+    let socket_path = std::env::temp_dir()
+        .join(format!("miarh-pool-cancel-test-{}.sock", std::process::id()))
+        .display().to_string();
+    serve_app(&socket_path, Arc::new(AtomicUsize::new(0)));
+    let pool = AppPool::new(PoolConf {
+        addr: UpstreamAddr::Unix(socket_path.to_string()),
+        size: 1,
+        max_idle: Duration::from_secs(60),
+        queue_limit: 1,
+    });
+    future::block_on(async {
+        let conn = pool.get().await.unwrap();
+        // Dropped while queued, like on a timeout.
+        for _ in 0..3 {
+            assert!(future::poll_once(pool.get()).await.is_none());
+        }
+        let mut waiter = Box::pin(pool.get());
+        assert!(future::poll_once(&mut waiter).await.is_none());
+        assert!(matches!(pool.get().await, Err(AppError::Saturated)));
+        pool.release(conn).await;
+        let conn = waiter.await.unwrap();
+        pool.release(conn).await;
+    });
+    let _ = std::fs::remove_file(&socket_path);
+}
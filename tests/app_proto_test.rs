@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use async_net::unix::UnixStream;
+use futures_lite::{future, AsyncWriteExt};
+use miarh::app_proto::{self, FrameType, ProtoError};
+use miarh_saras_http::Request;
+
+
+fn request(path: &str) -> Request {
+    Request {
+        method: "post".to_string(),
+        host: "example.com".to_string(),
+        path: path.to_string(),
+        session_id: "".to_string(),
+        query: HashMap::new(),
+        body_string: "{}".to_string(),
+        route: HashMap::new(),
+        files: HashMap::new(),
+    }
+}
+
+#[test]
+fn requests_share_one_connection() {
+    future::block_on(async {
+        let (mut miarh, mut app) = UnixStream::pair().unwrap();
+        let app_side = async {
+            app_proto::accept_handshake(&mut app).await.unwrap();
+            for _ in 0..2 {
+                let (req, body) = app_proto::read_request(&mut app).await.unwrap();
+                let resp = format!("HTTP/1.1 200 OK\r\n\r\n{} {}", req.path, body.len());
+                app_proto::send_response(&mut app, resp.as_bytes()).await.unwrap();
+            }
+        };
+        let miarh_side = async {
+            app_proto::handshake(&mut miarh).await.unwrap();
+            let mut resps = vec![];
+            for path in ["/a", "/b"] {
+                app_proto::send_request(&mut miarh, &request(path), b"xyz").await.unwrap();
+                resps.push(app_proto::read_response(&mut miarh).await.unwrap());
+            }
+            resps
+        };
+        let (_, resps) = future::zip(app_side, miarh_side).await;
+        assert_eq!(resps[0], b"HTTP/1.1 200 OK\r\n\r\n/a 3");
+        assert_eq!(resps[1], b"HTTP/1.1 200 OK\r\n\r\n/b 3");
+    });
+}
+
+#[test]
+fn version_mismatch_and_error_frames() {
+    future::block_on(async {
+        let (mut miarh, mut app) = UnixStream::pair().unwrap();
+        let hello = app_proto::encode_frame(FrameType::Hello, &99u16.to_be_bytes());
+        miarh.write_all(&hello).await.unwrap();
+        assert!(matches!(app_proto::accept_handshake(&mut app).await,
+                         Err(ProtoError::Version(99))));
+        assert!(matches!(app_proto::read_frame(&mut miarh).await,
+                         Err(ProtoError::App(_))));
+
+        let err = app_proto::encode_frame(FrameType::Error, b"db is down");
+        app.write_all(&err).await.unwrap();
+        match app_proto::read_response(&mut miarh).await {
+            Err(ProtoError::App(e)) => assert_eq!(e, "db is down"),
+            r => panic!("unexpected result: {r:?}"),
+        }
+
+        app.write_all(&[42, 0, 0, 0, 0]).await.unwrap();
+        assert!(matches!(app_proto::read_frame(&mut miarh).await,
+                         Err(ProtoError::UnknownFrame(42))));
+    });
+}
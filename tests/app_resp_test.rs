@@ -0,0 +1,103 @@
+use std::io::Read;
+use std::time::{Duration, UNIX_EPOCH};
+use miarh::app_resp::{RespHead, RespOptions, RespProcessor, MAX_BUFFERED_BODY};
+use miarh::http::http_date;
+
+
+fn processor(is_accept_brotli: bool) -> RespProcessor {
+    RespProcessor::new(RespOptions {
+        is_accept_brotli,
+        headers: vec![("X-Frame-Options".to_string(), "DENY".to_string())],
+        location_rewrites: vec![],
+        is_cgi: false,
+        is_head: false,
+    })
+}
+
+fn run(p: &mut RespProcessor, resp: &[u8], chunk_size: usize) -> Vec<u8> {
+    let mut out = vec![];
+    for c in resp.chunks(chunk_size) {
+        out.extend(p.feed(c).unwrap());
+    }
+    out.extend(p.finish().unwrap());
+    out
+}
+
+fn split(out: &[u8]) -> (RespHead, Vec<u8>) {
+    let end = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    (RespHead::parse(&out[..end]).unwrap(), out[end + 4..].to_vec())
+}
+
+fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    loop {
+        let end = body.windows(2).position(|w| w == b"\r\n").unwrap();
+        let len = usize::from_str_radix(std::str::from_utf8(&body[..end]).unwrap(), 16).unwrap();
+        if len == 0 { return out }
+        out.extend_from_slice(&body[end + 2..end + 2 + len]);
+        body = &body[end + 4 + len..];
+    }
+}
+
+#[test]
+fn fixes_length_and_headers() {
+    let mut p = processor(false);
+    let out = run(&mut p, b"HTTP/1.1 200 OK\r\nContent-Length: 99\r\n\
+        Connection: keep-alive, X-Internal\r\nX-Internal: 1\r\nKeep-Alive: 5\r\n\
+        Content-Type: image/png\r\n\r\nhello", 3);
+    let (head, body) = split(&out);
+    assert_eq!(Some(200), p.status());
+    assert_eq!(body, b"hello");
+    assert_eq!(head.get("content-length"), Some("5"));
+    assert_eq!(head.get("connection"), Some("close"));
+    assert_eq!(head.get("x-internal"), None);
+    assert_eq!(head.get("keep-alive"), None);
+    assert_eq!(head.get("server"), Some("miarh"));
+    assert_eq!(head.get("x-frame-options"), Some("DENY"));
+    assert!(head.get("date").is_some());
+}
+
+#[test]
+fn large_body_without_length_is_chunked() {
+    let body = vec![b'x'; MAX_BUFFERED_BODY * 3];
+    let mut resp = b"HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n\r\n".to_vec();
+    resp.extend(&body);
+    let out = run(&mut processor(true), &resp, 10_000);
+    let (head, chunked) = split(&out);
+    assert_eq!(head.get("transfer-encoding"), Some("chunked"));
+    assert_eq!(dechunk(&chunked), body);
+}
+
+#[test]
+fn compresses_on_the_fly() {
+    let text = "<p>hello</p>".repeat(1000);
+    let resp = format!("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\
+        Content-Length: {}\r\n\r\n{text}", text.len());
+    let out = run(&mut processor(true), resp.as_bytes(), 1000);
+    let (head, chunked) = split(&out);
+    assert_eq!(head.get("content-encoding"), Some("br"));
+    assert_eq!(head.get("content-length"), None);
+    let compressed = dechunk(&chunked);
+    assert!(compressed.len() < text.len());
+    let mut decompressed = String::new();
+    brotli::Decompressor::new(&compressed[..], 4096).read_to_string(&mut decompressed).unwrap();
+    assert_eq!(decompressed, text);
+}
+
+#[test]
+fn rejects_invalid_head() {
+    assert!(processor(false).feed(b"hello\r\n\r\n").is_err());
+    assert!(processor(false).feed(b"HTTP/1.1 999 OK\r\n\r\n").is_err());
+    assert!(processor(false).feed(b"HTTP/1.1 200 OK\r\nbad header\r\n\r\n").is_err());
+    let mut p = processor(false);
+    p.feed(b"HTTP/1.1 200 OK\r\n").unwrap();
+    assert!(p.finish().is_err());
+}
+
+#[test]
+fn date_format() {
+    let t = UNIX_EPOCH + Duration::from_secs(784111777);
+    assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT", http_date(t));
+    let t = UNIX_EPOCH + Duration::from_secs(951782400);
+    assert_eq!("Tue, 29 Feb 2000 00:00:00 GMT", http_date(t));
+}
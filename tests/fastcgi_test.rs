@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use async_net::unix::{UnixListener, UnixStream};
+use futures_lite::{future, AsyncWriteExt};
+use miarh::app_resp::{RespOptions, RespProcessor};
+use miarh::cgi::{self, CgiProtocol, CgiResp};
+use miarh::conf::CgiConf;
+use miarh::fastcgi::{self, Output, RecordType};
+use miarh::proxy::{ForwardInfo, RawHead};
+
+
+/// Params of PARAMS records, as the app decodes them.
+fn decode_params(mut data: &[u8]) -> Option<Vec<(String, String)>> {
+    fn len(data: &mut &[u8]) -> Option<usize> {
+        let (v, rest) = match data.first()? {
+            b if b & 0x80 == 0 => (*b as usize, &data[1..]),
+            _ if data.len() >= 4 => {
+                let v = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                ((v & 0x7fff_ffff) as usize, &data[4..])
+            },
+            _ => return None,
+        };
+        *data = rest;
+        Some(v)
+    }
+    let mut params = vec![];
+    while !data.is_empty() {
+        let k_len = len(&mut data)?;
+        let v_len = len(&mut data)?;
+        if data.len() < k_len + v_len { return None }
+        let k = String::from_utf8_lossy(&data[..k_len]).to_string();
+        let v = String::from_utf8_lossy(&data[k_len..k_len + v_len]).to_string();
+        params.push((k, v));
+        data = &data[k_len + v_len..];
+    }
+    Some(params)
+}
+
+fn cgi_conf() -> CgiConf {
+    CgiConf {
+        protocol: CgiProtocol::Fastcgi,
+        pass: "unix:/run/php-fpm.sock".to_string(),
+        root: "/var/www/site/".to_string(),
+        index: "index.php".to_string(),
+        params: HashMap::from([("APP_ENV".to_string(), "prod".to_string())]),
+    }
+}
+
+fn fwd() -> ForwardInfo {
+    ForwardInfo {
+        client_ip: Some("192.0.2.7".parse().unwrap()),
+        host: "example.com:4430".to_string(),
+        proto: "https".to_string(),
+        client_cert: None,
+    }
+}
+
+fn env(head: &[u8]) -> HashMap<String, String> {
+    let raw = RawHead::parse(head).unwrap();
+    cgi::cgi_env(&raw, &fwd(), &cgi_conf(), 4430).unwrap().into_iter().collect()
+}
+
+#[test]
+fn builds_cgi_env() {
+    let env = env(b"POST /app.php/users%20list?page=2 HTTP/1.1\r\n\
+        Host: example.com:4430\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: 7\r\n\
+        Proxy: http://evil\r\n\
+        X-Tag: a\r\n\
+        X-Tag: b\r\n\r\n");
+    let expected = [
+        ("REQUEST_METHOD", "POST"),
+        ("REQUEST_URI", "/app.php/users%20list?page=2"),
+        ("QUERY_STRING", "page=2"),
+        ("SCRIPT_NAME", "/app.php"),
+        ("SCRIPT_FILENAME", "/var/www/site/app.php"),
+        ("PATH_INFO", "/users list"),
+        ("DOCUMENT_ROOT", "/var/www/site"),
+        ("SERVER_NAME", "example.com"),
+        ("SERVER_PORT", "4430"),
+        ("HTTPS", "on"),
+        ("REMOTE_ADDR", "192.0.2.7"),
+        ("CONTENT_TYPE", "application/json"),
+        ("CONTENT_LENGTH", "7"),
+        ("HTTP_HOST", "example.com:4430"),
+        ("HTTP_X_TAG", "a, b"),
+        ("APP_ENV", "prod"),
+    ];
+    for (k, v) in expected {
+        assert_eq!(Some(v), env.get(k).map(|v| v.as_str()), "{k}");
+    }
+    assert!(!env.contains_key("HTTP_PROXY"));
+    assert!(!env.contains_key("HTTP_CONTENT_TYPE"));
+
+    let env = self::env(b"GET /users/1 HTTP/1.1\r\nHost: example.com\r\n\r\n");
+    assert_eq!("/index.php", env["SCRIPT_NAME"]);
+    assert_eq!("/users/1", env["PATH_INFO"]);
+    assert_eq!("0", env["CONTENT_LENGTH"]);
+
+    let raw = RawHead::parse(b"GET /../etc/passwd.php HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    assert!(cgi::cgi_env(&raw, &fwd(), &cgi_conf(), 4430).is_err());
+    assert_eq!(("/index.php".to_string(), "/a.phpx".to_string()),
+               cgi::split_script("/a.phpx", "index.php"));
+}
+
+#[test]
+fn converts_cgi_response() {
+    let mut resp = CgiResp::new();
+    assert_eq!(b"".to_vec(), resp.feed(b"Status: 404 Not Found\r\nContent-Ty").unwrap());
+    assert!(resp.finish().is_err());
+    let out = resp.feed(b"pe: text/plain\r\n\r\nnope").unwrap();
+    assert_eq!(b"HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\n\r\nnope".to_vec(), out);
+    assert_eq!(b"more".to_vec(), resp.feed(b"more").unwrap());
+    assert!(resp.finish().is_ok());
+
+    let mut resp = CgiResp::new();
+    let out = resp.feed(b"Location: /login\n\n").unwrap();
+    assert_eq!(b"HTTP/1.1 302 Found\r\nLocation: /login\r\n\r\n".to_vec(), out);
+
+    assert!(CgiResp::new().feed(b"Status: abc\r\n\r\n").is_err());
+    assert!(CgiResp::new().feed(b"no colon\r\n\r\n").is_err());
+}
+
+#[test]
+fn encodes_records_and_params() {
+    let long = "v".repeat(300);
+    let params = vec![
+        ("A".to_string(), "1".to_string()),
+        ("LONG".to_string(), long.to_string()),
+    ];
+    let encoded = fastcgi::encode_params(&params);
+    assert_eq!(&[1, 1, b'A', b'1', 4, 0x80, 0, 1, 44][..], &encoded[..9]);
+    assert_eq!(Some(params), decode_params(&encoded));
+    assert_eq!(None, decode_params(&[5, 1, b'a']));
+
+    let record = fastcgi::encode_record(RecordType::Stdout, b"hello");
+    assert_eq!(vec![1, 6, 0, 1, 0, 5, 3, 0, b'h', b'e', b'l', b'l', b'o', 0, 0, 0], record);
+    let records = fastcgi::encode_stream(RecordType::Stdin, &vec![0; 70000]);
+    assert_eq!(8 + 65535 + 1 + 8 + 4465 + 7, records.len());
+}
+
+// Stand-in FastCGI server: echoes params and stdin as a CGI response.
+fn serve_fastcgi(socket_path: &str) {
+    let _ = std::fs::remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path).unwrap();
+    std::thread::spawn(move || future::block_on(async {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let begin = fastcgi::read_record(&mut stream).await.unwrap();
+        assert_eq!(RecordType::BeginRequest, begin.kind);
+        assert_eq!(&[0, 1, 0], &begin.content[..3]);
+        let mut params = vec![];
+        loop {
+            let record = fastcgi::read_record(&mut stream).await.unwrap();
+            assert_eq!(RecordType::Params, record.kind);
+            if record.content.is_empty() { break }
+            params.extend(record.content);
+        }
+        let params: HashMap<String, String> =
+            decode_params(&params).unwrap().into_iter().collect();
+        let mut stdin = vec![];
+        loop {
+            let record = fastcgi::read_record(&mut stream).await.unwrap();
+            assert_eq!(RecordType::Stdin, record.kind);
+            if record.content.is_empty() { break }
+            stdin.extend(record.content);
+        }
+        let body = format!("{} {} {}", params["REQUEST_METHOD"], params["SCRIPT_FILENAME"],
+                           String::from_utf8(stdin).unwrap());
+        let head = "Status: 201 Created\r\nContent-Type: text/plain\r\n\r\n";
+        let mut out = fastcgi::encode_record(RecordType::Stderr, b"PHP Notice: test\n");
+        out.extend(fastcgi::encode_record(RecordType::Stdout, head.as_bytes()));
+        out.extend(fastcgi::encode_record(RecordType::Stdout, body.as_bytes()));
+        out.extend(fastcgi::encode_record(RecordType::Stdout, &[]));
+        out.extend(fastcgi::encode_record(RecordType::EndRequest, &[0, 0, 0, 0, 0, 0, 0, 0]));
+        stream.write_all(&out).await.unwrap();
+    }));
+}
+
+#[test]
+fn exchange_with_fastcgi_server() {
+    let socket_path = std::env::temp_dir()
+        .join(format!("miarh-fastcgi-test-{}.sock", std::process::id()))
+        .display().to_string();
+    serve_fastcgi(&socket_path);
+    future::block_on(async {
+        let raw = RawHead::parse(b"POST /save.php HTTP/1.1\r\n\
+            Host: example.com\r\nContent-Length: 9\r\n\r\n").unwrap();
+        let env = cgi::cgi_env(&raw, &fwd(), &cgi_conf(), 4430).unwrap();
+        let mut stream = UnixStream::connect(&socket_path).await.unwrap();
+        let mut out = fastcgi::begin_request(&env);
+        out.extend(fastcgi::encode_stream(RecordType::Stdin, b"name="));
+        out.extend(fastcgi::encode_stream(RecordType::Stdin, b"miarh"));
+        out.extend(fastcgi::end_stdin());
+        stream.write_all(&out).await.unwrap();
+
+        let mut cgi_resp = CgiResp::new();
+        let mut resp = RespProcessor::new(RespOptions {
+            is_accept_brotli: false,
+            headers: vec![],
+            location_rewrites: vec![],
+            is_cgi: false,
+            is_head: false,
+        });
+        let mut http = vec![];
+        loop {
+            match fastcgi::read_output(&mut stream).await.unwrap() {
+                Output::Stdout(data) => http.extend(resp.feed(&cgi_resp.feed(&data).unwrap()).unwrap()),
+                Output::End => {
+                    cgi_resp.finish().unwrap();
+                    http.extend(resp.finish().unwrap());
+                    break;
+                },
+            }
+        }
+        let http = String::from_utf8(http).unwrap();
+        assert!(http.starts_with("HTTP/1.1 201 Created\r\n"), "{http}");
+        assert!(http.contains("\r\nContent-Length: 38\r\n"), "{http}");
+        assert!(http.ends_with("\r\n\r\nPOST /var/www/site/save.php name=miarh"), "{http}");
+    });
+    let _ = std::fs::remove_file(&socket_path);
+}
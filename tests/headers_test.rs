@@ -21,3 +21,35 @@ fn reject_if_bad_utf8() {
     let r = parse_headers(&invalid);
     assert_eq!(false, r.is_valid());
 }
+
+#[test]
+fn detect_websocket_upgrade() {
+    let buf = "GET /ws HTTP/1.1\r\nHost: example.com\r\n\
+        Upgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\r\n";
+    let r = parse_headers(&buf.as_bytes().to_vec());
+    assert_eq!(true, r.is_websocket_upgrade());
+
+    let buf = "GET /ws HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\r\n";
+    let r = parse_headers(&buf.as_bytes().to_vec());
+    assert_eq!(false, r.is_websocket_upgrade());
+
+    let buf = "POST /ws HTTP/1.1\r\nHost: example.com\r\n\
+        Upgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+    let r = parse_headers(&buf.as_bytes().to_vec());
+    assert_eq!(false, r.is_websocket_upgrade());
+}
+
+#[test]
+fn any_method_token() {
+    let r = parse_headers(&b"PATCH /a HTTP/1.1\r\nHost: example.com\r\n\r\n".to_vec());
+    assert!(r.is_complete());
+    assert!(!r.is_app_method());
+    assert!(!r.is_valid());
+    assert_eq!("patch", r.get_header("method"));
+
+    let r = parse_headers(&b"GE(T /a HTTP/1.1\r\nHost: example.com\r\n\r\n".to_vec());
+    assert!(!r.is_complete());
+    // Only the first line is the request line.
+    let r = parse_headers(&b"X\r\nGET /a HTTP/1.1\r\nHost: example.com\r\n\r\n".to_vec());
+    assert!(!r.is_complete());
+}
@@ -0,0 +1,106 @@
+use std::time::Duration;
+use async_net::unix::UnixListener;
+use futures_lite::future;
+use miarh::app_pool::AppError;
+use miarh::app_proto;
+use miarh::health::{self, BreakerState, CircuitBreaker, HealthConf};
+use miarh::upstream::UpstreamAddr;
+
+
+fn conf(check_path: &str) -> HealthConf {
+    HealthConf {
+        max_fails: 2,
+        fail_timeout: Duration::from_millis(100),
+        check_interval: Duration::ZERO,
+        check_timeout: Duration::from_secs(1),
+        check_path: check_path.to_string(),
+        check_host: "example.com".to_string(),
+    }
+}
+
+#[test]
+fn breaker_opens_and_recovers() {
+    let conf = conf("");
+    let breaker = CircuitBreaker::new();
+    assert!(!breaker.failed(&conf));
+    assert_eq!(BreakerState::Closed, breaker.state());
+    assert!(breaker.failed(&conf));
+    assert!(matches!(breaker.state(), BreakerState::Open(_)));
+    assert!(!breaker.is_available());
+    assert_eq!((false, false), breaker.acquire());
+    assert!(breaker.retry_after().unwrap() <= Duration::from_millis(100));
+
+    std::thread::sleep(Duration::from_millis(150));
+    assert_eq!(BreakerState::HalfOpen, breaker.state());
+    assert_eq!((true, true), breaker.acquire());
+    // Only one trial request at a time.
+    assert!(!breaker.is_available());
+    assert_eq!((false, false), breaker.acquire());
+    // A failed trial reopens the circuit at once.
+    assert!(breaker.failed(&conf));
+    assert!(matches!(breaker.state(), BreakerState::Open(_)));
+
+    std::thread::sleep(Duration::from_millis(150));
+    assert_eq!((true, true), breaker.acquire());
+    breaker.release_trial();
+    assert_eq!((true, true), breaker.acquire());
+    assert!(breaker.succeeded());
+    assert_eq!(BreakerState::Closed, breaker.state());
+    assert!(breaker.retry_after().is_none());
+}
+
+#[test]
+fn passed_check_half_opens() {
+    let conf = conf("");
+    let breaker = CircuitBreaker::new();
+    assert!(!breaker.check_passed());
+    breaker.failed(&conf);
+    assert!(breaker.failed(&conf));
+    // Before fail_timeout, then a trial request still decides.
+    assert!(breaker.check_passed());
+    assert_eq!(BreakerState::HalfOpen, breaker.state());
+    assert!(!breaker.check_passed());
+    assert_eq!((true, true), breaker.acquire());
+    assert!(breaker.failed(&conf));
+    assert!(matches!(breaker.state(), BreakerState::Open(_)));
+}
+
+fn serve_health(socket_path: &str, status: &'static str) {
+    let _ = std::fs::remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path).unwrap();
+    std::thread::spawn(move || future::block_on(async {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            app_proto::accept_handshake(&mut stream).await.unwrap();
+            if let Ok((req, _)) = app_proto::read_request(&mut stream).await {
+                assert_eq!("/health", req.path);
+                let resp = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n");
+                app_proto::send_response(&mut stream, resp.as_bytes()).await.unwrap();
+            }
+        }
+    }));
+}
+
+#[test]
+fn active_checks() {
+    let dir = std::env::temp_dir();
+    let ok_path = dir.join(format!("miarh-health-ok-{}.sock", std::process::id()))
+        .display().to_string();
+    let bad_path = dir.join(format!("miarh-health-bad-{}.sock", std::process::id()))
+        .display().to_string();
+    serve_health(&ok_path, "200 OK");
+    serve_health(&bad_path, "500 Internal Server Error");
+    let ok = UpstreamAddr::Unix(ok_path.to_string());
+    let bad = UpstreamAddr::Unix(bad_path.to_string());
+    let missing = UpstreamAddr::Unix(dir.join("miarh-health-missing.sock").display().to_string());
+    future::block_on(async {
+        assert!(health::check(&ok, &conf("")).await.is_ok());
+        assert!(health::check(&ok, &conf("/health")).await.is_ok());
+        assert!(health::check(&bad, &conf("")).await.is_ok());
+        assert!(matches!(health::check(&bad, &conf("/health")).await,
+                         Err(AppError::InvalidResponse(_))));
+        assert!(matches!(health::check(&missing, &conf("")).await,
+                         Err(AppError::Connect(_))));
+    });
+    let _ = std::fs::remove_file(&ok_path);
+    let _ = std::fs::remove_file(&bad_path);
+}
@@ -0,0 +1,106 @@
+use miarh::conf::Conf;
+use miarh::http;
+
+
+fn conf(public_https_port: u16) -> Conf {
+    conf_with(&format!("public_https_port = {public_https_port}"))
+}
+
+fn conf_with(top_level: &str) -> Conf {
+    toml::from_str(&conf_toml(top_level)).unwrap()
+}
+
+fn conf_toml(top_level: &str) -> String {
+    format!(r#"
+        ip = "127.0.0.1"
+        https_port = 4430
+        http_port = 8000
+        {top_level}
+        acme_challenge_dir = ""
+        acme_challenge_url = ""
+        index_url = "/"
+        tmp_dir = "/tmp"
+        max_request_size_mb = 10
+        [[servers]]
+        name = "a"
+        hostnames = ["example.com", "www.example.com"]
+        static_dir = ""
+        dev_static_dir = ""
+        index_path = ""
+        redirect_code = 308
+        canonical_host = "example.com"
+        [servers.hsts]
+        max_age_secs = 31536000
+        include_subdomains = true
+        preload = true
+        [[servers]]
+        name = "b"
+        hostnames = ["b.example.com"]
+        static_dir = ""
+        dev_static_dir = ""
+        index_path = ""
+        serve_http = true
+        [servers.hsts]
+        max_age_secs = 300
+    "#)
+}
+
+#[test]
+fn builds_redirect_urls() {
+    assert_eq!(("example.com", None), http::split_host("example.com"));
+    assert_eq!(("example.com", Some(8000)), http::split_host("example.com:8000"));
+    assert_eq!(("[::1]", Some(8000)), http::split_host("[::1]:8000"));
+    assert_eq!(("[::1]", None), http::split_host("[::1]"));
+    assert_eq!(("example.com:x", None), http::split_host("example.com:x"));
+
+    assert_eq!("https://example.com/a?b=1", http::url("https", "example.com", Some(443), "/a?b=1"));
+    assert_eq!("https://example.com:4430/", http::url("https", "example.com", Some(4430), "/"));
+    assert_eq!("http://example.com/", http::url("http", "example.com", Some(80), "/"));
+    assert_eq!("http://example.com:443/", http::url("http", "example.com", Some(443), "/"));
+    assert_eq!("http://example.com/", http::url("http", "example.com", None, "/"));
+    assert_eq!("localhost:14000", http::authority("https", "localhost", Some(14000)));
+    assert_eq!("example.com", http::authority("https", "example.com", Some(443)));
+
+    let r = http::redirect_resp(308, "https://example.com/");
+    assert_eq!("HTTP/1.1 308 Permanent Redirect\r\n\
+        Content-Length: 0\r\n\
+        Content-Type: text/html\r\n\
+        Location: https://example.com/\r\n\r\n", r.get_resp());
+}
+
+#[test]
+fn reads_redirect_settings() {
+    assert_eq!(4430, conf(0).redirect_https_port());
+    let conf = conf(443);
+    assert_eq!(443, conf.redirect_https_port());
+
+    let a = conf.server("www.example.com").unwrap();
+    assert_eq!(308, a.redirect_code);
+    assert!(!a.serve_http);
+    assert_eq!(Some("example.com"), a.canonical_redirect("www.example.com"));
+    assert_eq!(None, a.canonical_redirect("EXAMPLE.com"));
+    assert_eq!("max-age=31536000; includeSubDomains; preload", a.hsts.as_ref().unwrap().header_value());
+
+    let b = conf.server("b.example.com").unwrap();
+    assert_eq!(301, b.redirect_code);
+    assert!(b.serve_http);
+    assert_eq!(None, b.canonical_redirect("b.example.com"));
+    assert_eq!("max-age=300", b.hsts.as_ref().unwrap().header_value());
+}
+
+#[test]
+fn rejects_other_redirect_codes() {
+    for code in ["302", "307", "200"] {
+        let toml = conf_toml("").replace("redirect_code = 308", &format!("redirect_code = {code}"));
+        let err = toml::from_str::<Conf>(&toml).unwrap_err();
+        assert!(err.to_string().contains("expected 301 or 308"), "{err}");
+    }
+}
+
+#[test]
+fn reads_plain_http_mode() {
+    assert!(!conf(0).plain_http);
+    let conf = conf_with("plain_http = true");
+    assert!(conf.plain_http);
+    assert_eq!(4430, conf.redirect_https_port());
+}
@@ -0,0 +1,199 @@
+use std::fs;
+use miarh::headers::parse_headers;
+use miarh::multipart::{MultipartError, MultipartLimits, MultipartStreamer};
+use miarh::upload::UploadRules;
+
+
+const CTYPE: &str = "multipart/form-data; boundary=xyz";
+
+fn limits(max_file_size: usize) -> MultipartLimits {
+    MultipartLimits {
+        tmp_dir: std::env::temp_dir().display().to_string(),
+        max_file_size,
+        max_fields_size: 1024,
+        rules: UploadRules::default(),
+    }
+}
+
+fn body() -> Vec<u8> {
+    let mut body = b"--xyz\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+        hello\r\n\
+        --xyz\r\n\
+        Content-Disposition: form-data; name=\"doc\"; filename=\"a.bin\"\r\n\
+        Content-Type: application/octet-stream\r\n\r\n".to_vec();
+    body.extend((0..5000).map(|i| (i % 251) as u8));
+    body.extend(b"\r\n--xyz--\r\n");
+    body
+}
+
+#[test]
+fn streams_file_to_tmp_dir() {
+    let mut hp = parse_headers(&b"POST / HTTP/1.1\r\nHost: a\r\n\r\n".to_vec());
+    let mut mp = MultipartStreamer::new(CTYPE, limits(10_000)).unwrap();
+    for chunk in body().chunks(7) {
+        mp.feed(chunk).unwrap();
+    }
+    mp.finish(&mut hp).unwrap();
+
+    let v: serde_json::Value = serde_json::from_str(&hp.body_string).unwrap();
+    assert_eq!(v["title"], "hello");
+    assert_eq!(v["doc"]["filename"], "a.bin");
+    assert_eq!(v["doc"]["size"], 5000);
+    assert_eq!(v["doc"]["content_type"], "application/octet-stream");
+    let path = v["doc"]["path"].as_str().unwrap().to_string();
+    let content = fs::read(&path).unwrap();
+    assert_eq!(content, (0..5000).map(|i| (i % 251) as u8).collect::<Vec<u8>>());
+
+    drop(hp);
+    assert!(fs::metadata(&path).is_err());
+}
+
+#[test]
+fn rejects_too_large_file() {
+    let mut mp = MultipartStreamer::new(CTYPE, limits(100)).unwrap();
+    match mp.feed(&body()) {
+        Err(MultipartError::FileTooLarge) => {},
+        r => panic!("unexpected result: {r:?}"),
+    }
+}
+
+#[test]
+fn rejects_truncated_body() {
+    let mut hp = parse_headers(&b"POST / HTTP/1.1\r\nHost: a\r\n\r\n".to_vec());
+    let mut mp = MultipartStreamer::new(CTYPE, limits(10_000)).unwrap();
+    let body = body();
+    mp.feed(&body[..body.len() - 20]).unwrap();
+    assert!(mp.finish(&mut hp).is_err());
+}
+
+#[test]
+fn text_fields_are_escaped_strings() {
+    let mut hp = parse_headers(&b"POST / HTTP/1.1\r\nHost: a\r\n\r\n".to_vec());
+    let mut mp = MultipartStreamer::new(CTYPE, limits(100)).unwrap();
+    let body = b"--xyz\r\n\
+        Content-Disposition: form-data; name=\"q\"\r\n\r\n\
+        say \"hi\",\r\n\"admin\": true\r\n\
+        --xyz\r\n\
+        Content-Disposition: form-data; name=\"n\"\r\n\r\n\
+        007\r\n\
+        --xyz\r\n\
+        Content-Disposition: form-data; name=\"n\"\r\n\r\n\
+        1e5\r\n\
+        --xyz--\r\n";
+    mp.feed(body).unwrap();
+    mp.finish(&mut hp).unwrap();
+
+    let v: serde_json::Value = serde_json::from_str(&hp.body_string).unwrap();
+    assert_eq!(v["q"], "say \"hi\",\r\n\"admin\": true");
+    assert_eq!(v["n"], serde_json::json!(["007", "1e5"]));
+    assert!(v.get("admin").is_none());
+}
+
+#[test]
+fn case_insensitive_headers_and_quoted_boundary() {
+    let buf = b"POST / HTTP/1.1\r\nHost: a\r\n\
+        Content-Type: Multipart/Form-Data; boundary=\"AbC d\"\r\n\r\n".to_vec();
+    let mut hp = parse_headers(&buf);
+    futures_lite::future::block_on(hp.check_is_multipart());
+    assert!(hp.is_multipart);
+    let mut mp = MultipartStreamer::new(&hp.get_header("content-type"), limits(100)).unwrap();
+    let body = "--AbC d  \r\n\
+        content-disposition: FORM-DATA; name=\"a;b\"; \
+        filename=\"x.txt\"; filename*=UTF-8''%D1%84%D0%B0%D0%B9%D0%BB.txt\r\n\
+        CONTENT-TYPE: text/plain\r\n\r\n\
+        data\r\n\
+        --AbC d--";
+    mp.feed(body.as_bytes()).unwrap();
+    mp.finish(&mut hp).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&hp.body_string).unwrap();
+    assert_eq!(v["a;b"]["filename"], "файл.txt");
+    assert_eq!(v["a;b"]["content_type"], "text/plain");
+    assert_eq!(v["a;b"]["size"], 4);
+}
+
+#[test]
+fn files_without_content_type_are_sniffed() {
+    let mut hp = parse_headers(&b"POST / HTTP/1.1\r\nHost: a\r\n\r\n".to_vec());
+    let mut limits = limits(100);
+    limits.rules.allowed_mime_types = vec!["image/png".to_string()];
+    let mut mp = MultipartStreamer::new(CTYPE, limits).unwrap();
+    let mut body = b"--xyz\r\n\
+        Content-Disposition: form-data; name=\"img\"; filename=\"a.png\"\r\n\r\n".to_vec();
+    body.extend(b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR");
+    body.extend(b"\r\n--xyz--\r\n");
+    mp.feed(&body).unwrap();
+    mp.finish(&mut hp).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&hp.body_string).unwrap();
+    assert_eq!(v["img"]["content_type"], "image/png");
+}
+
+#[test]
+fn rejects_malformed_input() {
+    assert!(MultipartStreamer::new("multipart/form-data", limits(100)).is_err());
+    assert!(MultipartStreamer::new("multipart/form-data; boundary=", limits(100)).is_err());
+    let bad_bodies: Vec<&[u8]> = vec![
+        b"--xyz\r\nContent-Type: text/plain\r\n\r\nv\r\n--xyz--",
+        b"--xyz\r\nContent-Disposition: form-data\r\n\r\nv\r\n--xyz--",
+        b"--xyz\r\nContent-Disposition: attachment; name=\"a\"\r\n\r\nv\r\n--xyz--",
+        b"--xyz\r\nContent-Disposition form-data; name=\"a\"\r\n\r\nv\r\n--xyz--",
+        b"--xyz\r\nContent-Disposition: form-data; name=\"\xff\"\r\n\r\nv\r\n--xyz--",
+        b"--xyz\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n\xff\xfe\r\n--xyz--",
+        b"--xyzjunk\r\n",
+    ];
+    for body in bad_bodies {
+        let mut mp = MultipartStreamer::new(CTYPE, limits(100)).unwrap();
+        match mp.feed(body) {
+            Err(MultipartError::Malformed(_)) => {},
+            r => panic!("unexpected result for {:?}: {r:?}", String::from_utf8_lossy(body)),
+        }
+    }
+}
+
+// Deterministic fuzzing: random mutations of a valid body, fed in random
+// chunks, must never panic.
+#[test]
+fn fuzz_never_panics() {
+    let mut seed: u64 = 0x2545F4914F6CDD1D;
+    let mut rand = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+    let tokens: Vec<&[u8]> = vec![
+        b"\r\n", b"--", b"--xyz", b"\r\n--xyz", b"\"", b";", b"=", b":",
+        b"name=", b"filename*=", b"''", b"%", b"\xff", b" ", b"\r\n\r\n",
+    ];
+    let original = body();
+    for _ in 0..3000 {
+        let mut body = original.clone();
+        for _ in 0..(rand() % 8 + 1) {
+            let pos = (rand() as usize) % (body.len() + 1);
+            match rand() % 4 {
+                0 => { body.truncate(pos); },
+                1 => {
+                    let t = tokens[(rand() as usize) % tokens.len()];
+                    body.splice(pos..pos, t.iter().cloned());
+                },
+                2 if pos < body.len() => { body[pos] = rand() as u8; },
+                _ => {
+                    let end = (pos + (rand() as usize) % 64).min(body.len());
+                    body.drain(pos..end);
+                },
+            }
+        }
+        let mut hp = parse_headers(&b"POST / HTTP/1.1\r\nHost: a\r\n\r\n".to_vec());
+        let mut mp = MultipartStreamer::new(CTYPE, limits(10_000)).unwrap();
+        let mut rest = &body[..];
+        let mut is_err = false;
+        while !rest.is_empty() {
+            let n = ((rand() as usize) % 100 + 1).min(rest.len());
+            if mp.feed(&rest[..n]).is_err() { is_err = true; break }
+            rest = &rest[n..];
+        }
+        if !is_err {
+            let _ = mp.finish(&mut hp);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::net::IpAddr;
+use miarh::app_resp::{RespOptions, RespProcessor};
+use miarh::conf::ServerConf;
+use miarh::proxy::{self, ForwardInfo, RawHead};
+use miarh::tls::ClientCert;
+use miarh::upstream::UpstreamAddr;
+
+
+fn fwd(ip: &str, host: &str) -> ForwardInfo {
+    ForwardInfo {
+        client_ip: Some(ip.parse::<IpAddr>().unwrap()),
+        host: host.to_string(),
+        proto: "https".to_string(),
+        client_cert: None,
+    }
+}
+
+#[test]
+fn builds_backend_request_head() {
+    let raw = RawHead::parse(b"POST /api/items?x=1 HTTP/1.1\r\n\
+        Host: example.com\r\n\
+        Connection: keep-alive, X-Secret\r\n\
+        X-Secret: 1\r\n\
+        Keep-Alive: timeout=5\r\n\
+        X-Forwarded-For: 10.0.0.1\r\n\
+        Forwarded: for=10.0.0.1\r\n\
+        Expect: 100-continue\r\n\
+        Content-Length: 2\r\n\
+        Cookie: session_id=abc\r\n\r\n").unwrap();
+    assert_eq!("/api/items", raw.path());
+    let head = proxy::request_head(&raw, &fwd("192.0.2.7", "example.com"));
+    let head = String::from_utf8(head).unwrap();
+    assert_eq!("POST /api/items?x=1 HTTP/1.1\r\n\
+        Host: example.com\r\n\
+        Content-Length: 2\r\n\
+        Cookie: session_id=abc\r\n\
+        X-Forwarded-For: 192.0.2.7\r\n\
+        X-Forwarded-Proto: https\r\n\
+        X-Forwarded-Host: example.com\r\n\
+        Forwarded: for=192.0.2.7;host=example.com;proto=https\r\n\
+        Connection: close\r\n\r\n", head);
+}
+
+#[test]
+fn builds_upgrade_head() {
+    let raw = RawHead::parse(b"GET /ws HTTP/1.1\r\n\
+        Host: example.com\r\n\
+        Connection: Upgrade\r\n\
+        Upgrade: websocket\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\r\n").unwrap();
+    let head = proxy::upgrade_head(&raw, &fwd("192.0.2.7", "example.com"));
+    let head = String::from_utf8(head).unwrap();
+    assert_eq!("GET /ws HTTP/1.1\r\n\
+        Host: example.com\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        X-Forwarded-For: 192.0.2.7\r\n\
+        X-Forwarded-Proto: https\r\n\
+        X-Forwarded-Host: example.com\r\n\
+        Forwarded: for=192.0.2.7;host=example.com;proto=https\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\r\n", head);
+}
+
+#[test]
+fn forwards_client_certificates() {
+    let raw = RawHead::parse(b"GET / HTTP/1.1\r\n\
+        Host: admin.example.com\r\n\
+        X-Client-Cert-Subject: CN=root\r\n\r\n").unwrap();
+    let head = String::from_utf8(proxy::request_head(&raw, &fwd("192.0.2.7", "admin.example.com"))).unwrap();
+    assert!(!head.contains("X-Client-Cert"), "{head}");
+
+    let mut info = fwd("192.0.2.7", "admin.example.com");
+    info.client_cert = Some(ClientCert {
+        subject: "CN=alice,O=Example".to_string(),
+        san: vec!["DNS:alice.example.com".to_string(), "email:alice@example.com".to_string()],
+        fingerprint: "ab01".to_string(),
+    });
+    let head = String::from_utf8(proxy::request_head(&raw, &info)).unwrap();
+    assert!(head.ends_with("Forwarded: for=192.0.2.7;host=admin.example.com;proto=https\r\n\
+        X-Client-Cert-Subject: CN=alice,O=Example\r\n\
+        X-Client-Cert-San: DNS:alice.example.com, email:alice@example.com\r\n\
+        X-Client-Cert-Fingerprint: ab01\r\n\
+        Connection: close\r\n\r\n"), "{head}");
+}
+
+#[test]
+fn forwarded_header() {
+    assert_eq!("for=\"[2001:db8::1]\";host=\"example.com:4430\";proto=https",
+               proxy::forwarded(&fwd("2001:db8::1", "example.com:4430")));
+    let mut info = fwd("192.0.2.7", "example.com");
+    info.client_ip = None;
+    assert_eq!("for=unknown;host=example.com;proto=https", proxy::forwarded(&info));
+    assert!(RawHead::parse(b"GET /\r\n\r\n").is_err());
+}
+
+#[test]
+fn rewrites_location() {
+    let rewrites = proxy::location_rewrites(
+        &UpstreamAddr::parse("http://127.0.0.1:8000"), &fwd("192.0.2.7", "example.com:4430"));
+    let rewrite = |v: &str| proxy::rewrite_location(v, &rewrites);
+    assert_eq!(Some("https://example.com:4430/login".to_string()),
+               rewrite("http://127.0.0.1:8000/login"));
+    assert_eq!(Some("https://example.com:4430?next=1".to_string()),
+               rewrite("http://example.com:4430?next=1"));
+    assert_eq!(Some("https://example.com:4430/".to_string()), rewrite("HTTP://example.com/"));
+    assert_eq!(None, rewrite("http://example.com.evil/"));
+    assert_eq!(None, rewrite("https://other.com/"));
+    assert_eq!(None, rewrite("/relative"));
+
+    let mut resp = RespProcessor::new(RespOptions {
+        is_accept_brotli: false,
+        headers: vec![],
+        location_rewrites: rewrites,
+        is_cgi: false,
+        is_head: false,
+    });
+    let mut out = resp.feed(b"HTTP/1.1 302 Found\r\n\
+        Location: http://127.0.0.1:8000/next\r\n\
+        Content-Length: 0\r\n\r\n").unwrap();
+    out.extend(resp.finish().unwrap());
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("\r\nLocation: https://example.com:4430/next\r\n"), "{out}");
+}
+
+#[test]
+fn proxy_routes() {
+    let srv: ServerConf = toml::from_str(r#"
+        name = "mysite"
+        hostnames = ["example.com"]
+        static_dir = ""
+        dev_static_dir = ""
+        index_path = ""
+        proxy_pass = "http://127.0.0.1:8000"
+        [[proxy_routes]]
+        prefix = "/api/"
+        proxy_pass = "unix:/run/api.sock"
+        [[proxy_routes]]
+        prefix = "/api/v2/"
+        proxy_pass = "127.0.0.1:9000"
+    "#).unwrap();
+    assert_eq!(Some("http://127.0.0.1:8000"), srv.proxy_for("/"));
+    assert_eq!(Some("unix:/run/api.sock"), srv.proxy_for("/api/items"));
+    assert_eq!(Some("127.0.0.1:9000"), srv.proxy_for("/api/v2/items"));
+    assert_eq!(UpstreamAddr::Tcp("127.0.0.1:8000".to_string()),
+               UpstreamAddr::parse("http://127.0.0.1:8000/"));
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use async_net::unix::{UnixListener, UnixStream};
+use futures_lite::{future, AsyncReadExt, AsyncWriteExt};
+use miarh::app_resp::{RespOptions, RespProcessor};
+use miarh::cgi::{self, CgiProtocol};
+use miarh::conf::CgiConf;
+use miarh::proxy::{ForwardInfo, RawHead};
+use miarh::scgi;
+
+
+/// Headers of an SCGI request and the length of its head, None while incomplete
+/// or if invalid.
+fn parse_head(data: &[u8]) -> Option<(Vec<(String, String)>, usize)> {
+    let colon = data.iter().position(|v| *v == b':')?;
+    let len = std::str::from_utf8(&data[..colon]).ok()?.parse::<usize>().ok()?;
+    let end = colon + 1 + len;
+    if data.get(end) != Some(&b',') { return None }
+    let mut parts = data[colon + 1..end].split(|v| *v == 0);
+    let mut headers = vec![];
+    while let (Some(k), Some(v)) = (parts.next(), parts.next()) {
+        if k.is_empty() { break }
+        headers.push((String::from_utf8_lossy(k).to_string(), String::from_utf8_lossy(v).to_string()));
+    }
+    Some((headers, end + 1))
+}
+
+fn env(head: &[u8]) -> Vec<(String, String)> {
+    let conf = CgiConf {
+        protocol: CgiProtocol::Scgi,
+        pass: "unix:/run/app.sock".to_string(),
+        root: "".to_string(),
+        index: "index.php".to_string(),
+        params: HashMap::new(),
+    };
+    let fwd = ForwardInfo {
+        client_ip: Some("192.0.2.7".parse().unwrap()),
+        host: "example.com".to_string(),
+        proto: "https".to_string(),
+        client_cert: None,
+    };
+    cgi::cgi_env(&RawHead::parse(head).unwrap(), &fwd, &conf, 443).unwrap()
+}
+
+#[test]
+fn encodes_request_head() {
+    let env = vec![
+        ("REQUEST_METHOD".to_string(), "GET".to_string()),
+        ("CONTENT_LENGTH".to_string(), "5".to_string()),
+    ];
+    let head = scgi::request_head(&env);
+    assert_eq!(b"43:CONTENT_LENGTH\x005\x00SCGI\x001\x00REQUEST_METHOD\x00GET\x00,".to_vec(), head);
+    let mut data = head.to_vec();
+    data.extend_from_slice(b"hello");
+    let (headers, len) = parse_head(&data).unwrap();
+    assert_eq!(head.len(), len);
+    assert_eq!(("CONTENT_LENGTH".to_string(), "5".to_string()), headers[0]);
+    assert_eq!(3, headers.len());
+    assert_eq!(None, parse_head(&head[..head.len() - 1]));
+
+    let env: HashMap<String, String> = self::env(b"GET /users/1?a=b HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .into_iter().collect();
+    assert_eq!("", env["SCRIPT_NAME"]);
+    assert_eq!("/users/1", env["PATH_INFO"]);
+    assert!(!env.contains_key("SCRIPT_FILENAME"));
+}
+
+// Stand-in SCGI server: echoes the method, path and body as a CGI response.
+fn serve_scgi(socket_path: &str) {
+    let _ = std::fs::remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path).unwrap();
+    std::thread::spawn(move || future::block_on(async {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0; 1024];
+        let (headers, head_len) = loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            data.extend_from_slice(&buf[..n]);
+            if let Some(v) = parse_head(&data) { break v }
+        };
+        let headers: HashMap<String, String> = headers.into_iter().collect();
+        let content_len: usize = headers["CONTENT_LENGTH"].parse().unwrap();
+        while data.len() < head_len + content_len {
+            let n = stream.read(&mut buf).await.unwrap();
+            data.extend_from_slice(&buf[..n]);
+        }
+        let body = format!("{} {} {}", headers["REQUEST_METHOD"], headers["PATH_INFO"],
+                           String::from_utf8_lossy(&data[head_len..]));
+        let resp = format!("Status: 201 Created\r\nContent-Type: text/plain\r\n\r\n{body}");
+        stream.write_all(resp.as_bytes()).await.unwrap();
+    }));
+}
+
+#[test]
+fn exchange_with_scgi_server() {
+    let socket_path = std::env::temp_dir()
+        .join(format!("miarh-scgi-test-{}.sock", std::process::id()))
+        .display().to_string();
+    serve_scgi(&socket_path);
+    future::block_on(async {
+        let env = env(b"POST /items HTTP/1.1\r\nHost: example.com\r\nContent-Length: 10\r\n\r\n");
+        let mut stream = UnixStream::connect(&socket_path).await.unwrap();
+        let mut out = scgi::request_head(&env);
+        out.extend_from_slice(b"name=miarh");
+        stream.write_all(&out).await.unwrap();
+
+        let mut resp = RespProcessor::new(RespOptions {
+            is_accept_brotli: false,
+            headers: vec![],
+            location_rewrites: vec![],
+            is_cgi: true,
+            is_head: false,
+        });
+        let mut http = vec![];
+        let mut buf = [0; 1024];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 { break }
+            http.extend(resp.feed(&buf[..n]).unwrap());
+        }
+        http.extend(resp.finish().unwrap());
+        let http = String::from_utf8(http).unwrap();
+        assert!(http.starts_with("HTTP/1.1 201 Created\r\n"), "{http}");
+        assert!(http.ends_with("\r\n\r\nPOST /items name=miarh"), "{http}");
+    });
+    let _ = std::fs::remove_file(&socket_path);
+}
@@ -0,0 +1,191 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::sync::Once;
+use async_net::{TcpListener, TcpStream};
+use futures_lite::{future, AsyncReadExt, AsyncWriteExt};
+use miarh::http_stream_handler::HttpStreamHandler;
+use miarh::stream_handler::StreamHandler;
+
+
+/// Test sites, in miarh.toml of a temp working dir, where CONF is read from.
+fn setup() -> PathBuf {
+    static INIT: Once = Once::new();
+    let dir = std::env::temp_dir().join(format!("miarh-stream-{}", std::process::id()));
+    INIT.call_once(|| {
+        fs::create_dir_all(dir.join("static")).unwrap();
+        fs::write(dir.join("static/x.css"), "body {}").unwrap();
+        let dir = dir.display();
+        fs::write(format!("{dir}/miarh.toml"), format!(r#"
+            ip = "127.0.0.1"
+            https_port = 4430
+            http_port = 8000
+            acme_challenge_dir = "{dir}/acme/"
+            acme_challenge_url = "/.well-known/acme-challenge/"
+            index_url = "/"
+            tmp_dir = "{dir}"
+            max_request_size_mb = 1
+            [[servers]]
+            name = "php"
+            hostnames = ["php.test"]
+            static_dir = "{dir}/static"
+            dev_static_dir = ""
+            index_path = ""
+            connect_timeout_ms = 1000
+            [servers.cgi]
+            pass = "unix:{dir}/missing-fpm.sock"
+            [[servers]]
+            name = "api"
+            hostnames = ["api.test"]
+            static_dir = "{dir}/static"
+            dev_static_dir = ""
+            index_path = ""
+            proxy_pass = "unix:{dir}/backend.sock"
+            [[servers]]
+            name = "app"
+            hostnames = ["app.test"]
+            socket_path = "{dir}/missing-app.sock"
+            static_dir = ""
+            dev_static_dir = ""
+            index_path = ""
+            [[servers]]
+            name = "x"
+            hostnames = ["x.test", "www.x.test"]
+            canonical_host = "x.test"
+            redirect_code = 308
+            socket_path = "{dir}/missing-x.sock"
+            static_dir = ""
+            dev_static_dir = ""
+            index_path = ""
+            serve_http = true
+            [[servers]]
+            name = "ws"
+            hostnames = ["ws.test"]
+            socket_path = "{dir}/missing-ws.sock"
+            static_dir = ""
+            dev_static_dir = ""
+            index_path = ""
+            websocket_paths = ["/ws"]
+        "#)).unwrap();
+        serve_backend(&format!("{dir}/backend.sock"));
+        std::env::set_current_dir(&dir.to_string()).unwrap();
+    });
+    dir
+}
+
+/// HTTP backend answering with the request line it got.
+fn serve_backend(socket_path: &str) {
+    let listener = UnixListener::bind(socket_path).unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request_line = String::new();
+            BufReader::new(&stream).read_line(&mut request_line).unwrap();
+            let body = request_line.trim_end().to_string();
+            let mut resp = format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\
+                Content-Length: {}\r\n\r\n", body.len());
+            if !body.starts_with("HEAD ") {
+                resp.push_str(&body);
+            }
+            stream.write_all(resp.as_bytes()).unwrap();
+        }
+    });
+}
+
+/// Response of miarh to a plain HTTP request.
+fn request(req: &str) -> String {
+    exchange(req, |stream| async move {
+        let mut handler = StreamHandler::new(stream, None);
+        handler.process().await;
+    })
+}
+
+/// Response of miarh to a request on http_port.
+fn http_port_request(req: &str) -> String {
+    exchange(req, |stream| HttpStreamHandler::new(stream).process())
+}
+
+fn exchange<F: std::future::Future<Output = ()>>(req: &str, serve: impl FnOnce(TcpStream) -> F) -> String {
+    setup();
+    future::block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        client.write_all(req.as_bytes()).await.unwrap();
+        let reading = async {
+            let mut resp = String::new();
+            client.read_to_string(&mut resp).await.unwrap();
+            resp
+        };
+        future::zip(serve(stream), reading).await.1
+    })
+}
+
+#[test]
+fn serves_static_files_of_cgi_sites() {
+    let resp = request("GET /static/x.css HTTP/1.1\r\nHost: php.test\r\n\r\n");
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{resp}");
+    assert!(resp.ends_with("\r\n\r\nbody {}"), "{resp}");
+    // Everything else goes to php-fpm, which isn't running.
+    let resp = request("GET /index.php HTTP/1.1\r\nHost: php.test\r\n\r\n");
+    assert!(resp.starts_with("HTTP/1.1 502"), "{resp}");
+}
+
+#[test]
+fn proxies_any_method() {
+    for method in ["PATCH", "OPTIONS", "PROPFIND"] {
+        let resp = request(&format!("{method} /items HTTP/1.1\r\nHost: api.test\r\n\r\n"));
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{resp}");
+        assert!(resp.ends_with(&format!("\r\n\r\n{method} /items HTTP/1.1")), "{resp}");
+    }
+    let resp = request("HEAD /items HTTP/1.1\r\nHost: api.test\r\n\r\n");
+    assert!(resp.contains("\r\nContent-Length: 20\r\n"), "{resp}");
+    assert!(resp.ends_with("\r\n\r\n"), "{resp}");
+    // The app protocol has no other methods.
+    let resp = request("PATCH /items HTTP/1.1\r\nHost: app.test\r\n\r\n");
+    assert!(resp.starts_with("HTTP/1.1 405"), "{resp}");
+    assert!(resp.contains("\r\nAllow: GET, POST, PUT, DELETE\r\n"), "{resp}");
+}
+
+#[test]
+fn static_paths_before_proxy() {
+    let resp = request("GET /static/x.css HTTP/1.1\r\nHost: api.test\r\n\r\n");
+    assert!(resp.ends_with("\r\n\r\nbody {}"), "{resp}");
+    // No index_path or dev_static_dir, the backend has these.
+    let resp = request("GET /?a=1 HTTP/1.1\r\nHost: api.test\r\n\r\n");
+    assert!(resp.ends_with("\r\n\r\nGET /?a=1 HTTP/1.1"), "{resp}");
+    let resp = request("GET /dev_static/x.css HTTP/1.1\r\nHost: api.test\r\n\r\n");
+    assert!(resp.ends_with("\r\n\r\nGET /dev_static/x.css HTTP/1.1"), "{resp}");
+}
+
+#[test]
+fn canonical_redirects_keep_port() {
+    let req = "GET /a?b=1 HTTP/1.1\r\nHost: www.x.test:8000\r\n\r\n";
+    let resp = request(req);
+    assert!(resp.starts_with("HTTP/1.1 308 Permanent Redirect\r\n"), "{resp}");
+    assert!(resp.contains("\r\nLocation: http://x.test:8000/a?b=1\r\n"), "{resp}");
+    let resp = http_port_request(req);
+    assert!(resp.starts_with("HTTP/1.1 308"), "{resp}");
+    assert!(resp.contains("\r\nLocation: http://x.test:8000/a?b=1\r\n"), "{resp}");
+    // Default ports are left out.
+    let resp = http_port_request("GET / HTTP/1.1\r\nHost: WWW.x.test:80\r\n\r\n");
+    assert!(resp.contains("\r\nLocation: http://x.test/\r\n"), "{resp}");
+    // Without serve_http, to https_port.
+    let resp = http_port_request("GET /a HTTP/1.1\r\nHost: php.test:8000\r\n\r\n");
+    assert!(resp.contains("\r\nLocation: https://php.test:4430/a\r\n"), "{resp}");
+}
+
+#[test]
+fn checks_ws_handshake_before_connecting() {
+    let upgrade = "GET /ws HTTP/1.1\r\nHost: ws.test\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n";
+    // The app isn't running, these are answered without it.
+    let resp = request(&format!("{upgrade}Sec-WebSocket-Version: 13\r\n\r\n"));
+    assert!(resp.starts_with("HTTP/1.1 400"), "{resp}");
+    let resp = request(&format!("{upgrade}Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"));
+    assert!(resp.starts_with("HTTP/1.1 426"), "{resp}");
+    assert!(resp.contains("\r\nSec-WebSocket-Version: 13\r\n"), "{resp}");
+    let resp = request(&format!("{upgrade}Sec-WebSocket-Version: 13\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"));
+    assert!(resp.starts_with("HTTP/1.1 502"), "{resp}");
+}
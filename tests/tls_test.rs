@@ -0,0 +1,508 @@
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use async_native_tls::{Identity, TlsConnector};
+use async_net::{TcpListener, TcpStream};
+use futures_lite::future;
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
+use openssl::x509::extension::{BasicConstraints, ExtendedKeyUsage, SubjectAlternativeName};
+use openssl::x509::{X509, X509NameBuilder};
+use miarh::conf::{ClientAuthConf, Conf, TlsConf};
+use miarh::tls::{self, ClientAuthMode, ClientCert, Sni, TlsError};
+
+
+fn ec_key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+fn self_signed(key: &PKey<Private>, cn: &str) -> X509 {
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", cn).unwrap();
+    let name = name.build();
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(30).unwrap()).unwrap();
+    cert.sign(key, MessageDigest::sha256()).unwrap();
+    cert.build()
+}
+
+fn tmp_file(name: &str, data: &[u8]) -> String {
+    let path: PathBuf = std::env::temp_dir()
+        .join(format!("miarh-tls-test-{}-{name}", std::process::id()));
+    std::fs::write(&path, data).unwrap();
+    path.display().to_string()
+}
+
+fn load_err(conf: &TlsConf) -> TlsError {
+    match tls::load_identity(conf) {
+        Ok(_) => panic!("loaded"),
+        Err(e) => e,
+    }
+}
+
+fn pem_conf(cert_path: &str, key_path: &str) -> TlsConf {
+    TlsConf {
+        cert_path: cert_path.to_string(),
+        key_path: key_path.to_string(),
+        ..TlsConf::default()
+    }
+}
+
+#[test]
+fn loads_pem_chain_and_key() {
+    let key = ec_key();
+    let cert_path = tmp_file("cert.pem", &self_signed(&key, "example.com").to_pem().unwrap());
+    let key_path = tmp_file("key.pem", &key.private_key_to_pem_pkcs8().unwrap());
+    assert!(tls::acceptor(&pem_conf(&cert_path, &key_path), &TlsConf::default()).is_ok());
+
+    // Traditional "BEGIN RSA PRIVATE KEY" keys are converted.
+    let rsa = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let rsa_cert_path = tmp_file("rsa-cert.pem", &self_signed(&rsa, "example.com").to_pem().unwrap());
+    let rsa_key_path = tmp_file("rsa-key.pem", &rsa.rsa().unwrap().private_key_to_pem().unwrap());
+    assert!(tls::acceptor(&pem_conf(&rsa_cert_path, &rsa_key_path), &TlsConf::default()).is_ok());
+
+    let e = load_err(&pem_conf(&rsa_cert_path, &key_path));
+    assert!(matches!(e, TlsError::KeyMismatch(_)), "{e}");
+    let e = load_err(&pem_conf(&key_path, &key_path));
+    assert!(matches!(e, TlsError::Pem(_, _)), "{e}");
+    let e = load_err(&pem_conf("/nonexistent/cert.pem", &key_path));
+    assert_eq!("TLS file not found: /nonexistent/cert.pem", e.to_string());
+    let e = load_err(&pem_conf(&cert_path, ""));
+    assert!(matches!(e, TlsError::Pem(_, _)), "{e}");
+
+    for path in [cert_path, key_path, rsa_cert_path, rsa_key_path] {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[test]
+fn loads_pkcs12_with_password() {
+    let key = ec_key();
+    let cert = self_signed(&key, "example.com");
+    let pkcs12 = Pkcs12::builder().name("miarh").pkey(&key).cert(&cert)
+        .build2("secret").unwrap();
+    let path = tmp_file("identity.pfx", &pkcs12.to_der().unwrap());
+    let conf = |password: &str| TlsConf {
+        pkcs12_path: path.to_string(),
+        pkcs12_password: password.to_string(),
+        ..TlsConf::default()
+    };
+    assert!(tls::acceptor(&conf("secret"), &TlsConf::default()).is_ok());
+    let e = load_err(&conf("wrong"));
+    assert!(e.to_string().contains("wrong password"), "{e}");
+
+    let not_pkcs12 = tmp_file("not.pfx", b"hello");
+    let e = load_err(&TlsConf { pkcs12_path: not_pkcs12.to_string(), ..conf("") });
+    assert!(e.to_string().contains("not a PKCS#12 file"), "{e}");
+    let e = load_err(&TlsConf { pkcs12_path: "".to_string(), ..conf("") });
+    assert!(matches!(e, TlsError::NotConfigured));
+
+    let _ = std::fs::remove_file(path);
+    let _ = std::fs::remove_file(not_pkcs12);
+}
+
+/// Collects what a client writes, the reads never complete.
+struct Wire(Vec<u8>);
+
+impl Read for Wire {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+impl Write for Wire {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn client_hello(hostname: Option<&str>) -> Vec<u8> {
+    let mut conf = SslConnector::builder(SslMethod::tls()).unwrap().build().configure().unwrap();
+    conf.set_use_server_name_indication(hostname.is_some());
+    conf.set_verify_hostname(false);
+    let ssl = conf.into_ssl(hostname.unwrap_or("")).unwrap();
+    let mut stream = SslStream::new(ssl, Wire(vec![])).unwrap();
+    assert!(stream.connect().is_err());
+    stream.get_ref().0.clone()
+}
+
+#[test]
+fn parses_sni() {
+    let hello = client_hello(Some("WWW.Example.com"));
+    assert_eq!(Sni::Name("www.example.com".to_string()), tls::parse_sni(&hello));
+    assert_eq!(Sni::Incomplete, tls::parse_sni(&hello[..hello.len() - 1]));
+    assert_eq!(Sni::Incomplete, tls::parse_sni(&hello[..3]));
+    // The same handshake message over two records.
+    let msg = &hello[5..];
+    let mut split = vec![];
+    for part in [&msg[..100], &msg[100..]] {
+        split.extend_from_slice(&[22, 3, 1]);
+        split.extend_from_slice(&(part.len() as u16).to_be_bytes());
+        split.extend_from_slice(part);
+    }
+    assert_eq!(Sni::Incomplete, tls::parse_sni(&split[..200]));
+    assert_eq!(Sni::Name("www.example.com".to_string()), tls::parse_sni(&split));
+    assert_eq!(Sni::Missing, tls::parse_sni(&client_hello(None)));
+    assert_eq!(Sni::Missing, tls::parse_sni(b"GET / HTTP/1.1\r\n\r\n"));
+}
+
+fn write_cert(name: &str, cn: &str) -> (String, String) {
+    let key = ec_key();
+    let cert_path = tmp_file(&format!("{name}.crt"), &self_signed(&key, cn).to_pem().unwrap());
+    let key_path = tmp_file(&format!("{name}.key"), &key.private_key_to_pem_pkcs8().unwrap());
+    (cert_path, key_path)
+}
+
+/// Files are prefixed with `test`, tests rewriting them run in parallel.
+fn sni_conf(test: &str, reject_unknown: bool) -> Conf {
+    let (default_cert, default_key) = write_cert(&format!("{test}-default"), "default");
+    let (a_cert, a_key) = write_cert(&format!("{test}-a"), "a.example");
+    toml::from_str(&format!(r#"
+        ip = "127.0.0.1"
+        https_port = 4430
+        http_port = 8000
+        acme_challenge_dir = ""
+        acme_challenge_url = ""
+        index_url = "/"
+        tmp_dir = "/tmp"
+        max_request_size_mb = 10
+        [tls]
+        cert_path = "{default_cert}"
+        key_path = "{default_key}"
+        reject_unknown_hostnames = {reject_unknown}
+        [[servers]]
+        name = "a"
+        hostnames = ["a.example", "www.a.example"]
+        static_dir = ""
+        dev_static_dir = ""
+        index_path = ""
+        [servers.tls]
+        cert_path = "{a_cert}"
+        key_path = "{a_key}"
+        [[servers]]
+        name = "b"
+        hostnames = ["b.example"]
+        static_dir = ""
+        dev_static_dir = ""
+        index_path = ""
+    "#)).unwrap()
+}
+
+/// CN of the certificate the server answers `hostname` with, None if the
+/// handshake fails.
+async fn handshake_cn(acceptors: &tls::Acceptors, hostname: &str) -> Option<String> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = async {
+        let (stream, _) = listener.accept().await.unwrap();
+        let sni = tls::peek_sni(&stream).await;
+        match acceptors.select(sni.as_deref()) {
+            Some(v) => { let _ = v.accept(stream).await; },
+            None => tls::refuse(stream).await,
+        }
+    };
+    let client = async {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let connector = TlsConnector::new().danger_accept_invalid_certs(true);
+        let tls_stream = connector.connect(hostname, stream).await.ok()?;
+        let cert = tls_stream.peer_certificate().unwrap().unwrap();
+        let cert = X509::from_der(&cert.to_der().unwrap()).unwrap();
+        let cn = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next().unwrap();
+        Some(cn.data().as_utf8().unwrap().to_string())
+    };
+    future::zip(server, client).await.1
+}
+
+#[test]
+fn picks_certificate_by_sni() {
+    future::block_on(async {
+        let acceptors = tls::Acceptors::from_conf(&sni_conf("sni", false)).unwrap();
+        assert_eq!(Some("a.example"), handshake_cn(&acceptors, "www.a.example").await.as_deref());
+        assert_eq!(Some("default"), handshake_cn(&acceptors, "b.example").await.as_deref());
+        assert_eq!(Some("default"), handshake_cn(&acceptors, "unknown.example").await.as_deref());
+        let acceptors = tls::Acceptors::from_conf(&sni_conf("sni", true)).unwrap();
+        assert_eq!(Some("a.example"), handshake_cn(&acceptors, "a.example").await.as_deref());
+        assert_eq!(Some("default"), handshake_cn(&acceptors, "b.example").await.as_deref());
+        assert_eq!(None, handshake_cn(&acceptors, "unknown.example").await);
+    });
+    // A server without its own certificate needs the default one.
+    let mut conf = sni_conf("sni", false);
+    conf.tls = TlsConf { pkcs12_path: String::new(), ..TlsConf::default() };
+    match tls::Acceptors::from_conf(&conf) {
+        Err(TlsError::Server(name, e)) => {
+            assert_eq!("b", name);
+            assert!(matches!(*e, TlsError::NotConfigured));
+        },
+        _ => panic!("loaded"),
+    }
+}
+
+#[test]
+fn swaps_acceptors_on_reload() {
+    future::block_on(async {
+        let conf = sni_conf("reload", false);
+        tls::install(tls::Acceptors::from_conf(&conf).unwrap()).await;
+        let old = tls::current().await.unwrap();
+        let mut files = tls::TlsFiles::new(&conf);
+        assert!(!files.changed());
+        // A renewal rewrites the default certificate in place.
+        let key = ec_key();
+        std::fs::write(&conf.tls.cert_path, self_signed(&key, "renewed.example").to_pem().unwrap()).unwrap();
+        std::fs::write(&conf.tls.key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        assert!(files.changed());
+        assert!(!files.changed());
+        tls::install(tls::Acceptors::from_conf(&conf).unwrap()).await;
+        let new = tls::current().await.unwrap();
+        assert_eq!(Some("renewed.example"), handshake_cn(&new, "b.example").await.as_deref());
+        assert_eq!(Some("a.example"), handshake_cn(&new, "a.example").await.as_deref());
+        // Acceptors taken before the swap keep working.
+        assert_eq!(Some("default"), handshake_cn(&old, "b.example").await.as_deref());
+    });
+}
+
+fn acceptor_err(conf: &TlsConf, opts: &TlsConf) -> TlsError {
+    match tls::acceptor(conf, opts) {
+        Ok(_) => panic!("accepted"),
+        Err(e) => e,
+    }
+}
+
+#[test]
+fn checks_handshake_settings() {
+    let opts: TlsConf = toml::from_str(r#"
+        backend = "rustls"
+        min_version = "1.3"
+        cipher_suites = ["TLS13_AES_256_GCM_SHA384"]
+        alpn = ["http/1.1"]
+        session_tickets = false
+    "#).unwrap();
+    assert_eq!(tls::TlsBackend::Rustls, opts.backend);
+    assert_eq!(tls::TlsVersion::Tls13, opts.min_version);
+    assert!(toml::from_str::<TlsConf>("min_version = \"1.1\"").is_err());
+    let defaults = TlsConf::default();
+    assert_eq!(tls::TlsBackend::Native, defaults.backend);
+    assert_eq!(vec!["http/1.1".to_string()], defaults.alpn);
+    assert!(defaults.session_tickets);
+
+    let key = ec_key();
+    let cert_path = tmp_file("settings-cert.pem", &self_signed(&key, "example.com").to_pem().unwrap());
+    let key_path = tmp_file("settings-key.pem", &key.private_key_to_pem_pkcs8().unwrap());
+    let conf = pem_conf(&cert_path, &key_path);
+    // No h2 yet, on either backend.
+    for backend in [tls::TlsBackend::Native, tls::TlsBackend::Rustls] {
+        let h2 = TlsConf { backend, alpn: vec!["h2".to_string(), "http/1.1".to_string()], ..opts.clone() };
+        let e = acceptor_err(&conf, &h2);
+        assert!(e.to_string().contains("ALPN protocol h2"), "{e}");
+    }
+    // native-tls has no say over suites, TLS 1.3 only, ALPN, tickets or key logs.
+    let native = |opts: TlsConf| TlsConf { backend: tls::TlsBackend::Native, ..opts };
+    let e = acceptor_err(&conf, &native(TlsConf { min_version: tls::TlsVersion::Tls13, ..TlsConf::default() }));
+    assert!(matches!(e, TlsError::Options(_)), "{e}");
+    let e = acceptor_err(&conf, &native(TlsConf { cipher_suites: opts.cipher_suites.clone(), ..TlsConf::default() }));
+    assert!(matches!(e, TlsError::Options(_)), "{e}");
+    let e = acceptor_err(&conf, &native(TlsConf { key_log: true, ..TlsConf::default() }));
+    assert!(matches!(e, TlsError::Options(_)), "{e}");
+    let e = acceptor_err(&conf, &native(TlsConf { alpn: vec![], ..TlsConf::default() }));
+    assert!(matches!(e, TlsError::Options(_)), "{e}");
+    let e = acceptor_err(&conf, &native(TlsConf { session_tickets: false, ..TlsConf::default() }));
+    assert!(matches!(e, TlsError::Options(_)), "{e}");
+    assert!(tls::acceptor(&conf, &native(TlsConf::default())).is_ok());
+
+    #[cfg(not(feature = "rustls"))]
+    {
+        let e = acceptor_err(&conf, &opts);
+        assert!(matches!(e, TlsError::Options(_)), "{e}");
+    }
+    #[cfg(feature = "rustls")]
+    {
+        assert!(tls::acceptor(&conf, &opts).is_ok());
+        let unknown = TlsConf { cipher_suites: vec!["TLS_NULL_WITH_NULL_NULL".to_string()], ..opts.clone() };
+        let e = acceptor_err(&conf, &unknown);
+        assert!(e.to_string().contains("TLS_NULL_WITH_NULL_NULL"), "{e}");
+        // Only TLS 1.3 with only a TLS 1.2 suite leaves nothing to negotiate.
+        let tls12 = TlsConf {
+            min_version: tls::TlsVersion::Tls13,
+            cipher_suites: vec!["TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256".to_string()],
+            ..opts.clone()
+        };
+        let e = acceptor_err(&conf, &tls12);
+        assert!(matches!(e, TlsError::Options(_)), "{e}");
+    }
+
+    let _ = std::fs::remove_file(cert_path);
+    let _ = std::fs::remove_file(key_path);
+}
+
+fn ca(cn: &str) -> (PKey<Private>, X509) {
+    let key = ec_key();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", cn).unwrap();
+    let name = name.build();
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(30).unwrap()).unwrap();
+    cert.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+    (key, cert.build())
+}
+
+/// Client certificate of `ca` with the subject entries in order, most
+/// significant first.
+fn issue(ca: &(PKey<Private>, X509), subject: &[(&str, &str)], email: &str) -> Identity {
+    let key = ec_key();
+    let mut name = X509NameBuilder::new().unwrap();
+    for (field, value) in subject {
+        name.append_entry_by_text(field, value).unwrap();
+    }
+    let name = name.build();
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_serial_number(&BigNum::from_u32(2).unwrap().to_asn1_integer().unwrap()).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(ca.1.subject_name()).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(30).unwrap()).unwrap();
+    cert.append_extension(ExtendedKeyUsage::new().client_auth().build().unwrap()).unwrap();
+    let san = SubjectAlternativeName::new().email(email)
+        .build(&cert.x509v3_context(Some(&ca.1), None)).unwrap();
+    cert.append_extension(san).unwrap();
+    cert.sign(&ca.0, MessageDigest::sha256()).unwrap();
+    let cert = cert.build();
+    Identity::from_pkcs8(&cert.to_pem().unwrap(), &key.private_key_to_pem_pkcs8().unwrap()).unwrap()
+}
+
+/// Server side of a handshake for `hostname`: the verified client
+/// certificate, an error if the handshake fails.
+async fn handshake_client_cert(acceptors: &tls::Acceptors, hostname: &str, identity: Option<Identity>
+                               ) -> Result<Option<ClientCert>, String> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = async {
+        let (stream, _) = listener.accept().await.unwrap();
+        let sni = tls::peek_sni(&stream).await;
+        let acceptor = acceptors.select(sni.as_deref()).unwrap();
+        acceptor.accept(stream).await.map(|v| v.client_cert()).map_err(|e| e.to_string())
+    };
+    let client = async {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut connector = TlsConnector::new().danger_accept_invalid_certs(true);
+        if let Some(identity) = identity {
+            connector = connector.identity(identity);
+        }
+        let _ = connector.connect(hostname, stream).await;
+    };
+    future::zip(server, client).await.0
+}
+
+#[test]
+fn verifies_client_certificates() {
+    let ca = ca("Clients CA");
+    let ca_path = tmp_file("clients-ca.pem", &ca.1.to_pem().unwrap());
+    let alice = || issue(&ca, &[("O", "Example"), ("OU", "ops"), ("CN", "alice")], "alice@example.com");
+    let mallory = || issue(&self::ca("Other CA"), &[("CN", "alice")], "alice@example.com");
+    let mut conf = sni_conf("client-auth", false);
+    conf.servers[0].client_auth = Some(ClientAuthConf {
+        ca_path: ca_path.to_string(),
+        mode: ClientAuthMode::Required,
+        allowed_subjects: vec![],
+    });
+    future::block_on(async {
+        let acceptors = tls::Acceptors::from_conf(&conf).unwrap();
+        let cert = handshake_client_cert(&acceptors, "a.example", Some(alice())).await.unwrap().unwrap();
+        assert_eq!("CN=alice,OU=ops,O=Example", cert.subject);
+        assert_eq!(vec!["email:alice@example.com".to_string()], cert.san);
+        assert_eq!(64, cert.fingerprint.len());
+        assert!(handshake_client_cert(&acceptors, "a.example", None).await.is_err());
+        assert!(handshake_client_cert(&acceptors, "a.example", Some(mallory())).await.is_err());
+        // Servers without client auth don't ask for certificates.
+        assert_eq!(Ok(None), handshake_client_cert(&acceptors, "b.example", Some(alice())).await);
+
+        conf.servers[0].client_auth.as_mut().unwrap().mode = ClientAuthMode::Optional;
+        let acceptors = tls::Acceptors::from_conf(&conf).unwrap();
+        assert_eq!(Ok(None), handshake_client_cert(&acceptors, "a.example", None).await);
+        let cert = handshake_client_cert(&acceptors, "a.example", Some(alice())).await.unwrap();
+        assert_eq!("CN=alice,OU=ops,O=Example", cert.unwrap().subject);
+        assert!(handshake_client_cert(&acceptors, "a.example", Some(mallory())).await.is_err());
+    });
+
+    conf.servers[0].client_auth.as_mut().unwrap().ca_path = "/nonexistent/ca.pem".to_string();
+    match tls::Acceptors::from_conf(&conf) {
+        Err(TlsError::Server(name, e)) => {
+            assert_eq!("a", name);
+            assert!(matches!(*e, TlsError::Read(_, _)), "{e}");
+        },
+        _ => panic!("loaded"),
+    }
+    let _ = std::fs::remove_file(ca_path);
+}
+
+#[test]
+fn allows_client_subjects() {
+    let cert = |subject: &str| ClientCert {
+        subject: subject.to_string(),
+        san: vec![],
+        fingerprint: String::new(),
+    };
+    let auth = |mode: ClientAuthMode, allowed_subjects: &[&str]| ClientAuthConf {
+        ca_path: String::new(),
+        mode,
+        allowed_subjects: allowed_subjects.iter().map(|v| v.to_string()).collect(),
+    };
+    let alice = cert("CN=alice,OU=ops,O=Example");
+    let bob = cert("CN=bob,OU=dev,O=Example");
+    let ops = auth(ClientAuthMode::Required, &["CN=*,OU=ops,O=Example"]);
+    assert!(tls::client_allowed(Some(&alice), &ops));
+    assert!(!tls::client_allowed(Some(&bob), &ops));
+    assert!(!tls::client_allowed(None, &ops));
+    assert!(tls::client_allowed(None, &auth(ClientAuthMode::Optional, &["CN=*,OU=ops,O=Example"])));
+    assert!(tls::client_allowed(Some(&bob), &auth(ClientAuthMode::Required, &[])));
+    for (pattern, is_allowed) in [
+        ("CN=alice,OU=ops,O=Example", true),
+        ("CN=*,OU=*,O=Example", true),
+        ("CN=a*e,OU=ops,O=Exam*", true),
+        ("CN=alice", false),
+        ("CN=*,OU=dev,O=Example", false),
+        // Within one value only.
+        ("CN=alice,*", false),
+        ("*", false),
+    ] {
+        assert_eq!(is_allowed, tls::client_allowed(Some(&alice), &auth(ClientAuthMode::Required, &[pattern])),
+                   "{pattern}");
+    }
+
+    // Separators in values are escaped, they can't fake other parts.
+    let key = ec_key();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("O", "Example").unwrap();
+    name.append_entry_by_text("CN", "eve,OU=ops").unwrap();
+    let mut x509 = X509::builder().unwrap();
+    x509.set_subject_name(&name.build()).unwrap();
+    x509.set_pubkey(&key).unwrap();
+    x509.sign(&key, MessageDigest::sha256()).unwrap();
+    let eve = ClientCert::from_x509(&x509.build());
+    assert_eq!("CN=eve\\,OU=ops,O=Example", eve.subject);
+    assert!(!tls::client_allowed(Some(&eve), &ops));
+}
@@ -0,0 +1,110 @@
+use std::time::{Duration, Instant};
+use async_net::unix::UnixStream;
+use futures_lite::{future, AsyncReadExt, AsyncWriteExt};
+use miarh::app_proto::{self, FrameType};
+use miarh::tunnel;
+
+
+#[test]
+fn copies_both_ways_until_closed() {
+    future::block_on(async {
+        let (mut client, client_end) = UnixStream::pair().unwrap();
+        let (backend_end, mut backend) = UnixStream::pair().unwrap();
+        let piping = tunnel::pipe(client_end, backend_end, Duration::from_secs(5));
+        let talking = async move {
+            let mut buf = [0; 5];
+            client.write_all(b"hello").await.unwrap();
+            backend.read_exact(&mut buf).await.unwrap();
+            assert_eq!(b"hello", &buf);
+            backend.write_all(b"world").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(b"world", &buf);
+            drop(backend);
+            assert_eq!(0, client.read(&mut buf).await.unwrap());
+            // Done once the client closes too.
+            drop(client);
+        };
+        let (r, _) = future::zip(piping, talking).await;
+        assert!(r.is_ok());
+    });
+}
+
+#[test]
+fn keeps_half_closed_tunnels() {
+    future::block_on(async {
+        let (mut client, client_end) = UnixStream::pair().unwrap();
+        let (backend_end, mut backend) = UnixStream::pair().unwrap();
+        let piping = tunnel::pipe(client_end, backend_end, Duration::from_secs(5));
+        let talking = async {
+            client.write_all(b"hello").await.unwrap();
+            client.close().await.unwrap();
+            let mut req = vec![];
+            backend.read_to_end(&mut req).await.unwrap();
+            assert_eq!(b"hello".to_vec(), req);
+            backend.write_all(b"world").await.unwrap();
+            drop(backend);
+            let mut resp = vec![];
+            client.read_to_end(&mut resp).await.unwrap();
+            assert_eq!(b"world".to_vec(), resp);
+        };
+        let (r, _) = future::zip(piping, talking).await;
+        assert!(r.is_ok());
+    });
+}
+
+#[test]
+fn closes_idle_tunnels() {
+    future::block_on(async {
+        let (mut client, client_end) = UnixStream::pair().unwrap();
+        let (backend_end, mut backend) = UnixStream::pair().unwrap();
+        let started = Instant::now();
+        let piping = tunnel::pipe(client_end, backend_end, Duration::from_millis(300));
+        // Traffic in one direction keeps the other one open too.
+        let talking = async {
+            let mut buf = [0; 1];
+            for _ in 0..3 {
+                async_io::Timer::after(Duration::from_millis(200)).await;
+                client.write_all(b"x").await.unwrap();
+                backend.read_exact(&mut buf).await.unwrap();
+            }
+        };
+        let (r, _) = future::zip(piping, talking).await;
+        assert_eq!(std::io::ErrorKind::TimedOut, r.unwrap_err().kind());
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    });
+}
+
+#[test]
+fn limits_open_tunnels() {
+    future::block_on(async {
+        let a = tunnel::acquire("tunnel-test", 2).await.unwrap();
+        let b = tunnel::acquire("tunnel-test", 2).await.unwrap();
+        assert!(tunnel::acquire("tunnel-test", 2).await.is_none());
+        assert_eq!(2, tunnel::open_count("tunnel-test").await);
+        drop(a);
+        let c = tunnel::acquire("tunnel-test", 2).await;
+        assert!(c.is_some());
+        drop((b, c));
+        assert_eq!(0, tunnel::open_count("tunnel-test").await);
+    });
+}
+
+#[test]
+fn upgrade_leaves_app_protocol() {
+    future::block_on(async {
+        let (mut miarh, mut app) = UnixStream::pair().unwrap();
+        let head = b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+        app_proto::send_upgrade(&mut miarh, head).await.unwrap();
+        let frame = app_proto::read_frame(&mut app).await.unwrap();
+        assert_eq!(FrameType::Upgrade, frame.kind);
+        assert_eq!(head.to_vec(), frame.payload);
+        assert_eq!(FrameType::End, app_proto::read_frame(&mut app).await.unwrap().kind);
+        let resp = b"HTTP/1.1 101 Switching Protocols\r\n\r\n";
+        app_proto::send_response(&mut app, resp).await.unwrap();
+        app.write_all(b"raw").await.unwrap();
+        assert_eq!(resp.to_vec(), app_proto::read_response(&mut miarh).await.unwrap());
+        let mut buf = [0; 3];
+        miarh.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"raw", &buf);
+    });
+}
@@ -0,0 +1,65 @@
+use miarh::multipart::MultipartError;
+use miarh::upload::{detected_type, sanitize_filename, sniff_mimetype, UploadRules, MAX_FILENAME_LEN};
+
+
+#[test]
+fn sanitize() {
+    assert_eq!("passwd", sanitize_filename("../../etc/passwd"));
+    assert_eq!("evil.exe", sanitize_filename("C:\\Users\\me\\evil.exe"));
+    assert_eq!("ab.txt", sanitize_filename("a\u{0}b\r\n.txt"));
+    assert_eq!("invoicefdp.exe", sanitize_filename("invoice\u{202E}fdp.exe"));
+    assert_eq!("htaccess", sanitize_filename(".htaccess"));
+    assert_eq!("file", sanitize_filename(".."));
+    assert_eq!("é.txt", sanitize_filename("e\u{301}.txt"));
+    let long = format!("{}.jpeg", "я".repeat(300));
+    let short = sanitize_filename(&long);
+    assert!(short.len() <= MAX_FILENAME_LEN);
+    assert!(short.ends_with("я.jpeg"));
+}
+
+#[test]
+fn allow_lists() {
+    let rules = UploadRules {
+        allowed_extensions: vec![".jpg".to_string(), "png".to_string()],
+        allowed_mime_types: vec!["image/jpeg".to_string(), "image/png".to_string()],
+    };
+    assert!(rules.check_declared("a.PNG", "image/png").is_ok());
+    assert!(matches!(rules.check_declared("a.gif", "image/png"),
+                     Err(MultipartError::UnsupportedMediaType(_))));
+    assert!(matches!(rules.check_declared("a.png", "image/gif"),
+                     Err(MultipartError::UnsupportedMediaType(_))));
+}
+
+#[test]
+fn magic_bytes() {
+    let png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR";
+    assert_eq!(Some("image/png"), sniff_mimetype(png));
+    assert_eq!(None, sniff_mimetype(b"hello"));
+    let rules = UploadRules::default();
+    assert!(rules.check_content("a.png", "image/png", png).is_ok());
+    assert!(rules.check_content("a.txt", "text/plain", b"hello").is_ok());
+    assert!(rules.check_content("a.docx", "application/octet-stream", b"PK\x03\x04").is_ok());
+    assert!(matches!(rules.check_content("a.jpg", "image/jpeg", b"MZ\x90\x00"),
+                     Err(MultipartError::ContentMismatch(_))));
+    assert!(matches!(rules.check_content("a.bin", "image/jpeg", png),
+                     Err(MultipartError::ContentMismatch(_))));
+}
+
+#[test]
+fn undeclared_type_is_sniffed() {
+    let rules = UploadRules {
+        allowed_extensions: vec![],
+        allowed_mime_types: vec!["image/png".to_string(), "application/pdf".to_string()],
+    };
+    let png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR";
+    assert!(rules.check_declared("a.png", "").is_ok());
+    assert!(rules.check_content("a.png", "", png).is_ok());
+    // No magic bytes, typed by the extension.
+    assert!(rules.check_content("a.pdf", "", b"").is_ok());
+    assert!(matches!(rules.check_content("a.png", "", b"MZ\x90\x00"),
+                     Err(MultipartError::UnsupportedMediaType(_))));
+    assert!(matches!(rules.check_content("a.txt", "", b"hello"),
+                     Err(MultipartError::UnsupportedMediaType(_))));
+    assert_eq!("image/png", detected_type("a.bin", png));
+    assert_eq!("application/octet-stream", detected_type("a", b"hello"));
+}
@@ -0,0 +1,136 @@
+use std::sync::Arc;
+use std::time::Duration;
+use async_net::TcpListener;
+use futures_lite::future;
+use miarh::app_pool::{AppPool, PoolConf};
+use miarh::app_proto;
+use miarh::health::HealthConf;
+use miarh::upstream::{Balance, UpstreamAddr, UpstreamGroup};
+
+
+fn pool(addr: &str) -> Arc<AppPool> {
+    Arc::new(AppPool::new(PoolConf {
+        addr: UpstreamAddr::parse(addr),
+        size: 4,
+        max_idle: Duration::from_secs(60),
+        queue_limit: 4,
+    }))
+}
+
+fn group(balance: Balance, n: usize, fail_timeout: Duration) -> UpstreamGroup {
+    let pools = (0..n).map(|i| pool(&format!("127.0.0.1:{}", 9000 + i))).collect();
+    let health = HealthConf {
+        max_fails: 2,
+        fail_timeout,
+        check_interval: Duration::ZERO,
+        check_timeout: Duration::from_secs(1),
+        check_path: "".to_string(),
+        check_host: "".to_string(),
+    };
+    UpstreamGroup::new(balance, health, pools)
+}
+
+#[test]
+fn parses_addresses() {
+    assert_eq!(UpstreamAddr::Unix("/a.sock".to_string()), UpstreamAddr::parse("/a.sock"));
+    assert_eq!(UpstreamAddr::Unix("a.sock".to_string()), UpstreamAddr::parse("unix:a.sock"));
+    assert_eq!(UpstreamAddr::Tcp("app:9000".to_string()), UpstreamAddr::parse("tcp:app:9000"));
+    assert_eq!(UpstreamAddr::Tcp("127.0.0.1:9000".to_string()),
+               UpstreamAddr::parse("127.0.0.1:9000"));
+}
+
+#[test]
+fn round_robin() {
+    let group = group(Balance::RoundRobin, 3, Duration::from_secs(60));
+    let picked: Vec<usize> = (0..6)
+        .map(|_| group.pick("", &[]).unwrap().upstream.idx).collect();
+    assert_eq!(vec![0, 1, 2, 0, 1, 2], picked);
+    assert_eq!(2, group.pick("", &[0, 1]).unwrap().upstream.idx);
+    assert!(group.pick("", &[0, 1, 2]).is_none());
+}
+
+#[test]
+fn least_conn() {
+    let group = group(Balance::LeastConn, 3, Duration::from_secs(60));
+    let a = group.pick("", &[]).unwrap();
+    let b = group.pick("", &[]).unwrap();
+    assert_ne!(a.upstream.idx, b.upstream.idx);
+    let c = group.pick("", &[]).unwrap();
+    drop(b);
+    let busy = [a.upstream.idx, c.upstream.idx];
+    for _ in 0..3 {
+        let d = group.pick("", &[]).unwrap();
+        assert!(!busy.contains(&d.upstream.idx));
+    }
+    assert_eq!(1, a.upstream.active());
+}
+
+#[test]
+fn hash_is_sticky() {
+    let group = group(Balance::Hash, 4, Duration::from_secs(60));
+    let mut counts = [0; 4];
+    for i in 0..400 {
+        let session_id = format!("session-{i}");
+        let idx = group.pick(&session_id, &[]).unwrap().upstream.idx;
+        assert_eq!(idx, group.pick(&session_id, &[]).unwrap().upstream.idx);
+        counts[idx] += 1;
+    }
+    assert!(counts.iter().all(|v| *v > 40), "{counts:?}");
+
+    // Ejecting an upstream moves only its own sessions.
+    let before: Vec<usize> = (0..100)
+        .map(|i| group.pick(&format!("s{i}"), &[]).unwrap().upstream.idx).collect();
+    let ejected = Arc::clone(&group.upstreams[1]);
+    group.failed(&ejected);
+    group.failed(&ejected);
+    for (i, idx) in before.iter().enumerate() {
+        let now = group.pick(&format!("s{i}"), &[]).unwrap().upstream.idx;
+        if *idx == 1 { assert_ne!(1, now) } else { assert_eq!(*idx, now) }
+    }
+}
+
+#[test]
+fn ejects_failing_upstream_for_cooldown() {
+    let group = group(Balance::RoundRobin, 2, Duration::from_millis(100));
+    let upstream = Arc::clone(&group.upstreams[0]);
+    group.failed(&upstream);
+    group.succeeded(&upstream);
+    group.failed(&upstream);
+    assert!(upstream.is_available());
+    group.failed(&upstream);
+    assert!(!upstream.is_available());
+    for _ in 0..4 {
+        assert_eq!(1, group.pick("", &[]).unwrap().upstream.idx);
+    }
+    assert!(group.pick("", &[1]).is_none());
+    std::thread::sleep(Duration::from_millis(150));
+    assert!(upstream.is_available());
+}
+
+#[test]
+fn connects_over_tcp() {
+    future::block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || future::block_on(async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            app_proto::accept_handshake(&mut stream).await.unwrap();
+            let (req, _) = app_proto::read_request(&mut stream).await.unwrap();
+            app_proto::send_response(&mut stream, req.path.as_bytes()).await.unwrap();
+        }));
+        let pool = pool(&format!("tcp:{addr}"));
+        let mut conn = pool.get().await.unwrap();
+        let req = miarh_saras_http::Request {
+            method: "get".to_string(),
+            host: "localhost".to_string(),
+            path: "/tcp".to_string(),
+            session_id: "".to_string(),
+            query: Default::default(),
+            body_string: "".to_string(),
+            route: Default::default(),
+            files: Default::default(),
+        };
+        app_proto::send_request(&mut conn.stream, &req, b"").await.unwrap();
+        assert_eq!(b"/tcp".to_vec(), app_proto::read_response(&mut conn.stream).await.unwrap());
+    });
+}
@@ -0,0 +1,28 @@
+use futures_lite::future;
+use miarh::headers::parse_headers;
+use miarh::urlencoded::{decode, parse_urlencoded};
+
+
+#[test]
+fn decode_percent_and_plus() {
+    assert_eq!("a b/c", decode("a+b%2Fc"));
+    assert_eq!("привет", decode("%D0%BF%D1%80%D0%B8%D0%B2%D0%B5%D1%82"));
+    assert_eq!("100%", decode("100%"));
+    assert_eq!("%zz", decode("%zz"));
+}
+
+#[test]
+fn body_to_json() {
+    let buf = "POST / HTTP/1.1\r\nHost: example.com\r\n\
+        Content-Type: application/x-www-form-urlencoded\r\n\r\n";
+    let mut hp = parse_headers(&buf.as_bytes().to_vec());
+    hp.body = "name=John+%22Doe%22&age=007&empty=&flag&tag=a&tag=b&tag=c"
+        .as_bytes().to_vec();
+    future::block_on(parse_urlencoded(&mut hp));
+    let v: serde_json::Value = serde_json::from_str(&hp.body_string).unwrap();
+    assert_eq!(v["name"], "John \"Doe\"");
+    assert_eq!(v["age"], "007");
+    assert_eq!(v["empty"], "");
+    assert_eq!(v["flag"], "");
+    assert_eq!(v["tag"], serde_json::json!(["a", "b", "c"]));
+}
@@ -0,0 +1,102 @@
+use async_net::unix::{UnixListener, UnixStream};
+use futures_lite::{future, AsyncReadExt, AsyncWriteExt};
+use miarh::app_resp::{RespOptions, RespProcessor};
+use miarh::uwsgi;
+
+
+/// Vars of a packet without its header.
+fn decode_vars(mut data: &[u8]) -> Option<Vec<(String, String)>> {
+    fn string(data: &mut &[u8]) -> Option<String> {
+        if data.len() < 2 { return None }
+        let len = u16::from_le_bytes([data[0], data[1]]) as usize;
+        let v = data.get(2..2 + len)?;
+        *data = &data[2 + len..];
+        Some(String::from_utf8_lossy(v).to_string())
+    }
+    let mut vars = vec![];
+    while !data.is_empty() {
+        vars.push((string(&mut data)?, string(&mut data)?));
+    }
+    Some(vars)
+}
+
+fn vars() -> Vec<(String, String)> {
+    vec![
+        ("REQUEST_METHOD".to_string(), "POST".to_string()),
+        ("PATH_INFO".to_string(), "/items".to_string()),
+        ("CONTENT_LENGTH".to_string(), "10".to_string()),
+    ]
+}
+
+#[test]
+fn encodes_packet() {
+    let head = uwsgi::request_head(&[("A".to_string(), "bc".to_string())]).unwrap();
+    assert_eq!(vec![0, 7, 0, 0, 1, 0, b'A', 2, 0, b'b', b'c'], head);
+    let head = uwsgi::request_head(&vars()).unwrap();
+    assert_eq!((head.len() - uwsgi::HEADER_LEN) as u16, u16::from_le_bytes([head[1], head[2]]));
+    assert_eq!(Some(vars()), decode_vars(&head[uwsgi::HEADER_LEN..]));
+    assert_eq!(None, decode_vars(&[5, 0, b'a']));
+
+    let large = vec![("A".to_string(), "v".repeat(70000))];
+    assert!(uwsgi::request_head(&large).is_err());
+    let many: Vec<_> = (0..2000).map(|i| (format!("K{i}"), "v".repeat(40))).collect();
+    assert!(uwsgi::request_head(&many).is_err());
+}
+
+// Stand-in uWSGI server: answers with an HTTP response like the uWSGI
+// python plugin.
+fn serve_uwsgi(socket_path: &str) {
+    let _ = std::fs::remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path).unwrap();
+    std::thread::spawn(move || future::block_on(async {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut header = [0; uwsgi::HEADER_LEN];
+        stream.read_exact(&mut header).await.unwrap();
+        assert_eq!(uwsgi::MODIFIER_WSGI, header[0]);
+        let mut vars = vec![0; u16::from_le_bytes([header[1], header[2]]) as usize];
+        stream.read_exact(&mut vars).await.unwrap();
+        let vars = decode_vars(&vars).unwrap();
+        let get = |name: &str| vars.iter().find(|(k, _)| k == name).unwrap().1.to_string();
+        let mut body = vec![0; get("CONTENT_LENGTH").parse().unwrap()];
+        stream.read_exact(&mut body).await.unwrap();
+        let body = format!("{} {} {}", get("REQUEST_METHOD"), get("PATH_INFO"),
+                           String::from_utf8(body).unwrap());
+        let resp = format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n{body}");
+        stream.write_all(resp.as_bytes()).await.unwrap();
+    }));
+}
+
+#[test]
+fn exchange_with_uwsgi_server() {
+    let socket_path = std::env::temp_dir()
+        .join(format!("miarh-uwsgi-test-{}.sock", std::process::id()))
+        .display().to_string();
+    serve_uwsgi(&socket_path);
+    future::block_on(async {
+        let mut stream = UnixStream::connect(&socket_path).await.unwrap();
+        let mut out = uwsgi::request_head(&vars()).unwrap();
+        out.extend_from_slice(b"name=miarh");
+        stream.write_all(&out).await.unwrap();
+
+        let mut resp = RespProcessor::new(RespOptions {
+            is_accept_brotli: false,
+            headers: vec![],
+            location_rewrites: vec![],
+            is_cgi: false,
+            is_head: false,
+        });
+        let mut http = vec![];
+        let mut buf = [0; 1024];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 { break }
+            http.extend(resp.feed(&buf[..n]).unwrap());
+        }
+        http.extend(resp.finish().unwrap());
+        let http = String::from_utf8(http).unwrap();
+        assert!(http.starts_with("HTTP/1.1 200 OK\r\n"), "{http}");
+        assert!(http.contains("\r\nContent-Length: 22\r\n"), "{http}");
+        assert!(http.ends_with("\r\n\r\nPOST /items name=miarh"), "{http}");
+    });
+    let _ = std::fs::remove_file(&socket_path);
+}
@@ -0,0 +1,272 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use async_net::unix::{UnixListener, UnixStream};
+use futures_lite::{future, AsyncReadExt, AsyncWriteExt};
+use miarh::app_pool::{AppError, AppPool, PoolConf};
+use miarh::app_proto::{self, FrameType};
+use miarh::health::HealthConf;
+use miarh::upstream::{AppStream, Balance, UpstreamAddr, UpstreamGroup};
+use miarh::ws::{self, Assembler, Frame, Message, Opcode, WsError};
+use miarh::ws_bridge::{self, Bridge, WsEvent};
+
+
+fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+    match payload.len() {
+        n if n < 126 => frame.push(0x80 | n as u8),
+        n => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(n as u16).to_be_bytes());
+        },
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+}
+
+/// Reads an unmasked server frame.
+async fn server_frame(s: &mut UnixStream) -> (u8, Vec<u8>) {
+    let mut header = [0; 2];
+    s.read_exact(&mut header).await.unwrap();
+    assert_eq!(0, header[1] & 0x80);
+    let mut payload = vec![0; (header[1] & 0x7f) as usize];
+    s.read_exact(&mut payload).await.unwrap();
+    (header[0] & 0x0f, payload)
+}
+
+#[test]
+fn handshake() {
+    assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", ws::accept_key("dGhlIHNhbXBsZSBub25jZQ=="));
+    let resp = ws::handshake_resp(Some("dGhlIHNhbXBsZSBub25jZQ=="), Some("13")).unwrap();
+    let resp = String::from_utf8(resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(resp.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    assert_eq!(426, ws::handshake_resp(Some("dGhlIHNhbXBsZSBub25jZQ=="), Some("8")).unwrap_err().0);
+    assert_eq!(400, ws::handshake_resp(None, Some("13")).unwrap_err().0);
+}
+
+#[test]
+fn reads_frames_and_fragments() {
+    future::block_on(async {
+        let long = vec![7; 300];
+        let mut data = client_frame(false, 1, "héllo, ".as_bytes());
+        data.extend(client_frame(true, 9, b"ping"));
+        data.extend(client_frame(true, 0, b"world"));
+        data.extend(client_frame(true, 2, &long));
+        let mut r = &data[..];
+        let mut assembler = Assembler::new(1024);
+        let frame = ws::read_frame(&mut r, 1024).await.unwrap();
+        assert_eq!(None, assembler.push(frame).unwrap());
+        let ping = ws::read_frame(&mut r, 1024).await.unwrap();
+        assert_eq!(Frame { fin: true, opcode: Opcode::Ping, payload: b"ping".to_vec() }, ping);
+        let frame = ws::read_frame(&mut r, 1024).await.unwrap();
+        assert_eq!(Some(Message::Text("héllo, world".to_string())), assembler.push(frame).unwrap());
+        let frame = ws::read_frame(&mut r, 1024).await.unwrap();
+        assert_eq!(Some(Message::Binary(long)), assembler.push(frame).unwrap());
+
+        let unmasked = [0x81, 0x01, b'a'];
+        assert!(matches!(ws::read_frame(&mut &unmasked[..], 1024).await, Err(WsError::Protocol(_))));
+        let big_ping = client_frame(true, 9, &[0; 126]);
+        assert!(matches!(ws::read_frame(&mut &big_ping[..], 1024).await, Err(WsError::Protocol(_))));
+        let big = client_frame(true, 2, &[0; 300]);
+        let e = ws::read_frame(&mut &big[..], 100).await.unwrap_err();
+        assert_eq!(ws::CLOSE_TOO_BIG, e.close_code());
+    });
+    let mut assembler = Assembler::new(1024);
+    let cont = Frame { fin: true, opcode: Opcode::Continuation, payload: vec![] };
+    assert!(assembler.push(cont).is_err());
+    let bad_text = Frame { fin: true, opcode: Opcode::Text, payload: vec![0xff] };
+    assert_eq!(ws::CLOSE_INVALID_DATA, assembler.push(bad_text).unwrap_err().close_code());
+}
+
+#[test]
+fn close_frames() {
+    assert_eq!(vec![0x88, 2, 0x03, 0xe8], ws::encode_frame(Opcode::Close, &ws::close_payload(1000, "")));
+    assert_eq!(1001, ws::parse_close(&ws::close_payload(1001, "bye")).unwrap());
+    assert_eq!(ws::CLOSE_NO_STATUS, ws::parse_close(&[]).unwrap());
+    assert!(ws::parse_close(&1005u16.to_be_bytes()).is_err());
+    assert!(ws::parse_close(&999u16.to_be_bytes()).is_err());
+    assert!(ws::parse_close(&[3, 232, 0xff]).is_err());
+    assert_eq!(127, ws::encode_frame(Opcode::Binary, &vec![0; 70000])[1]);
+}
+
+#[test]
+fn encodes_events() {
+    let events = [
+        WsEvent::Open { conn_id: 7, head: b"GET /ws HTTP/1.1\r\n\r\n".to_vec() },
+        WsEvent::Message { conn_ids: vec![1, 2, 3], msg: Message::Text("hi".to_string()) },
+        WsEvent::Message { conn_ids: vec![1], msg: Message::Binary(vec![0, 1]) },
+        WsEvent::Close { conn_ids: vec![4, 5], code: 1000 },
+    ];
+    for event in events {
+        let encoded = event.encode();
+        let frame = future::block_on(app_proto::read_frame(&mut &encoded[..])).unwrap();
+        assert_eq!(event, WsEvent::decode(&frame).unwrap());
+    }
+    let frame = app_proto::Frame { kind: FrameType::WsClose, payload: vec![0, 2, 0, 0, 0, 0, 0, 0, 0, 1] };
+    assert!(WsEvent::decode(&frame).is_err());
+}
+
+#[test]
+fn bridges_messages_to_app() {
+    future::block_on(async {
+        let (miarh_end, mut app) = UnixStream::pair().unwrap();
+        let bridge = Bridge::start("ws-test", AppStream::Unix(miarh_end));
+        let (mut client, server_end) = UnixStream::pair().unwrap();
+        let conn = bridge.open(b"GET /ws HTTP/1.1\r\n\r\n".to_vec()).await.unwrap();
+        let id = conn.id;
+        let serving = ws_bridge::serve(server_end, &bridge, conn, Duration::from_secs(5), 1024);
+
+        let talking = async {
+            let event = |frame| WsEvent::decode(&frame).unwrap();
+            let opened = event(app_proto::read_frame(&mut app).await.unwrap());
+            assert_eq!(WsEvent::Open { conn_id: id, head: b"GET /ws HTTP/1.1\r\n\r\n".to_vec() }, opened);
+
+            client.write_all(&client_frame(false, 1, b"sub ")).await.unwrap();
+            client.write_all(&client_frame(true, 9, b"p")).await.unwrap();
+            client.write_all(&client_frame(true, 0, b"news")).await.unwrap();
+            assert_eq!((10, b"p".to_vec()), server_frame(&mut client).await);
+            let msg = event(app_proto::read_frame(&mut app).await.unwrap());
+            assert_eq!(WsEvent::Message { conn_ids: vec![id], msg: Message::Text("sub news".to_string()) }, msg);
+
+            // Fan-out: unknown ids are skipped.
+            let out = WsEvent::Message { conn_ids: vec![id, 999_999], msg: Message::Binary(vec![1, 2]) };
+            app.write_all(&out.encode()).await.unwrap();
+            assert_eq!((2, vec![1, 2]), server_frame(&mut client).await);
+
+            let close = WsEvent::Close { conn_ids: vec![id], code: 4000 };
+            app.write_all(&close.encode()).await.unwrap();
+            assert_eq!((8, ws::close_payload(4000, "")), server_frame(&mut client).await);
+            client.write_all(&client_frame(true, 8, &ws::close_payload(4000, ""))).await.unwrap();
+        };
+        let (code, _) = future::zip(serving, talking).await;
+        assert_eq!(4000, code);
+        bridge.close(id, code).await;
+        let closed = WsEvent::decode(&app_proto::read_frame(&mut app).await.unwrap()).unwrap();
+        assert_eq!(WsEvent::Close { conn_ids: vec![id], code: 4000 }, closed);
+        assert_eq!(0, bridge.conn_count().await);
+    });
+}
+
+#[test]
+fn closes_on_protocol_errors_and_idle() {
+    future::block_on(async {
+        let (miarh_end, _app) = UnixStream::pair().unwrap();
+        let bridge = Bridge::start("ws-test-errors", AppStream::Unix(miarh_end));
+
+        let (mut client, server_end) = UnixStream::pair().unwrap();
+        let conn = bridge.open(vec![]).await.unwrap();
+        let serving = ws_bridge::serve(server_end, &bridge, conn, Duration::from_secs(5), 1024);
+        let talking = async {
+            client.write_all(&[0x81, 0x01, b'a']).await.unwrap();
+            assert_eq!((8, ws::close_payload(1002, "")), server_frame(&mut client).await);
+        };
+        assert_eq!(ws::CLOSE_PROTOCOL_ERROR, future::zip(serving, talking).await.0);
+
+        let (mut client, server_end) = UnixStream::pair().unwrap();
+        let conn = bridge.open(vec![]).await.unwrap();
+        let serving = ws_bridge::serve(server_end, &bridge, conn, Duration::from_millis(200), 1024);
+        let talking = async {
+            assert_eq!((9, vec![]), server_frame(&mut client).await);
+            assert_eq!((8, ws::close_payload(1001, "")), server_frame(&mut client).await);
+            drop(client);
+        };
+        assert_eq!(ws_bridge::CLOSE_ABNORMAL, future::zip(serving, talking).await.0);
+    });
+}
+
+#[test]
+fn slow_connections_stay_until_closed() {
+    future::block_on(async {
+        let (miarh_end, mut app) = UnixStream::pair().unwrap();
+        let bridge = Bridge::start("ws-test-slow", AppStream::Unix(miarh_end));
+        // Never served, its queue fills up.
+        let conn = bridge.open(vec![]).await.unwrap();
+        app_proto::read_frame(&mut app).await.unwrap();
+        let msg = WsEvent::Message { conn_ids: vec![conn.id], msg: Message::Binary(vec![1]) };
+        for _ in 0..=ws_bridge::CONN_QUEUE_LEN {
+            app.write_all(&msg.encode()).await.unwrap();
+        }
+        while !conn.tx.is_closed() {
+            async_io::Timer::after(Duration::from_millis(10)).await;
+        }
+        assert_eq!(1, bridge.conn_count().await);
+        // The app still hears about it.
+        bridge.close(conn.id, ws::CLOSE_POLICY).await;
+        let closed = WsEvent::decode(&app_proto::read_frame(&mut app).await.unwrap()).unwrap();
+        assert_eq!(WsEvent::Close { conn_ids: vec![conn.id], code: ws::CLOSE_POLICY }, closed);
+        assert_eq!(0, bridge.conn_count().await);
+    });
+}
+
+fn group(socket_path: &str) -> UpstreamGroup {
+    let pool = Arc::new(AppPool::new(PoolConf {
+        addr: UpstreamAddr::Unix(socket_path.to_string()),
+        size: 1,
+        max_idle: Duration::from_secs(60),
+        queue_limit: 1,
+    }));
+    let health = HealthConf {
+        max_fails: 1,
+        fail_timeout: Duration::from_secs(1),
+        check_interval: Duration::ZERO,
+        check_timeout: Duration::from_secs(1),
+        check_path: "".to_string(),
+        check_host: "".to_string(),
+    };
+    UpstreamGroup::new(Balance::RoundRobin, health, vec![pool])
+}
+
+#[test]
+fn stalled_app_delays_only_its_server() {
+    let dir = std::env::temp_dir();
+    let stalled_path = dir.join(format!("miarh-ws-stalled-{}.sock", std::process::id()));
+    let ok_path = dir.join(format!("miarh-ws-ok-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&stalled_path);
+    let _ = std::fs::remove_file(&ok_path);
+    // Connections wait in the backlog, the handshake is never answered.
+    let _stalled = std::os::unix::net::UnixListener::bind(&stalled_path).unwrap();
+    let ok = UnixListener::bind(&ok_path).unwrap();
+    std::thread::spawn(move || future::block_on(async {
+        let mut streams = vec![];
+        while let Ok((mut stream, _)) = ok.accept().await {
+            app_proto::accept_handshake(&mut stream).await.unwrap();
+            streams.push(stream);
+        }
+    }));
+    let stalled = group(&stalled_path.display().to_string());
+    let ok = group(&ok_path.display().to_string());
+    future::block_on(async {
+        let timeout = Duration::from_secs(2);
+        let connecting = ws_bridge::get_bridge("ws-stalled", &stalled, timeout);
+        let other = async {
+            async_io::Timer::after(Duration::from_millis(100)).await;
+            let started = Instant::now();
+            let bridge = ws_bridge::get_bridge("ws-ok", &ok, timeout).await.unwrap();
+            assert!(started.elapsed() < Duration::from_secs(1));
+            bridge
+        };
+        let (r, bridge) = future::zip(connecting, other).await;
+        assert!(r.is_err());
+        let again = ws_bridge::get_bridge("ws-ok", &ok, timeout).await.unwrap();
+        assert!(Arc::ptr_eq(&bridge, &again));
+    });
+    let _ = std::fs::remove_file(&stalled_path);
+    let _ = std::fs::remove_file(&ok_path);
+}
+
+#[test]
+fn failed_bridge_connects_open_circuit() {
+    let socket_path = std::env::temp_dir().join(format!("miarh-ws-down-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&socket_path);
+    let down = group(&socket_path.display().to_string());
+    future::block_on(async {
+        let timeout = Duration::from_secs(1);
+        let r = ws_bridge::get_bridge("ws-down", &down, timeout).await;
+        assert!(matches!(r, Err(AppError::Connect(_))));
+        assert!(!down.upstreams[0].is_available());
+        let r = ws_bridge::get_bridge("ws-down", &down, timeout).await;
+        assert!(matches!(r, Err(AppError::Unavailable(_))));
+    });
+}